@@ -0,0 +1,62 @@
+use std::process::Command;
+
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    let build_timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=OTTERHOUND_GIT_SHA={}", git_sha);
+    println!(
+        "cargo:rustc-env=OTTERHOUND_BUILD_TIMESTAMP={}",
+        build_timestamp
+    );
+
+    #[cfg(feature = "grpc")]
+    compile_grpc_service();
+}
+
+/// Generates the server for `proto/otterhound.proto` without needing `protoc`; the messages are
+/// written out in `src/grpc.rs`.
+#[cfg(feature = "grpc")]
+fn compile_grpc_service() {
+    let method = |name: &str, route_name: &str, input_type: &str, output_type: &str| {
+        tonic_build::manual::Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::{}", input_type))
+            .output_type(format!("crate::grpc::{}", output_type))
+            .codec_path("tonic::codec::ProstCodec")
+            .build()
+    };
+
+    let service = tonic_build::manual::Service::builder()
+        .name("Subscriptions")
+        .package("otterhound")
+        .method(method(
+            "get_subscription",
+            "GetSubscription",
+            "GetSubscriptionRequest",
+            "GetSubscriptionResponse",
+        ))
+        .method(method(
+            "list_recent_events",
+            "ListRecentEvents",
+            "ListRecentEventsRequest",
+            "ListRecentEventsResponse",
+        ))
+        .build();
+
+    tonic_build::manual::Builder::new()
+        .build_client(false)
+        .compile(&[service]);
+}
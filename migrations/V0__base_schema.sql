@@ -0,0 +1,16 @@
+CREATE TABLE subscription_checkout_sessions (
+    stripe_id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    tier_id INTEGER NOT NULL,
+    completed BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE user_subscriptions (
+    tier INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    start_timestamp TIMESTAMPTZ NOT NULL,
+    end_timestamp TIMESTAMPTZ NOT NULL,
+    stripe_subscription TEXT
+);
+
+CREATE INDEX user_subscriptions_user_id ON user_subscriptions (user_id);
@@ -0,0 +1,5 @@
+ALTER TABLE event_log
+    ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN next_attempt_at TIMESTAMPTZ;
+
+CREATE INDEX event_log_next_attempt_at ON event_log (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
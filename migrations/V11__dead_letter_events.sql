@@ -0,0 +1,9 @@
+CREATE TABLE dead_letter_events (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT,
+    attempts INTEGER NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+CREATE TABLE stripe_customers (
+    stripe_customer_id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL
+);
+CREATE INDEX stripe_customers_user_id ON stripe_customers (user_id);
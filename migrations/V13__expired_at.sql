@@ -0,0 +1 @@
+ALTER TABLE user_subscriptions ADD COLUMN expired_at TIMESTAMPTZ;
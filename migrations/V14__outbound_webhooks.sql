@@ -0,0 +1,19 @@
+CREATE TABLE webhook_subscribers (
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE
+);
+CREATE TABLE outbound_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    subscriber_id INTEGER NOT NULL REFERENCES webhook_subscribers (id) ON DELETE CASCADE,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ DEFAULT now(),
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ
+);
+CREATE INDEX outbound_deliveries_due ON outbound_deliveries (next_attempt_at) WHERE status='pending';
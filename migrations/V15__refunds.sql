@@ -0,0 +1,20 @@
+ALTER TABLE user_subscriptions ADD COLUMN revoked_at TIMESTAMPTZ;
+ALTER TABLE user_subscriptions ADD COLUMN revoked_until TIMESTAMPTZ;
+CREATE TABLE subscription_refunds (
+    stripe_charge TEXT PRIMARY KEY,
+    stripe_subscription TEXT NOT NULL,
+    amount BIGINT NOT NULL,
+    amount_refunded BIGINT NOT NULL,
+    currency TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER,
+    stripe_subscription TEXT,
+    action TEXT NOT NULL,
+    details TEXT NOT NULL,
+    event_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX audit_log_user_id ON audit_log (user_id);
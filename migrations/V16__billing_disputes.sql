@@ -0,0 +1,13 @@
+ALTER TABLE user_subscriptions ADD COLUMN suspended_at TIMESTAMPTZ;
+CREATE TABLE billing_disputes (
+    stripe_dispute TEXT PRIMARY KEY,
+    stripe_charge TEXT NOT NULL,
+    stripe_subscription TEXT NOT NULL,
+    amount BIGINT NOT NULL,
+    currency TEXT NOT NULL,
+    reason TEXT,
+    status TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    closed_at TIMESTAMPTZ
+);
+CREATE INDEX billing_disputes_open ON billing_disputes (stripe_subscription) WHERE closed_at IS NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE user_subscriptions ADD COLUMN trial BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE user_subscriptions ADD COLUMN trial_end TIMESTAMPTZ;
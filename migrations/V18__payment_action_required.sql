@@ -0,0 +1,2 @@
+ALTER TABLE user_subscriptions ADD COLUMN payment_action_required_at TIMESTAMPTZ;
+ALTER TABLE user_subscriptions ADD COLUMN payment_action_url TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE user_subscriptions ADD COLUMN pending_tier INTEGER;
+ALTER TABLE user_subscriptions ADD COLUMN pending_tier_from TIMESTAMPTZ;
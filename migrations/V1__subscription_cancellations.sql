@@ -0,0 +1,9 @@
+CREATE TABLE subscription_cancellations (
+    stripe_subscription TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    reason TEXT,
+    feedback TEXT,
+    comment TEXT,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (stripe_subscription, user_id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE user_subscriptions
+    ADD COLUMN upcoming_invoice_amount BIGINT,
+    ADD COLUMN upcoming_invoice_currency TEXT,
+    ADD COLUMN upcoming_invoice_date TIMESTAMPTZ;
@@ -0,0 +1 @@
+CREATE UNIQUE INDEX user_subscriptions_stripe_subscription ON user_subscriptions (stripe_subscription);
@@ -0,0 +1 @@
+ALTER TABLE user_subscriptions ADD COLUMN payment_method_missing BOOLEAN NOT NULL DEFAULT FALSE;
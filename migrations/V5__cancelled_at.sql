@@ -0,0 +1 @@
+ALTER TABLE user_subscriptions ADD COLUMN cancelled_at TIMESTAMPTZ;
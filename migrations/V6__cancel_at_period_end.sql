@@ -0,0 +1 @@
+ALTER TABLE user_subscriptions ADD COLUMN cancel_at_period_end BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+ALTER TABLE user_subscriptions
+    ADD COLUMN status TEXT NOT NULL DEFAULT 'active',
+    ADD COLUMN past_due_since TIMESTAMPTZ;
@@ -0,0 +1,4 @@
+CREATE TABLE stripe_events (
+    id TEXT PRIMARY KEY,
+    processed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
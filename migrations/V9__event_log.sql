@@ -0,0 +1,12 @@
+CREATE TABLE event_log (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX event_log_status ON event_log (status);
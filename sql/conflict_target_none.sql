@@ -0,0 +1,4 @@
+-- Prepares user_subscriptions for SUBSCRIPTION_CONFLICT_TARGET=none, which always inserts. Run it
+-- once after the migrations.
+DROP INDEX IF EXISTS user_subscriptions_stripe_subscription;
+DROP INDEX IF EXISTS user_subscriptions_user_id_tier;
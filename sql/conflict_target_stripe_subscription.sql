@@ -0,0 +1,4 @@
+-- Restores the default SUBSCRIPTION_CONFLICT_TARGET=stripe_subscription after using another
+-- target. It fails while a Stripe subscription has several rows.
+DROP INDEX IF EXISTS user_subscriptions_user_id_tier;
+CREATE UNIQUE INDEX IF NOT EXISTS user_subscriptions_stripe_subscription ON user_subscriptions (stripe_subscription);
@@ -0,0 +1,4 @@
+-- Prepares user_subscriptions for SUBSCRIPTION_CONFLICT_TARGET=user_id,tier, one row per user and
+-- tier. Run it once after the migrations; it fails while a user has several rows for one tier.
+DROP INDEX IF EXISTS user_subscriptions_stripe_subscription;
+CREATE UNIQUE INDEX IF NOT EXISTS user_subscriptions_user_id_tier ON user_subscriptions (user_id, tier);
@@ -0,0 +1,381 @@
+//! The `/admin/` API, for operators to inspect and fix billing state without database access.
+//!
+//! Only served when admin tokens are configured, see `Config::admin_tokens`, and every request
+//! must carry one as `Authorization: Bearer <token>`. Each endpoint needs a scope:
+//!
+//! - `GET /admin/events?status=<status>&limit=<n>` (`read`) lists `event_log` rows, most recently
+//!   updated first. Both parameters are optional; `limit` defaults to 100.
+//! - `GET /admin/subscriptions/<user_id>` (`read`) lists a user's subscriptions.
+//! - `POST /admin/events/<id>/replay` (`replay`) handles a logged event again.
+//! - `POST /admin/batch` (`replay`) handles several logged events again, one after another, for
+//!   controlled backfills. The body is JSON with `event_ids`, at most 100 of them. The response
+//!   is an array with each event's `event_id`, its `outcome` (`handled`, `failed` or
+//!   `not_found`) and the `error` unless it was handled. It is a 200 if any event was handled,
+//!   so check the outcomes, and a 500 if none was.
+//! - `GET /admin/event-types` (`read`) lists the event types otterhound acts on, to compare with
+//!   the events the Stripe webhook endpoint sends. All others are counted in
+//!   `otterhound_unhandled_events_total` and ignored.
+
+use std::sync::Arc;
+
+use otterhound::{AdminScope, AdminToken};
+use serde_derive::Deserialize;
+
+use crate::{method_not_allowed_response, read_body, status_response, ServerState};
+
+/// The most events one `/admin/batch` request may handle.
+const MAX_BATCH_EVENTS: usize = 100;
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    event_ids: Vec<String>,
+}
+
+pub fn json_response(
+    status: hyper::StatusCode,
+    body: serde_json::Value,
+) -> hyper::Response<hyper::Body> {
+    let mut res = hyper::Response::new(body.to_string().into());
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+pub fn error_json(status: hyper::StatusCode, message: &str) -> hyper::Response<hyper::Body> {
+    json_response(status, serde_json::json!({ "error": message }))
+}
+
+/// Reads and parses a JSON request body, or gives the status and message to respond with.
+pub async fn read_json<T: serde::de::DeserializeOwned>(
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> Result<T, (hyper::StatusCode, String)> {
+    let body = match read_body(req.into_body(), state.max_body_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            return Err((
+                hyper::StatusCode::PAYLOAD_TOO_LARGE,
+                "Body too large".to_owned(),
+            ))
+        }
+        Err(err) => {
+            return Err((
+                hyper::StatusCode::BAD_REQUEST,
+                format!("Failed reading body: {}", err),
+            ))
+        }
+    };
+
+    serde_json::from_slice(&body).map_err(|err| {
+        (
+            hyper::StatusCode::BAD_REQUEST,
+            format!("Invalid request: {}", err),
+        )
+    })
+}
+
+pub fn epoch_secs(time: std::time::SystemTime) -> u64 {
+    otterhound::from_timestamp(time).unwrap_or(0)
+}
+
+/// Compares in constant time, so the token can't be guessed byte by byte from response times.
+fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The scope of the token the request carries, if it carries a known one.
+pub fn request_scope(
+    req: &hyper::Request<hyper::Body>,
+    tokens: &[AdminToken],
+) -> Option<AdminScope> {
+    bearer_scope(
+        req.headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+        tokens,
+    )
+}
+
+/// The scope of the token in an `Authorization: Bearer <token>` value, if it's a known one.
+pub fn bearer_scope(authorization: Option<&str>, tokens: &[AdminToken]) -> Option<AdminScope> {
+    let given = authorization.and_then(|value| value.strip_prefix("Bearer "))?;
+
+    tokens
+        .iter()
+        .filter(|token| token_matches(given.as_bytes(), token.token.as_bytes()))
+        .map(|token| token.scope)
+        .max()
+}
+
+pub fn unauthorized_response() -> hyper::Response<hyper::Body> {
+    metrics::counter!("otterhound_admin_requests_rejected_total", "reason" => "unauthorized")
+        .increment(1);
+    let mut res = status_response(hyper::StatusCode::UNAUTHORIZED);
+    res.headers_mut().insert(
+        hyper::header::WWW_AUTHENTICATE,
+        hyper::header::HeaderValue::from_static("Bearer"),
+    );
+    res
+}
+
+pub fn forbidden_response() -> hyper::Response<hyper::Body> {
+    metrics::counter!("otterhound_admin_requests_rejected_total", "reason" => "scope").increment(1);
+    error_json(
+        hyper::StatusCode::FORBIDDEN,
+        "Token lacks the scope for this request",
+    )
+}
+
+fn query_param(req: &hyper::Request<hyper::Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key == name {
+            Some(
+                percent_encoding::percent_decode_str(value)
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+pub async fn handle(
+    req: hyper::Request<hyper::Body>,
+    state: Arc<ServerState>,
+) -> hyper::Response<hyper::Body> {
+    if state.admin_tokens.is_empty() {
+        return status_response(hyper::StatusCode::NOT_FOUND);
+    }
+    let scope = match request_scope(&req, &state.admin_tokens) {
+        Some(scope) => scope,
+        None => return unauthorized_response(),
+    };
+
+    let segments: Vec<&str> = req.uri().path()["/admin/".len()..].split('/').collect();
+    match segments.as_slice() {
+        ["events"] => {
+            if req.method() != hyper::Method::GET {
+                return method_not_allowed_response("GET");
+            }
+            if scope < AdminScope::Read {
+                return forbidden_response();
+            }
+            list_events(&req, &state).await
+        }
+        ["subscriptions", user_id] => {
+            if req.method() != hyper::Method::GET {
+                return method_not_allowed_response("GET");
+            }
+            if scope < AdminScope::Read {
+                return forbidden_response();
+            }
+            match user_id.parse() {
+                Ok(user_id) => user_subscriptions(user_id, &state).await,
+                Err(_) => error_json(hyper::StatusCode::BAD_REQUEST, "Invalid user ID"),
+            }
+        }
+        ["event-types"] => {
+            if req.method() != hyper::Method::GET {
+                return method_not_allowed_response("GET");
+            }
+            if scope < AdminScope::Read {
+                return forbidden_response();
+            }
+            json_response(
+                hyper::StatusCode::OK,
+                serde_json::json!({ "handled": state.otterhound.handled_event_types() }),
+            )
+        }
+        ["events", id, "replay"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Replay {
+                return forbidden_response();
+            }
+            replay_event(id, &state).await
+        }
+        ["batch"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Replay {
+                return forbidden_response();
+            }
+            replay_batch(req, &state).await
+        }
+        _ => status_response(hyper::StatusCode::NOT_FOUND),
+    }
+}
+
+async fn list_events(
+    req: &hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let status = query_param(req, "status");
+    let limit = match query_param(req, "limit").map(|limit| limit.parse::<i64>()) {
+        None => 100,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return error_json(hyper::StatusCode::BAD_REQUEST, "Invalid limit"),
+    };
+
+    match state
+        .otterhound
+        .list_logged_events(status.as_deref(), limit)
+        .await
+    {
+        Ok(events) => json_response(
+            hyper::StatusCode::OK,
+            events
+                .into_iter()
+                .map(|event| {
+                    serde_json::json!({
+                        "id": event.id,
+                        "event_type": event.event_type,
+                        "created": epoch_secs(event.created),
+                        "status": event.status,
+                        "error": event.error,
+                        "attempts": event.attempts,
+                        "updated_at": epoch_secs(event.updated_at),
+                    })
+                })
+                .collect(),
+        ),
+        Err(err) => {
+            tracing::error!("Failed to list events: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn user_subscriptions(user_id: i32, state: &ServerState) -> hyper::Response<hyper::Body> {
+    match state.otterhound.user_subscriptions(user_id).await {
+        Ok(subs) => json_response(
+            hyper::StatusCode::OK,
+            subs.into_iter()
+                .map(|sub| {
+                    serde_json::json!({
+                        "tier": sub.tier,
+                        "start_timestamp": epoch_secs(sub.start_timestamp),
+                        "end_timestamp": epoch_secs(sub.end_timestamp),
+                        "stripe_subscription": sub.stripe_subscription,
+                        "status": sub.status,
+                        "cancel_at_period_end": sub.cancel_at_period_end,
+                        "cancelled_at": sub.cancelled_at.map(epoch_secs),
+                        "past_due_since": sub.past_due_since.map(epoch_secs),
+                        "payment_method_missing": sub.payment_method_missing,
+                        "suspended_at": sub.suspended_at.map(epoch_secs),
+                        "trial": sub.trial,
+                        "trial_end": sub.trial_end.map(epoch_secs),
+                    })
+                })
+                .collect(),
+        ),
+        Err(err) => {
+            tracing::error!("Failed to load subscriptions: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn replay_event(id: &str, state: &ServerState) -> hyper::Response<hyper::Body> {
+    let events = match state
+        .otterhound
+        .load_logged_events(otterhound::EventSelection::Ids(vec![id.to_owned()]))
+        .await
+    {
+        Ok(events) => events,
+        Err(err) => {
+            tracing::error!("Failed to load event {}: {}", id, err);
+            return error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+        }
+    };
+    let event = match events.into_iter().next() {
+        Some(event) => event,
+        None => return error_json(hyper::StatusCode::NOT_FOUND, "Event not found in event_log"),
+    };
+
+    tracing::info!("Replaying event {} on admin request", id);
+    match state.otterhound.handle_logged_event(event).await {
+        Ok(()) => json_response(
+            hyper::StatusCode::OK,
+            serde_json::json!({ "id": id, "outcome": "handled" }),
+        ),
+        Err(err) => json_response(
+            hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({ "id": id, "outcome": "failed", "error": err.to_string() }),
+        ),
+    }
+}
+
+async fn replay_batch(
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let request: BatchRequest = match read_json(req, state).await {
+        Ok(request) => request,
+        Err((status, message)) => return error_json(status, &message),
+    };
+    if request.event_ids.is_empty() {
+        return error_json(hyper::StatusCode::BAD_REQUEST, "No event_ids given");
+    }
+    if request.event_ids.len() > MAX_BATCH_EVENTS {
+        return error_json(
+            hyper::StatusCode::BAD_REQUEST,
+            &format!("At most {} event_ids per batch", MAX_BATCH_EVENTS),
+        );
+    }
+
+    tracing::info!(
+        "Replaying {} events on admin request",
+        request.event_ids.len()
+    );
+    let mut handled = 0;
+    let mut outcomes = Vec::with_capacity(request.event_ids.len());
+    // loaded one by one, so an event that fails to load only fails itself
+    for id in request.event_ids {
+        let outcome = match state
+            .otterhound
+            .load_logged_events(otterhound::EventSelection::Ids(vec![id.clone()]))
+            .await
+            .map(|events| events.into_iter().next())
+        {
+            Ok(Some(event)) => match state.otterhound.handle_logged_event(event).await {
+                Ok(()) => {
+                    handled += 1;
+                    serde_json::json!({ "event_id": id, "outcome": "handled" })
+                }
+                Err(err) => {
+                    serde_json::json!({ "event_id": id, "outcome": "failed", "error": err.to_string() })
+                }
+            },
+            Ok(None) => serde_json::json!({
+                "event_id": id,
+                "outcome": "not_found",
+                "error": "Event not found in event_log",
+            }),
+            Err(err) => {
+                tracing::error!("Failed to load event {}: {}", id, err);
+                serde_json::json!({ "event_id": id, "outcome": "failed", "error": err.to_string() })
+            }
+        };
+        outcomes.push(outcome);
+    }
+
+    let status = if handled == 0 {
+        hyper::StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        hyper::StatusCode::OK
+    };
+    json_response(status, serde_json::Value::Array(outcomes))
+}
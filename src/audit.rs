@@ -0,0 +1,28 @@
+//! The `audit_log` table, recording billing actions that revoke or restrict access, so support
+//! can later tell why a user lost it.
+
+use tokio_postgres::Transaction;
+
+use crate::OtterhoundError;
+
+/// Records `action` against `stripe_subscription` and the user it belongs to, if known.
+/// `details` is stored as JSON text.
+pub async fn record(
+    txn: &Transaction<'_>,
+    event_id: Option<&str>,
+    stripe_subscription: &str,
+    action: &str,
+    details: &serde_json::Value,
+) -> Result<u64, OtterhoundError> {
+    Ok(txn
+        .execute(
+            "INSERT INTO audit_log (user_id, stripe_subscription, action, details, event_id) VALUES ((SELECT user_id FROM user_subscriptions WHERE stripe_subscription=$1 LIMIT 1), $1, $2, $3, $4)",
+            &[
+                &stripe_subscription,
+                &action,
+                &details.to_string(),
+                &event_id,
+            ],
+        )
+        .await?)
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::stripe::client::HttpClient;
+use crate::{
+    gen_auth_header, migrate, pool, schema, Config, ConflictTarget, CurrencyPolicy,
+    DatabaseSslMode, Debouncer, HandlingSettings, MissingSessionBehavior, Otterhound,
+    OtterhoundError, PoolSettings, RedeliveryTracker, RetryPolicy, StripeClient, StripeSettings,
+    SubscriptionRepo,
+};
+
+/// Constructs an `Otterhound` without reading anything from the environment, e.g. when embedding
+/// it in another service. Every setting has a default, except the database URL and Stripe key.
+#[derive(Default)]
+pub struct OtterhoundBuilder {
+    database_url: Option<String>,
+    database_ssl_mode: Option<DatabaseSslMode>,
+    database_ca_cert_path: Option<String>,
+    stripe_secret_key: Option<String>,
+    http_client: Option<HttpClient>,
+    pool: PoolSettings,
+    stripe: StripeSettings,
+    handling: HandlingSettings,
+    run_migrations: bool,
+    baseline: Option<u32>,
+}
+
+impl OtterhoundBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts from loaded settings.
+    pub fn from_config(config: &Config) -> Self {
+        OtterhoundBuilder {
+            database_url: Some(config.database_url.clone()),
+            database_ssl_mode: Some(config.database_ssl_mode),
+            database_ca_cert_path: config.database_ca_cert_path.clone(),
+            stripe_secret_key: Some(config.stripe_secret_key.clone()),
+            pool: config.db_pool.clone(),
+            stripe: config.stripe.clone(),
+            handling: config.handling.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn database_url(mut self, database_url: impl Into<String>) -> Self {
+        self.database_url = Some(database_url.into());
+        self
+    }
+
+    /// Whether and how database connections use TLS, `Disable` by default.
+    pub fn database_ssl_mode(mut self, database_ssl_mode: DatabaseSslMode) -> Self {
+        self.database_ssl_mode = Some(database_ssl_mode);
+        self
+    }
+
+    /// Trusts the CA in this PEM file for the database's certificate, besides the system's.
+    pub fn database_ca_cert_path(mut self, database_ca_cert_path: impl Into<String>) -> Self {
+        self.database_ca_cert_path = Some(database_ca_cert_path.into());
+        self
+    }
+
+    pub fn stripe_secret_key(mut self, stripe_secret_key: impl Into<String>) -> Self {
+        self.stripe_secret_key = Some(stripe_secret_key.into());
+        self
+    }
+
+    /// Shares an existing HTTP client for Stripe requests instead of creating one.
+    pub fn http_client(mut self, http_client: HttpClient) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Maximum number of database connections, 10 by default.
+    pub fn max_connections(mut self, max_connections: u32) -> Self {
+        self.pool.max_size = Some(max_connections);
+        self
+    }
+
+    /// Connections kept open while idle. They are opened before `build` returns.
+    pub fn min_idle(mut self, min_idle: u32) -> Self {
+        self.pool.min_idle = Some(min_idle);
+        self
+    }
+
+    /// How long to wait for a database connection before failing, 30 seconds by default.
+    pub fn connection_timeout(mut self, connection_timeout: std::time::Duration) -> Self {
+        self.pool.connection_timeout = Some(connection_timeout);
+        self
+    }
+
+    /// How long a connection may sit idle before it's closed, 10 minutes by default. Zero keeps
+    /// idle connections open.
+    pub fn idle_timeout(mut self, idle_timeout: std::time::Duration) -> Self {
+        self.pool.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// How long a connection is used before it's replaced, 30 minutes by default. Zero keeps
+    /// connections forever.
+    pub fn max_lifetime(mut self, max_lifetime: std::time::Duration) -> Self {
+        self.pool.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// How many times `build` tries to reach the database, waiting `retry_delay` after the first
+    /// failure and doubling that up to 30 seconds after each further one. 5 attempts 1 second apart
+    /// by default, so the database may start a little after otterhound.
+    pub fn connect_attempts(mut self, attempts: u32, retry_delay: std::time::Duration) -> Self {
+        self.pool.connect_attempts = Some(attempts);
+        self.pool.connect_retry_delay = Some(retry_delay);
+        self
+    }
+
+    /// The Stripe API version sent with every request, `DEFAULT_API_VERSION` by default.
+    pub fn stripe_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.stripe.api_version = Some(api_version.into());
+        self
+    }
+
+    /// Sends Stripe requests somewhere other than `DEFAULT_API_BASE`, e.g. a mock of the API.
+    pub fn stripe_api_base(mut self, api_base: impl Into<String>) -> Self {
+        self.stripe.api_base = Some(api_base.into());
+        self
+    }
+
+    /// How many times a Stripe request failing with a connection error, 429 or 5xx is retried, 2
+    /// by default.
+    pub fn stripe_max_retries(mut self, max_retries: u32) -> Self {
+        self.stripe.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Opens the Stripe circuit breaker after `threshold` consecutive failures, for `cooldown`
+    /// before letting a request through. 5 failures and 30 seconds by default.
+    pub fn stripe_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.stripe.breaker_threshold = Some(threshold);
+        self.stripe.breaker_cooldown = Some(cooldown);
+        self
+    }
+
+    /// What to do when a completed checkout session has no matching row, `Skip` by default.
+    pub fn on_missing_session(mut self, on_missing_session: MissingSessionBehavior) -> Self {
+        self.handling.on_missing_session = on_missing_session;
+        self
+    }
+
+    /// Which columns identify a `user_subscriptions` row, `stripe_subscription` by default.
+    pub fn conflict_target(mut self, conflict_target: ConflictTarget) -> Self {
+        self.handling.conflict_target = conflict_target;
+        self
+    }
+
+    /// The customer metadata holding the user ID, `user_id` by default.
+    pub fn user_id_metadata_key(mut self, key: impl Into<String>) -> Self {
+        self.handling.user_id_metadata_key = key.into();
+        self
+    }
+
+    /// The price metadata holding the tier ID, `tier_id` by default.
+    pub fn tier_metadata_key(mut self, key: impl Into<String>) -> Self {
+        self.handling.tier_metadata_key = key.into();
+        self
+    }
+
+    /// How long access is kept after a renewal payment fails, 3 days by default.
+    pub fn payment_grace_period(mut self, payment_grace_period: Duration) -> Self {
+        self.handling.payment_grace_period = payment_grace_period;
+        self
+    }
+
+    /// How long after its end timestamp a subscription is marked expired, zero by default.
+    pub fn expiry_grace_period(mut self, expiry_grace_period: Duration) -> Self {
+        self.handling.expiry_grace_period = expiry_grace_period;
+        self
+    }
+
+    /// How long handling an event may take before it is abandoned as failed, 60 seconds by
+    /// default.
+    pub fn event_timeout(mut self, event_timeout: Duration) -> Self {
+        self.handling.event_timeout = event_timeout;
+        self
+    }
+
+    /// Overrides `event_timeout` for some event types. None by default.
+    pub fn event_timeouts(mut self, event_timeouts: HashMap<String, Duration>) -> Self {
+        self.handling.event_timeouts = event_timeouts;
+        self
+    }
+
+    /// Logs a warning when one event is delivered `threshold` times within `window`. 5 times in 10
+    /// minutes by default.
+    pub fn redelivery_warning(mut self, threshold: u32, window: Duration) -> Self {
+        self.handling.redelivery_warning_threshold = threshold;
+        self.handling.redelivery_window = window;
+        self
+    }
+
+    /// Waits this long for more `customer.subscription.updated` events for the same subscription,
+    /// writing only the latest. Must be shorter than the timeout for those events. Off by default.
+    pub fn update_debounce(mut self, update_debounce: Duration) -> Self {
+        self.handling.update_debounce = update_debounce;
+        self
+    }
+
+    /// Records why subscriptions were cancelled, in `subscription_cancellations`. Off by default.
+    pub fn store_cancellation_reasons(mut self, store_cancellation_reasons: bool) -> Self {
+        self.handling.store_cancellation_reasons = store_cancellation_reasons;
+        self
+    }
+
+    /// Skips checking the database has the expected tables and columns. Off by default.
+    pub fn skip_schema_check(mut self, skip_schema_check: bool) -> Self {
+        self.handling.skip_schema_check = skip_schema_check;
+        self
+    }
+
+    /// Records upcoming renewals from `invoice.upcoming` events. Off by default.
+    pub fn handle_invoice_upcoming(mut self, handle_invoice_upcoming: bool) -> Self {
+        self.handling.handle_invoice_upcoming = handle_invoice_upcoming;
+        self
+    }
+
+    /// Makes readiness also depend on reaching Stripe. Off by default.
+    pub fn readiness_check_stripe(mut self, readiness_check_stripe: bool) -> Self {
+        self.handling.readiness_check_stripe = readiness_check_stripe;
+        self
+    }
+
+    /// Keeps the higher tier until the end of the period when a subscription moves to a lower
+    /// one, see `HandlingSettings::defer_downgrades`. Off by default.
+    pub fn defer_downgrades(mut self, defer_downgrades: bool) -> Self {
+        self.handling.defer_downgrades = defer_downgrades;
+        self
+    }
+
+    /// Policies by lower-case currency code. Currencies without one get no access buffer.
+    pub fn currency_policies(mut self, currency_policies: HashMap<String, CurrencyPolicy>) -> Self {
+        self.handling.currency_policies = currency_policies;
+        self
+    }
+
+    /// The Stripe price of each tier, needed to create Checkout sessions. None by default.
+    pub fn tier_prices(mut self, tier_prices: HashMap<i32, String>) -> Self {
+        self.handling.tier_prices = tier_prices;
+        self
+    }
+
+    /// How failed events are retried, see `RetryPolicy`'s defaults.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.handling.retry_policy = retry_policy;
+        self
+    }
+
+    /// Applies the embedded migrations before anything else touches the database. Meant for
+    /// fresh databases, see `migrations/`.
+    pub fn run_migrations(mut self, run_migrations: bool) -> Self {
+        self.run_migrations = run_migrations;
+        self
+    }
+
+    /// Records the migrations up to and including `version` as applied before running any, for a
+    /// database whose schema was built by hand. Combine with `run_migrations` to apply the rest.
+    pub fn baseline(mut self, version: u32) -> Self {
+        self.baseline = Some(version);
+        self
+    }
+
+    pub async fn build(self) -> Result<Otterhound, OtterhoundError> {
+        let database_url = self
+            .database_url
+            .ok_or_else(|| OtterhoundError::Config("Missing database URL".to_owned()))?;
+        let stripe_secret_key = self
+            .stripe_secret_key
+            .ok_or_else(|| OtterhoundError::Config("Missing Stripe secret key".to_owned()))?;
+        let http_client = self.http_client.unwrap_or_else(|| {
+            std::sync::Arc::new(hyper::Client::builder().build(hyper_tls::HttpsConnector::new()))
+        });
+
+        let handling = self.handling;
+        if handling.user_id_metadata_key.is_empty() || handling.tier_metadata_key.is_empty() {
+            return Err(OtterhoundError::Config(
+                "Metadata keys must not be empty".to_owned(),
+            ));
+        }
+        let update_timeout = handling
+            .event_timeouts
+            .get("customer.subscription.updated")
+            .copied()
+            .unwrap_or(handling.event_timeout);
+        if !handling.update_debounce.is_zero() && handling.update_debounce >= update_timeout {
+            return Err(OtterhoundError::Config(format!(
+                "The update debounce window ({:?}) must be shorter than the timeout for customer.subscription.updated ({:?})",
+                handling.update_debounce, update_timeout
+            )));
+        }
+
+        let redeliveries = RedeliveryTracker::new(
+            10_000,
+            handling.redelivery_warning_threshold,
+            handling.redelivery_window,
+        );
+
+        let disabled_if_zero =
+            |duration: std::time::Duration| Some(duration).filter(|duration| !duration.is_zero());
+        let mut db_pool = bb8::Pool::builder()
+            .min_idle(self.pool.min_idle)
+            .error_sink(Box::new(pool::LogErrorSink));
+        if let Some(max_size) = self.pool.max_size {
+            db_pool = db_pool.max_size(max_size);
+        }
+        if let Some(connection_timeout) = self.pool.connection_timeout {
+            db_pool = db_pool.connection_timeout(connection_timeout);
+        }
+        if let Some(idle_timeout) = self.pool.idle_timeout {
+            db_pool = db_pool.idle_timeout(disabled_if_zero(idle_timeout));
+        }
+        if let Some(max_lifetime) = self.pool.max_lifetime {
+            db_pool = db_pool.max_lifetime(disabled_if_zero(max_lifetime));
+        }
+        let mut db_config: tokio_postgres::Config = database_url.parse().map_err(|err| {
+            OtterhoundError::Config(format!("Failed to parse database_url: {}", err))
+        })?;
+        let ssl_mode = self.database_ssl_mode.unwrap_or(DatabaseSslMode::Disable);
+        db_config.ssl_mode(match ssl_mode {
+            DatabaseSslMode::Disable => tokio_postgres::config::SslMode::Disable,
+            DatabaseSslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+            _ => tokio_postgres::config::SslMode::Require,
+        });
+        let tls = pool::tls_connector(ssl_mode, self.database_ca_cert_path.as_deref())?;
+
+        let manager = pool::PostgresConnectionManager::new(db_config, tls);
+        pool::wait_for_database(
+            &manager,
+            self.pool.connect_attempts.unwrap_or(5),
+            self.pool
+                .connect_retry_delay
+                .unwrap_or(std::time::Duration::from_secs(1)),
+        )
+        .await?;
+
+        let db_pool = db_pool.build(manager).await?;
+
+        if self.pool.min_idle.is_some() {
+            tracing::info!(
+                "Warmed up database pool with {} connections",
+                db_pool.state().idle_connections
+            );
+        }
+
+        if let Some(version) = self.baseline {
+            migrate::baseline(&db_pool, version).await?;
+        }
+        if self.run_migrations {
+            migrate::run(&db_pool).await?;
+        }
+
+        if !handling.skip_schema_check {
+            schema::check(&db_pool, handling.store_cancellation_reasons).await?;
+        }
+
+        handling.conflict_target.validate(&db_pool).await?;
+
+        Ok(Otterhound {
+            stripe: StripeClient::new(
+                gen_auth_header(&stripe_secret_key),
+                http_client.clone(),
+                &self.stripe,
+            ),
+            http_client,
+            db_pool,
+            store_cancellation_reasons: handling.store_cancellation_reasons,
+            handle_invoice_upcoming: handling.handle_invoice_upcoming,
+            readiness_check_stripe: handling.readiness_check_stripe,
+            defer_downgrades: handling.defer_downgrades,
+            on_missing_session: handling.on_missing_session,
+            currency_policies: handling.currency_policies,
+            tier_prices: handling.tier_prices,
+            subscriptions: SubscriptionRepo::new(
+                handling.conflict_target.insert_subscription_query(),
+            ),
+            redeliveries,
+            user_id_metadata_key: handling.user_id_metadata_key,
+            tier_metadata_key: handling.tier_metadata_key,
+            payment_grace_period: handling.payment_grace_period,
+            expiry_grace_period: handling.expiry_grace_period,
+            retry_policy: handling.retry_policy,
+            event_timeout: handling.event_timeout,
+            event_timeouts: handling.event_timeouts,
+            update_debouncer: Debouncer::new(handling.update_debounce),
+            handlers: std::collections::HashMap::new(),
+            error_reporter: None,
+            publisher: None,
+        })
+    }
+}
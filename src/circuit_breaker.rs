@@ -0,0 +1,189 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Reported as the `otterhound_stripe_circuit_state` gauge, 0 when closed, 1 when half-open and 2
+/// when open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    /// Requests are sent normally.
+    Closed,
+    /// Too many consecutive failures, requests fail fast until the cooldown passes.
+    Open,
+    /// The cooldown has passed, and a single request at a time is let through to test recovery.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// The value of the `otterhound_stripe_circuit_state` gauge.
+    pub fn gauge_value(self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        }
+    }
+}
+
+fn record_state(state: CircuitState) {
+    metrics::gauge!("otterhound_stripe_circuit_state").set(state.gauge_value());
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // when the trial request let through while half-open was sent, if it hasn't finished
+    probe_started_at: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) => {
+                if opened_at.elapsed() < self.cooldown {
+                    CircuitState::Open
+                } else {
+                    CircuitState::HalfOpen
+                }
+            }
+        }
+    }
+
+    /// Whether a request should be attempted. While half-open only one is, until it is recorded
+    /// with `record_success` or `record_failure`. A trial that is never recorded, e.g. because
+    /// its request was dropped, is given up on after another cooldown.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => true,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => false,
+            Some(_) => match state.probe_started_at {
+                Some(started_at) if started_at.elapsed() < self.cooldown => false,
+                _ => {
+                    state.probe_started_at = Some(Instant::now());
+                    true
+                }
+            },
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            tracing::info!("Stripe API recovered, closing circuit");
+            record_state(CircuitState::Closed);
+        }
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.probe_started_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.probe_started_at = None;
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.failure_threshold {
+            if state.opened_at.is_none() {
+                tracing::warn!(
+                    "Stripe API failed {} times in a row, opening circuit",
+                    state.consecutive_failures
+                );
+            }
+            record_state(CircuitState::Open);
+            // re-opening from half-open restarts the cooldown
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn half_open_admits_one_probe() {
+        let breaker = open_breaker();
+        std::thread::sleep(COOLDOWN);
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn successful_probe_closes() {
+        let breaker = open_breaker();
+        std::thread::sleep(COOLDOWN);
+
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = open_breaker();
+        std::thread::sleep(COOLDOWN);
+
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+
+        // and the next cooldown admits another probe
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn unrecorded_probe_is_given_up_on() {
+        let breaker = open_breaker();
+        std::thread::sleep(COOLDOWN);
+
+        assert!(breaker.allow());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+    }
+}
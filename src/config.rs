@@ -0,0 +1,1332 @@
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{
+    ConflictTarget, CurrencyPolicy, DatabaseSslMode, MissingSessionBehavior, OtterhoundError,
+    RetryPolicy,
+};
+
+/// Settings needed at startup.
+///
+/// Read from the TOML file named by `OTTERHOUND_CONFIG`, if set, with each field overridden by the
+/// environment variable of the same name in upper case, e.g. `DATABASE_URL`.
+pub struct Config {
+    pub database_url: String,
+    /// Whether and how database connections use TLS, `disable` by default.
+    pub database_ssl_mode: DatabaseSslMode,
+    /// PEM file with a CA to trust for the database's certificate, besides the system's.
+    pub database_ca_cert_path: Option<String>,
+    pub db_pool: PoolSettings,
+    pub stripe_secret_key: String,
+    pub stripe: StripeSettings,
+    pub handling: HandlingSettings,
+    /// Webhook signing secrets, any of which is accepted. More than one is only needed while
+    /// rotating secrets. Only needed by the webhook server, see `load_server`.
+    pub signing_secrets: Vec<String>,
+    /// How far a webhook's signed timestamp may be from now, 5 minutes by default.
+    pub signature_tolerance: std::time::Duration,
+    /// Address to listen on, `::` by default. Use `0.0.0.0` on hosts without IPv6.
+    pub bind_addr: std::net::IpAddr,
+    pub port: u16,
+    /// PEM certificate chain and private key, both set to serve HTTPS instead of HTTP.
+    pub tls: Option<TlsPaths>,
+    /// Unix socket to listen on instead of `port`, e.g. behind a reverse proxy on the same host.
+    pub unix_socket_path: Option<String>,
+    /// Bearer tokens accepted by the server's admin and internal APIs, which are disabled without
+    /// any.
+    pub admin_tokens: Vec<AdminToken>,
+    /// Path Stripe posts events to, `/stripe/webhook` by default.
+    pub webhook_path: String,
+    /// Larger webhook bodies are rejected with 413, 256 KiB by default.
+    pub max_body_bytes: usize,
+    /// `background` by default.
+    pub processing_mode: ProcessingMode,
+    /// Event types processed differently from `processing_mode`, from `sync_event_types` and
+    /// `background_event_types`.
+    pub processing_mode_overrides: HashMap<String, ProcessingMode>,
+    /// How many events `Sync` requests and the `Queue` workers handle at once, 32 by default.
+    pub max_concurrent_events: usize,
+    /// Events the `Queue` mode holds before answering 503, 1000 by default.
+    pub queue_capacity: usize,
+    /// Workers draining the `Queue` mode's queue, 4 by default.
+    pub queue_workers: usize,
+    /// Events the `Background` worker handles at once, 4 by default.
+    pub worker_concurrency: usize,
+    /// How often the `Background` worker looks for queued events, 1 second by default.
+    pub worker_poll_interval: Duration,
+    /// Threads of a runtime dedicated to processing events. Processing shares the server's
+    /// runtime without one.
+    pub processing_threads: Option<usize>,
+    /// How often failed events are retried, 30 seconds by default. Zero disables retrying, e.g.
+    /// when another instance does it.
+    pub retry_poll_interval: Duration,
+    /// How often lapsed subscriptions are expired, 60 seconds by default. Zero disables it.
+    pub expiry_poll_interval: Duration,
+    /// How often outbound webhooks are sent, 5 seconds by default. Zero disables them.
+    pub outbound_poll_interval: Duration,
+    /// Logs a warning when no event has arrived for this long.
+    pub event_silence_warning: Option<Duration>,
+    /// How long shutdown waits for events being processed, 30 seconds by default.
+    pub shutdown_timeout: Duration,
+    /// Port for the gRPC admin API, which needs `admin_tokens`. Only used with the `grpc`
+    /// feature.
+    pub grpc_port: Option<u16>,
+    /// Sentry DSN that handling failures are reported to. Only used with the
+    /// `sentry-reporting` feature.
+    pub sentry_dsn: Option<String>,
+    /// NATS server that processed events are published to. Only used with the
+    /// `nats-publishing` feature.
+    pub nats_url: Option<String>,
+    /// Prefix of the subjects events are published under, `billing` by default.
+    pub nats_subject_prefix: String,
+}
+
+/// How an accepted event is processed relative to the webhook response.
+///
+/// `Background` acknowledges once the event is stored in Postgres and leaves processing to the
+/// worker pool, so nothing acknowledged is lost. `Sync` processes it first and returns an error
+/// status on failure, so Stripe retries it. `Queue` acknowledges once the event is in a bounded
+/// in-memory queue drained by a fixed set of workers, answering 503 when the queue is full so
+/// Stripe retries later. It writes nothing to `event_log`, so queued events are lost if the
+/// process dies, and events that fail aren't retried or available to replay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcessingMode {
+    Background,
+    Sync,
+    Queue,
+}
+
+impl std::str::FromStr for ProcessingMode {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src {
+            // `durable` is the old name for persisting before acknowledging
+            "background" | "durable" => Ok(ProcessingMode::Background),
+            "sync" => Ok(ProcessingMode::Sync),
+            "queue" => Ok(ProcessingMode::Queue),
+            _ => Err(format!("Unknown processing mode: {}", src)),
+        }
+    }
+}
+
+/// Database pool settings. Anything unset keeps bb8's default.
+#[derive(Clone, Debug, Default)]
+pub struct PoolSettings {
+    /// `DB_MAX_CONNECTIONS`, 10 by default.
+    pub max_size: Option<u32>,
+    /// `DB_MIN_IDLE`, connections kept open while idle.
+    pub min_idle: Option<u32>,
+    /// `DB_CONNECTION_TIMEOUT_SECS`, how long to wait for a connection, 30 seconds by default.
+    pub connection_timeout: Option<std::time::Duration>,
+    /// `DB_IDLE_TIMEOUT_SECS`, after which idle connections are closed, 10 minutes by default.
+    /// 0 keeps them open.
+    pub idle_timeout: Option<std::time::Duration>,
+    /// `DB_MAX_LIFETIME_SECS`, after which connections are replaced, 30 minutes by default. 0
+    /// keeps them forever.
+    pub max_lifetime: Option<std::time::Duration>,
+    /// `DB_CONNECT_ATTEMPTS`, how many times to try reaching the database at startup, 5 by
+    /// default.
+    pub connect_attempts: Option<u32>,
+    /// `DB_CONNECT_RETRY_DELAY_SECS`, the delay after the first failed attempt at startup, 1
+    /// second by default. It doubles after each further attempt, up to 30 seconds.
+    pub connect_retry_delay: Option<std::time::Duration>,
+}
+
+/// Stripe API client settings. Anything unset keeps the default.
+#[derive(Clone, Debug, Default)]
+pub struct StripeSettings {
+    /// `STRIPE_API_VERSION`, sent with every request, `DEFAULT_API_VERSION` by default.
+    pub api_version: Option<String>,
+    /// `STRIPE_API_BASE`, e.g. to point at a mock of the API, `DEFAULT_API_BASE` by default.
+    pub api_base: Option<String>,
+    /// `STRIPE_MAX_RETRIES`, retries of requests failing with a connection error, 429 or 5xx, 2
+    /// by default.
+    pub max_retries: Option<u32>,
+    /// `STRIPE_BREAKER_THRESHOLD`, consecutive failures that open the circuit breaker, 5 by
+    /// default.
+    pub breaker_threshold: Option<u32>,
+    /// `STRIPE_BREAKER_COOLDOWN_SECS`, how long the breaker stays open before letting a request
+    /// through, 30 seconds by default.
+    pub breaker_cooldown: Option<Duration>,
+}
+
+/// How events are handled.
+#[derive(Clone, Debug)]
+pub struct HandlingSettings {
+    /// `ON_MISSING_SESSION`, `skip` by default.
+    pub on_missing_session: MissingSessionBehavior,
+    /// `SUBSCRIPTION_CONFLICT_TARGET`, `stripe_subscription` by default. Others need the indexes
+    /// changed, see `ConflictTarget`.
+    pub conflict_target: ConflictTarget,
+    /// `USER_ID_METADATA_KEY`, the customer metadata holding the user ID, `user_id` by default.
+    pub user_id_metadata_key: String,
+    /// `TIER_METADATA_KEY`, the price metadata holding the tier ID, `tier_id` by default.
+    pub tier_metadata_key: String,
+    /// `PAYMENT_GRACE_PERIOD_SECS`, how long access is kept after a renewal payment fails, 3 days
+    /// by default.
+    pub payment_grace_period: Duration,
+    /// `EXPIRY_GRACE_SECS`, how long after its end timestamp a subscription is marked expired, 0
+    /// by default.
+    pub expiry_grace_period: Duration,
+    /// `EVENT_TIMEOUT_SECS`, how long handling an event may take, 60 seconds by default.
+    pub event_timeout: Duration,
+    /// `EVENT_TIMEOUTS`, overrides of `event_timeout` by event type, e.g.
+    /// `invoice.payment_succeeded:120,customer.updated:10`.
+    pub event_timeouts: HashMap<String, Duration>,
+    /// `REDELIVERY_WARNING_THRESHOLD`, deliveries of one event within `redelivery_window` before
+    /// a warning is logged, 5 by default.
+    pub redelivery_warning_threshold: u32,
+    /// `REDELIVERY_WINDOW_SECS`, 10 minutes by default.
+    pub redelivery_window: Duration,
+    /// `UPDATE_DEBOUNCE_SECS`, how long to wait for more `customer.subscription.updated` events
+    /// for the same subscription before writing only the latest, 0 (off) by default. Handling
+    /// each of those events waits for the write, so this must be shorter than their timeout.
+    pub update_debounce: Duration,
+    /// `STORE_CANCELLATION_REASONS`, which needs `subscription_cancellations`, off by default.
+    pub store_cancellation_reasons: bool,
+    /// `SKIP_SCHEMA_CHECK`, off by default.
+    pub skip_schema_check: bool,
+    /// `HANDLE_INVOICE_UPCOMING`, off by default.
+    pub handle_invoice_upcoming: bool,
+    /// `READINESS_CHECK_STRIPE`, whether readiness also pings Stripe, off by default.
+    pub readiness_check_stripe: bool,
+    /// `DEFER_DOWNGRADES`, whether a move to a lower tier keeps the higher one until the end of
+    /// the current period, off by default. Tiers are ranked by ID, like `active_subscription`
+    /// does, and upgrades always apply right away.
+    ///
+    /// Stripe prorates a plan change by default, crediting the unused time on the old price, so
+    /// the lower tier applying right away matches what is billed. Deferring suits changes made
+    /// with `proration_behavior=none`, where the higher tier is paid for until the period ends. A
+    /// change Stripe applies at renewal, e.g. from a subscription schedule, arrives with the new
+    /// period and isn't deferred any further.
+    pub defer_downgrades: bool,
+    /// `CURRENCY_ACCESS_BUFFERS`, by lower-case currency code, e.g. `eur:86400,usd:3600`.
+    pub currency_policies: HashMap<String, CurrencyPolicy>,
+    /// `TIER_PRICES`, the Stripe price of each tier, e.g. `1:price_1Nx...,2:price_1Ny...`.
+    pub tier_prices: HashMap<i32, String>,
+    /// `RETRY_MAX_ATTEMPTS`, `RETRY_BASE_DELAY_SECS` and `RETRY_MAX_DELAY_SECS`.
+    pub retry_policy: RetryPolicy,
+}
+
+impl Default for HandlingSettings {
+    fn default() -> Self {
+        HandlingSettings {
+            on_missing_session: MissingSessionBehavior::Skip,
+            conflict_target: Default::default(),
+            user_id_metadata_key: "user_id".to_owned(),
+            tier_metadata_key: "tier_id".to_owned(),
+            payment_grace_period: Duration::from_secs(60 * 60 * 24 * 3),
+            expiry_grace_period: Duration::ZERO,
+            event_timeout: Duration::from_secs(60),
+            event_timeouts: HashMap::new(),
+            redelivery_warning_threshold: 5,
+            redelivery_window: Duration::from_secs(600),
+            update_debounce: Duration::ZERO,
+            store_cancellation_reasons: false,
+            skip_schema_check: false,
+            handle_invoice_upcoming: false,
+            readiness_check_stripe: false,
+            defer_downgrades: false,
+            currency_policies: HashMap::new(),
+            tier_prices: HashMap::new(),
+            retry_policy: Default::default(),
+        }
+    }
+}
+
+pub struct TlsPaths {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// What an admin token may do. Each scope includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdminScope {
+    /// Querying a user's subscription status, for internal services.
+    Status,
+    /// Inspecting events and subscriptions.
+    Read,
+    /// Also starting and changing users' subscriptions through the internal API.
+    Billing,
+    /// Also replaying events, which changes billing state.
+    Replay,
+}
+
+impl std::str::FromStr for AdminScope {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src {
+            "status" => Ok(AdminScope::Status),
+            "read" => Ok(AdminScope::Read),
+            "billing" => Ok(AdminScope::Billing),
+            "replay" => Ok(AdminScope::Replay),
+            _ => Err(format!("Unknown admin scope: {}", src)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AdminToken {
+    pub token: String,
+    pub scope: AdminScope,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAdminToken {
+    token: String,
+    scope: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    database_url: Option<String>,
+    database_sslmode: Option<String>,
+    database_ca_cert_path: Option<String>,
+    db_max_connections: Option<u32>,
+    db_min_idle: Option<u32>,
+    db_connection_timeout_secs: Option<u64>,
+    db_idle_timeout_secs: Option<u64>,
+    db_max_lifetime_secs: Option<u64>,
+    db_connect_attempts: Option<u32>,
+    db_connect_retry_delay_secs: Option<u64>,
+    stripe_secret_key: Option<String>,
+    signing_secret: Option<String>,
+    signing_secrets: Option<Vec<String>>,
+    signature_tolerance_secs: Option<u64>,
+    bind_addr: Option<String>,
+    port: Option<u16>,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    unix_socket_path: Option<String>,
+    admin_token: Option<String>,
+    admin_tokens: Option<Vec<FileAdminToken>>,
+    stripe_api_version: Option<String>,
+    stripe_api_base: Option<String>,
+    stripe_max_retries: Option<u32>,
+    stripe_breaker_threshold: Option<u32>,
+    stripe_breaker_cooldown_secs: Option<u64>,
+    on_missing_session: Option<String>,
+    subscription_conflict_target: Option<String>,
+    user_id_metadata_key: Option<String>,
+    tier_metadata_key: Option<String>,
+    payment_grace_period_secs: Option<u64>,
+    expiry_grace_secs: Option<u64>,
+    event_timeout_secs: Option<u64>,
+    event_timeouts: Option<HashMap<String, u64>>,
+    redelivery_warning_threshold: Option<u32>,
+    redelivery_window_secs: Option<u64>,
+    update_debounce_secs: Option<u64>,
+    store_cancellation_reasons: Option<bool>,
+    skip_schema_check: Option<bool>,
+    handle_invoice_upcoming: Option<bool>,
+    readiness_check_stripe: Option<bool>,
+    defer_downgrades: Option<bool>,
+    currency_access_buffers: Option<HashMap<String, u64>>,
+    tier_prices: Option<HashMap<String, String>>,
+    retry_max_attempts: Option<u32>,
+    retry_base_delay_secs: Option<u64>,
+    retry_max_delay_secs: Option<u64>,
+    webhook_path: Option<String>,
+    max_body_bytes: Option<usize>,
+    processing_mode: Option<String>,
+    sync_event_types: Option<Vec<String>>,
+    background_event_types: Option<Vec<String>>,
+    max_concurrent_events: Option<usize>,
+    queue_capacity: Option<usize>,
+    queue_workers: Option<usize>,
+    worker_concurrency: Option<usize>,
+    worker_poll_millis: Option<u64>,
+    processing_threads: Option<usize>,
+    retry_poll_secs: Option<u64>,
+    expiry_poll_secs: Option<u64>,
+    outbound_poll_secs: Option<u64>,
+    event_silence_warning_secs: Option<u64>,
+    shutdown_timeout_secs: Option<u64>,
+    grpc_port: Option<u16>,
+    sentry_dsn: Option<String>,
+    nats_url: Option<String>,
+    nats_subject_prefix: Option<String>,
+}
+
+impl Config {
+    /// Loads the settings the library needs.
+    pub fn load() -> Result<Self, OtterhoundError> {
+        Config::load_inner(false, &|name| std::env::var(name).ok())
+    }
+
+    /// Loads the settings the webhook server needs, which also include the signing secret.
+    pub fn load_server() -> Result<Self, OtterhoundError> {
+        Config::load_inner(true, &|name| std::env::var(name).ok())
+    }
+
+    /// Loads the settings with the environment variables from `env`, so tests don't need the
+    /// process environment.
+    fn load_inner(
+        require_signing_secret: bool,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, OtterhoundError> {
+        // every problem is collected, so a broken deployment can be fixed in one go
+        let mut problems = Vec::new();
+
+        let file = match env("OTTERHOUND_CONFIG") {
+            Some(path) => match std::fs::read_to_string(&path) {
+                Ok(src) => toml::from_str(&src).unwrap_or_else(|err| {
+                    problems.push(format!("failed to parse {}: {}", path, err));
+                    FileConfig::default()
+                }),
+                Err(err) => {
+                    problems.push(format!("failed to read {}: {}", path, err));
+                    FileConfig::default()
+                }
+            },
+            None => FileConfig::default(),
+        };
+
+        let mut string_setting = |name: &str, env_name: &str, file_value: Option<String>| {
+            let value = env(env_name).or(file_value);
+            if let Some(value) = &value {
+                if value.is_empty() {
+                    problems.push(format!("{} ({}) must not be empty", name, env_name));
+                    return None;
+                }
+            }
+            value
+        };
+
+        let database_url = string_setting("database_url", "DATABASE_URL", file.database_url);
+        let database_sslmode = string_setting(
+            "database_sslmode",
+            "DATABASE_SSLMODE",
+            file.database_sslmode,
+        );
+        let database_ca_cert_path = string_setting(
+            "database_ca_cert_path",
+            "DATABASE_CA_CERT_PATH",
+            file.database_ca_cert_path,
+        );
+        let stripe_secret_key = string_setting(
+            "stripe_secret_key",
+            "STRIPE_SECRET_KEY",
+            file.stripe_secret_key,
+        );
+        let tls_cert_path = string_setting("tls_cert_path", "TLS_CERT_PATH", file.tls_cert_path);
+        let tls_key_path = string_setting("tls_key_path", "TLS_KEY_PATH", file.tls_key_path);
+        let unix_socket_path = string_setting(
+            "unix_socket_path",
+            "UNIX_SOCKET_PATH",
+            file.unix_socket_path,
+        );
+        // a single `ADMIN_TOKEN` has every scope
+        let admin_token = string_setting("admin_token", "ADMIN_TOKEN", file.admin_token);
+        let stripe_api_version = string_setting(
+            "stripe_api_version",
+            "STRIPE_API_VERSION",
+            file.stripe_api_version,
+        );
+        let stripe_api_base =
+            string_setting("stripe_api_base", "STRIPE_API_BASE", file.stripe_api_base);
+        let on_missing_session = string_setting(
+            "on_missing_session",
+            "ON_MISSING_SESSION",
+            file.on_missing_session,
+        );
+        let conflict_target = string_setting(
+            "subscription_conflict_target",
+            "SUBSCRIPTION_CONFLICT_TARGET",
+            file.subscription_conflict_target,
+        );
+        let user_id_metadata_key = string_setting(
+            "user_id_metadata_key",
+            "USER_ID_METADATA_KEY",
+            file.user_id_metadata_key,
+        );
+        let tier_metadata_key = string_setting(
+            "tier_metadata_key",
+            "TIER_METADATA_KEY",
+            file.tier_metadata_key,
+        );
+        let webhook_path = string_setting("webhook_path", "WEBHOOK_PATH", file.webhook_path)
+            .unwrap_or_else(|| "/stripe/webhook".to_owned());
+        let sentry_dsn = string_setting("sentry_dsn", "SENTRY_DSN", file.sentry_dsn);
+        let nats_url = string_setting("nats_url", "NATS_URL", file.nats_url);
+        let nats_subject_prefix = string_setting(
+            "nats_subject_prefix",
+            "NATS_SUBJECT_PREFIX",
+            file.nats_subject_prefix,
+        )
+        .unwrap_or_else(|| "billing".to_owned());
+        let processing_mode =
+            string_setting("processing_mode", "PROCESSING_MODE", file.processing_mode);
+        // `SIGNING_SECRETS` is comma-separated, and takes precedence over a single secret
+        let signing_secrets: Vec<String> = match env("SIGNING_SECRETS") {
+            Some(value) => value
+                .split(',')
+                .map(|secret| secret.trim())
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| secret.to_owned())
+                .collect(),
+            None => match file.signing_secrets {
+                Some(secrets) => secrets,
+                None => string_setting("signing_secret", "SIGNING_SECRET", file.signing_secret)
+                    .into_iter()
+                    .collect(),
+            },
+        };
+
+        match &database_url {
+            Some(url) => {
+                if let Err(err) = url.parse::<tokio_postgres::Config>() {
+                    problems.push(format!("database_url (DATABASE_URL) is invalid: {}", err));
+                }
+            }
+            None => problems.push("database_url (DATABASE_URL) is missing".to_owned()),
+        }
+        let database_ssl_mode = match database_sslmode {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                problems.push(format!(
+                    "database_sslmode (DATABASE_SSLMODE) must be one of disable, prefer, require, verify-ca or verify-full, not {:?}",
+                    value
+                ));
+                DatabaseSslMode::Disable
+            }),
+            None => DatabaseSslMode::Disable,
+        };
+        match &stripe_secret_key {
+            Some(key) => {
+                if !key.starts_with("sk_") && !key.starts_with("rk_") {
+                    problems.push(
+                        "stripe_secret_key (STRIPE_SECRET_KEY) is not a secret or restricted key"
+                            .to_owned(),
+                    );
+                }
+            }
+            None => problems.push("stripe_secret_key (STRIPE_SECRET_KEY) is missing".to_owned()),
+        }
+        if require_signing_secret && signing_secrets.is_empty() {
+            problems.push(
+                "signing_secrets (SIGNING_SECRETS) or signing_secret (SIGNING_SECRET) is missing"
+                    .to_owned(),
+            );
+        }
+
+        // `ADMIN_TOKENS` is comma-separated `token:scope` pairs
+        let scoped_admin_tokens: Vec<(String, String)> = match env("ADMIN_TOKENS") {
+            Some(value) => value
+                .split(',')
+                .map(|entry| entry.trim())
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| match entry.rsplit_once(':') {
+                    Some((token, scope)) => (token.to_owned(), scope.to_owned()),
+                    None => (entry.to_owned(), String::new()),
+                })
+                .collect(),
+            None => file
+                .admin_tokens
+                .unwrap_or_default()
+                .into_iter()
+                .map(|entry| (entry.token, entry.scope))
+                .collect(),
+        };
+        let mut admin_tokens: Vec<AdminToken> = admin_token
+            .map(|token| AdminToken {
+                token,
+                scope: AdminScope::Replay,
+            })
+            .into_iter()
+            .collect();
+        for (token, scope) in scoped_admin_tokens {
+            if token.is_empty() {
+                problems.push("admin_tokens (ADMIN_TOKENS) contains an empty token".to_owned());
+                continue;
+            }
+            match scope.parse() {
+                Ok(scope) => admin_tokens.push(AdminToken { token, scope }),
+                Err(_) => problems.push(format!(
+                    "admin_tokens (ADMIN_TOKENS) scopes must be status, read, billing or replay, not {:?}",
+                    scope
+                )),
+            }
+        }
+
+        let tls = match (tls_cert_path, tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsPaths {
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            _ => {
+                problems.push(
+                    "tls_cert_path (TLS_CERT_PATH) and tls_key_path (TLS_KEY_PATH) must be set together"
+                        .to_owned(),
+                );
+                None
+            }
+        };
+
+        if unix_socket_path.is_some() {
+            if tls.is_some() {
+                problems.push(
+                    "unix_socket_path (UNIX_SOCKET_PATH) can't be combined with TLS".to_owned(),
+                );
+            }
+            if cfg!(not(unix)) {
+                problems.push(
+                    "unix_socket_path (UNIX_SOCKET_PATH) is only supported on Unix".to_owned(),
+                );
+            }
+        }
+
+        let bind_addr = match env("BIND_ADDR").or(file.bind_addr) {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                problems.push(format!(
+                    "bind_addr (BIND_ADDR) is not an IP address: {:?}",
+                    value
+                ));
+                std::net::Ipv6Addr::UNSPECIFIED.into()
+            }),
+            None => std::net::Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        let db_max_connections = number_setting(
+            &mut problems,
+            env,
+            "db_max_connections",
+            "DB_MAX_CONNECTIONS",
+            file.db_max_connections,
+        );
+        let db_min_idle = number_setting(
+            &mut problems,
+            env,
+            "db_min_idle",
+            "DB_MIN_IDLE",
+            file.db_min_idle,
+        );
+        let secs = |value: Option<u64>| value.map(std::time::Duration::from_secs);
+        let db_pool = PoolSettings {
+            max_size: db_max_connections,
+            min_idle: db_min_idle,
+            connection_timeout: secs(number_setting(
+                &mut problems,
+                env,
+                "db_connection_timeout_secs",
+                "DB_CONNECTION_TIMEOUT_SECS",
+                file.db_connection_timeout_secs,
+            )),
+            idle_timeout: secs(number_setting(
+                &mut problems,
+                env,
+                "db_idle_timeout_secs",
+                "DB_IDLE_TIMEOUT_SECS",
+                file.db_idle_timeout_secs,
+            )),
+            max_lifetime: secs(number_setting(
+                &mut problems,
+                env,
+                "db_max_lifetime_secs",
+                "DB_MAX_LIFETIME_SECS",
+                file.db_max_lifetime_secs,
+            )),
+            connect_attempts: number_setting(
+                &mut problems,
+                env,
+                "db_connect_attempts",
+                "DB_CONNECT_ATTEMPTS",
+                file.db_connect_attempts,
+            ),
+            connect_retry_delay: secs(number_setting(
+                &mut problems,
+                env,
+                "db_connect_retry_delay_secs",
+                "DB_CONNECT_RETRY_DELAY_SECS",
+                file.db_connect_retry_delay_secs,
+            )),
+        };
+        let stripe = StripeSettings {
+            api_version: stripe_api_version,
+            api_base: stripe_api_base,
+            max_retries: number_setting(
+                &mut problems,
+                env,
+                "stripe_max_retries",
+                "STRIPE_MAX_RETRIES",
+                file.stripe_max_retries,
+            ),
+            breaker_threshold: number_setting(
+                &mut problems,
+                env,
+                "stripe_breaker_threshold",
+                "STRIPE_BREAKER_THRESHOLD",
+                file.stripe_breaker_threshold,
+            ),
+            breaker_cooldown: secs(number_setting(
+                &mut problems,
+                env,
+                "stripe_breaker_cooldown_secs",
+                "STRIPE_BREAKER_COOLDOWN_SECS",
+                file.stripe_breaker_cooldown_secs,
+            )),
+        };
+        let default = HandlingSettings::default();
+        let payment_grace_period = secs(number_setting(
+            &mut problems,
+            env,
+            "payment_grace_period_secs",
+            "PAYMENT_GRACE_PERIOD_SECS",
+            file.payment_grace_period_secs,
+        ))
+        .unwrap_or(default.payment_grace_period);
+        let expiry_grace_period = secs(number_setting(
+            &mut problems,
+            env,
+            "expiry_grace_secs",
+            "EXPIRY_GRACE_SECS",
+            file.expiry_grace_secs,
+        ))
+        .unwrap_or(default.expiry_grace_period);
+        let event_timeout = secs(number_setting(
+            &mut problems,
+            env,
+            "event_timeout_secs",
+            "EVENT_TIMEOUT_SECS",
+            file.event_timeout_secs,
+        ))
+        .unwrap_or(default.event_timeout);
+        let redelivery_warning_threshold = number_setting(
+            &mut problems,
+            env,
+            "redelivery_warning_threshold",
+            "REDELIVERY_WARNING_THRESHOLD",
+            file.redelivery_warning_threshold,
+        )
+        .unwrap_or(default.redelivery_warning_threshold);
+        let redelivery_window = secs(number_setting(
+            &mut problems,
+            env,
+            "redelivery_window_secs",
+            "REDELIVERY_WINDOW_SECS",
+            file.redelivery_window_secs,
+        ))
+        .unwrap_or(default.redelivery_window);
+        let update_debounce = secs(number_setting(
+            &mut problems,
+            env,
+            "update_debounce_secs",
+            "UPDATE_DEBOUNCE_SECS",
+            file.update_debounce_secs,
+        ))
+        .unwrap_or(default.update_debounce);
+        let retry_policy = RetryPolicy {
+            max_attempts: number_setting(
+                &mut problems,
+                env,
+                "retry_max_attempts",
+                "RETRY_MAX_ATTEMPTS",
+                file.retry_max_attempts,
+            )
+            .unwrap_or(default.retry_policy.max_attempts),
+            base_delay: secs(number_setting(
+                &mut problems,
+                env,
+                "retry_base_delay_secs",
+                "RETRY_BASE_DELAY_SECS",
+                file.retry_base_delay_secs,
+            ))
+            .unwrap_or(default.retry_policy.base_delay),
+            max_delay: secs(number_setting(
+                &mut problems,
+                env,
+                "retry_max_delay_secs",
+                "RETRY_MAX_DELAY_SECS",
+                file.retry_max_delay_secs,
+            ))
+            .unwrap_or(default.retry_policy.max_delay),
+        };
+
+        let max_body_bytes = number_setting(
+            &mut problems,
+            env,
+            "max_body_bytes",
+            "MAX_BODY_BYTES",
+            file.max_body_bytes,
+        )
+        .unwrap_or(256 * 1024);
+        let max_concurrent_events = number_setting(
+            &mut problems,
+            env,
+            "max_concurrent_events",
+            "MAX_CONCURRENT_EVENTS",
+            file.max_concurrent_events,
+        )
+        .unwrap_or(32);
+        let queue_capacity = number_setting(
+            &mut problems,
+            env,
+            "queue_capacity",
+            "QUEUE_CAPACITY",
+            file.queue_capacity,
+        )
+        .unwrap_or(1000);
+        let queue_workers = number_setting(
+            &mut problems,
+            env,
+            "queue_workers",
+            "QUEUE_WORKERS",
+            file.queue_workers,
+        )
+        .unwrap_or(4);
+        let worker_concurrency = number_setting(
+            &mut problems,
+            env,
+            "worker_concurrency",
+            "WORKER_CONCURRENCY",
+            file.worker_concurrency,
+        )
+        .unwrap_or(4);
+        let worker_poll_interval = Duration::from_millis(
+            number_setting(
+                &mut problems,
+                env,
+                "worker_poll_millis",
+                "WORKER_POLL_MILLIS",
+                file.worker_poll_millis,
+            )
+            .unwrap_or(1000),
+        );
+        let processing_threads = number_setting(
+            &mut problems,
+            env,
+            "processing_threads",
+            "PROCESSING_THREADS",
+            file.processing_threads,
+        );
+        // the poll intervals disable their task when zero, e.g. when another instance runs it
+        let retry_poll_interval = Duration::from_secs(
+            number_setting(
+                &mut problems,
+                env,
+                "retry_poll_secs",
+                "RETRY_POLL_SECS",
+                file.retry_poll_secs,
+            )
+            .unwrap_or(30),
+        );
+        let expiry_poll_interval = Duration::from_secs(
+            number_setting(
+                &mut problems,
+                env,
+                "expiry_poll_secs",
+                "EXPIRY_POLL_SECS",
+                file.expiry_poll_secs,
+            )
+            .unwrap_or(60),
+        );
+        let outbound_poll_interval = Duration::from_secs(
+            number_setting(
+                &mut problems,
+                env,
+                "outbound_poll_secs",
+                "OUTBOUND_POLL_SECS",
+                file.outbound_poll_secs,
+            )
+            .unwrap_or(5),
+        );
+        let event_silence_warning = secs(number_setting(
+            &mut problems,
+            env,
+            "event_silence_warning_secs",
+            "EVENT_SILENCE_WARNING_SECS",
+            file.event_silence_warning_secs,
+        ));
+        let shutdown_timeout = Duration::from_secs(
+            number_setting(
+                &mut problems,
+                env,
+                "shutdown_timeout_secs",
+                "SHUTDOWN_TIMEOUT_SECS",
+                file.shutdown_timeout_secs,
+            )
+            .unwrap_or(30),
+        );
+
+        if db_pool.max_size == Some(0) {
+            problems.push("db_max_connections (DB_MAX_CONNECTIONS) must be at least 1".to_owned());
+        }
+        if db_pool.connect_attempts == Some(0) {
+            problems
+                .push("db_connect_attempts (DB_CONNECT_ATTEMPTS) must be at least 1".to_owned());
+        }
+        if db_pool.connection_timeout == Some(std::time::Duration::ZERO) {
+            problems.push(
+                "db_connection_timeout_secs (DB_CONNECTION_TIMEOUT_SECS) must be at least 1"
+                    .to_owned(),
+            );
+        }
+
+        let port = match env("PORT") {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                problems.push(format!("port (PORT) is not a valid port: {:?}", value));
+                0
+            }),
+            None => file.port.unwrap_or(6868),
+        };
+
+        let grpc_port = match env("GRPC_PORT") {
+            Some(value) => value.parse().map(Some).unwrap_or_else(|_| {
+                problems.push(format!(
+                    "grpc_port (GRPC_PORT) is not a valid port: {:?}",
+                    value
+                ));
+                None
+            }),
+            None => file.grpc_port,
+        };
+        if grpc_port.is_some() && admin_tokens.is_empty() {
+            problems.push(
+                "grpc_port (GRPC_PORT) requires admin_tokens to authenticate calls with".to_owned(),
+            );
+        }
+
+        if !webhook_path.starts_with('/') {
+            problems.push(format!(
+                "webhook_path (WEBHOOK_PATH) must start with /, not {:?}",
+                webhook_path
+            ));
+        }
+        let processing_mode = match processing_mode {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                problems.push(format!(
+                    "processing_mode (PROCESSING_MODE) must be background, sync or queue, not {:?}",
+                    value
+                ));
+                ProcessingMode::Background
+            }),
+            None => ProcessingMode::Background,
+        };
+        // `SYNC_EVENT_TYPES` and `BACKGROUND_EVENT_TYPES` are comma-separated
+        let event_types = |env_name: &str, file_value: Option<Vec<String>>| -> Vec<String> {
+            match env(env_name) {
+                Some(value) => value
+                    .split(',')
+                    .map(|event_type| event_type.trim())
+                    .filter(|event_type| !event_type.is_empty())
+                    .map(|event_type| event_type.to_owned())
+                    .collect(),
+                None => file_value.unwrap_or_default(),
+            }
+        };
+        let processing_mode_overrides = event_types("SYNC_EVENT_TYPES", file.sync_event_types)
+            .into_iter()
+            .map(|event_type| (event_type, ProcessingMode::Sync))
+            .chain(
+                event_types("BACKGROUND_EVENT_TYPES", file.background_event_types)
+                    .into_iter()
+                    .map(|event_type| (event_type, ProcessingMode::Background)),
+            )
+            .collect();
+        for (value, name) in [
+            (
+                max_concurrent_events,
+                "max_concurrent_events (MAX_CONCURRENT_EVENTS)",
+            ),
+            (queue_capacity, "queue_capacity (QUEUE_CAPACITY)"),
+            (queue_workers, "queue_workers (QUEUE_WORKERS)"),
+            (
+                worker_concurrency,
+                "worker_concurrency (WORKER_CONCURRENCY)",
+            ),
+            (
+                processing_threads.unwrap_or(1),
+                "processing_threads (PROCESSING_THREADS)",
+            ),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+        if event_silence_warning == Some(Duration::ZERO) {
+            problems.push(
+                "event_silence_warning_secs (EVENT_SILENCE_WARNING_SECS) must be at least 1"
+                    .to_owned(),
+            );
+        }
+
+        let signature_tolerance = match env("SIGNATURE_TOLERANCE_SECS") {
+            Some(value) => value.parse().map(std::time::Duration::from_secs).unwrap_or_else(|_| {
+                problems.push(format!(
+                    "signature_tolerance_secs (SIGNATURE_TOLERANCE_SECS) is not a number of seconds: {:?}",
+                    value
+                ));
+                Default::default()
+            }),
+            None => file
+                .signature_tolerance_secs
+                .map(std::time::Duration::from_secs)
+                .unwrap_or(crate::signature::DEFAULT_TOLERANCE),
+        };
+
+        let mut flag_setting =
+            |name: &str, env_name: &str, file_value: Option<bool>| match env(env_name) {
+                Some(value) => match value.as_ref() {
+                    "1" | "true" | "yes" => true,
+                    "" | "0" | "false" | "no" => false,
+                    _ => {
+                        problems.push(format!(
+                            "{} ({}) must be true or false, not {:?}",
+                            name, env_name, value
+                        ));
+                        false
+                    }
+                },
+                None => file_value.unwrap_or(false),
+            };
+        let store_cancellation_reasons = flag_setting(
+            "store_cancellation_reasons",
+            "STORE_CANCELLATION_REASONS",
+            file.store_cancellation_reasons,
+        );
+        let skip_schema_check = flag_setting(
+            "skip_schema_check",
+            "SKIP_SCHEMA_CHECK",
+            file.skip_schema_check,
+        );
+        let handle_invoice_upcoming = flag_setting(
+            "handle_invoice_upcoming",
+            "HANDLE_INVOICE_UPCOMING",
+            file.handle_invoice_upcoming,
+        );
+        let readiness_check_stripe = flag_setting(
+            "readiness_check_stripe",
+            "READINESS_CHECK_STRIPE",
+            file.readiness_check_stripe,
+        );
+        let defer_downgrades = flag_setting(
+            "defer_downgrades",
+            "DEFER_DOWNGRADES",
+            file.defer_downgrades,
+        );
+
+        // maps are comma-separated `key:value` pairs in the environment, and tables in the file
+        let mut pairs_setting = |name: &str,
+                                 env_name: &str,
+                                 file_value: Option<Vec<(String, String)>>|
+         -> Vec<(String, String)> {
+            match env(env_name) {
+                Some(value) => value
+                    .split(',')
+                    .map(|entry| entry.trim())
+                    .filter(|entry| !entry.is_empty())
+                    .filter_map(|entry| match entry.rsplit_once(':') {
+                        Some((key, value)) => Some((key.to_owned(), value.to_owned())),
+                        None => {
+                            problems.push(format!(
+                                "{} ({}) entries must be key:value pairs, not {:?}",
+                                name, env_name, entry
+                            ));
+                            None
+                        }
+                    })
+                    .collect(),
+                None => file_value.unwrap_or_default(),
+            }
+        };
+        let to_pairs = |table: HashMap<String, u64>| {
+            table
+                .into_iter()
+                .map(|(key, value)| (key, value.to_string()))
+                .collect()
+        };
+        let event_timeout_pairs = pairs_setting(
+            "event_timeouts",
+            "EVENT_TIMEOUTS",
+            file.event_timeouts.map(to_pairs),
+        );
+        let currency_access_buffer_pairs = pairs_setting(
+            "currency_access_buffers",
+            "CURRENCY_ACCESS_BUFFERS",
+            file.currency_access_buffers.map(to_pairs),
+        );
+        let tier_price_pairs = pairs_setting(
+            "tier_prices",
+            "TIER_PRICES",
+            file.tier_prices.map(|table| table.into_iter().collect()),
+        );
+
+        let mut event_timeouts = HashMap::new();
+        for (event_type, value) in event_timeout_pairs {
+            match value.parse() {
+                Ok(secs) => {
+                    event_timeouts.insert(event_type, Duration::from_secs(secs));
+                }
+                Err(_) => problems.push(format!(
+                    "event_timeouts (EVENT_TIMEOUTS) for {} is not a number of seconds: {:?}",
+                    event_type, value
+                )),
+            }
+        }
+        let mut currency_policies = HashMap::new();
+        for (currency, value) in currency_access_buffer_pairs {
+            match value.parse() {
+                Ok(secs) => {
+                    currency_policies.insert(
+                        currency.to_lowercase(),
+                        CurrencyPolicy {
+                            access_buffer: Duration::from_secs(secs),
+                        },
+                    );
+                }
+                Err(_) => problems.push(format!(
+                    "currency_access_buffers (CURRENCY_ACCESS_BUFFERS) for {} is not a number of seconds: {:?}",
+                    currency, value
+                )),
+            }
+        }
+        let mut tier_prices = HashMap::new();
+        for (tier_id, price) in tier_price_pairs {
+            match tier_id.parse() {
+                Ok(tier_id) => {
+                    tier_prices.insert(tier_id, price);
+                }
+                Err(_) => problems.push(format!(
+                    "tier_prices (TIER_PRICES) keys must be tier IDs, not {:?}",
+                    tier_id
+                )),
+            }
+        }
+
+        let on_missing_session = match on_missing_session {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                problems.push(format!(
+                    "on_missing_session (ON_MISSING_SESSION) must be error or skip, not {:?}",
+                    value
+                ));
+                default.on_missing_session
+            }),
+            None => default.on_missing_session,
+        };
+        let conflict_target = match conflict_target {
+            Some(value) => value.parse().unwrap_or_else(|err| {
+                problems.push(format!(
+                    "subscription_conflict_target (SUBSCRIPTION_CONFLICT_TARGET) is invalid: {}",
+                    err
+                ));
+                ConflictTarget::default()
+            }),
+            None => ConflictTarget::default(),
+        };
+
+        let handling = HandlingSettings {
+            on_missing_session,
+            conflict_target,
+            user_id_metadata_key: user_id_metadata_key.unwrap_or(default.user_id_metadata_key),
+            tier_metadata_key: tier_metadata_key.unwrap_or(default.tier_metadata_key),
+            payment_grace_period,
+            expiry_grace_period,
+            event_timeout,
+            event_timeouts,
+            redelivery_warning_threshold,
+            redelivery_window,
+            update_debounce,
+            store_cancellation_reasons,
+            skip_schema_check,
+            handle_invoice_upcoming,
+            readiness_check_stripe,
+            defer_downgrades,
+            currency_policies,
+            tier_prices,
+            retry_policy,
+        };
+
+        if !problems.is_empty() {
+            return Err(OtterhoundError::Config(format!(
+                "{} problem(s):\n  - {}",
+                problems.len(),
+                problems.join("\n  - ")
+            )));
+        }
+
+        Ok(Config {
+            database_url: database_url.unwrap(),
+            database_ssl_mode,
+            database_ca_cert_path,
+            db_pool,
+            stripe_secret_key: stripe_secret_key.unwrap(),
+            stripe,
+            handling,
+            signing_secrets,
+            signature_tolerance,
+            bind_addr,
+            port,
+            tls,
+            unix_socket_path,
+            admin_tokens,
+            webhook_path,
+            max_body_bytes,
+            processing_mode,
+            processing_mode_overrides,
+            max_concurrent_events,
+            queue_capacity,
+            queue_workers,
+            worker_concurrency,
+            worker_poll_interval,
+            processing_threads,
+            retry_poll_interval,
+            expiry_poll_interval,
+            outbound_poll_interval,
+            event_silence_warning,
+            shutdown_timeout,
+            grpc_port,
+            sentry_dsn,
+            nats_url,
+            nats_subject_prefix,
+        })
+    }
+}
+
+/// Reads a number from `env_name`, falling back to the file's value. A value that doesn't parse
+/// as a `T`, including one out of its range, is a problem rather than being truncated.
+fn number_setting<T>(
+    problems: &mut Vec<String>,
+    env: &dyn Fn(&str) -> Option<String>,
+    name: &str,
+    env_name: &str,
+    file_value: Option<T>,
+) -> Option<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env(env_name) {
+        Some(value) => value.parse().map(Some).unwrap_or_else(|err| {
+            problems.push(format!(
+                "{} ({}) is not a valid number: {:?} ({})",
+                name, env_name, value, err
+            ));
+            None
+        }),
+        None => file_value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUIRED: &[(&str, &str)] = &[
+        ("DATABASE_URL", "postgres://localhost/otterhound"),
+        ("STRIPE_SECRET_KEY", "sk_test_123"),
+    ];
+
+    fn load(vars: &[(&str, &str)]) -> Result<Config, OtterhoundError> {
+        let env = |name: &str| {
+            vars.iter()
+                .chain(REQUIRED)
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value).to_owned())
+        };
+        Config::load_inner(false, &env)
+    }
+
+    fn problems(vars: &[(&str, &str)]) -> String {
+        match load(vars) {
+            Err(OtterhoundError::Config(problems)) => problems,
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Loaded despite {:?}", vars),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(&[]).unwrap();
+        assert_eq!(config.port, 6868);
+        assert_eq!(config.webhook_path, "/stripe/webhook");
+        assert_eq!(config.max_body_bytes, 256 * 1024);
+        assert_eq!(config.processing_mode, ProcessingMode::Background);
+        assert_eq!(config.retry_poll_interval, Duration::from_secs(30));
+        assert_eq!(config.nats_subject_prefix, "billing");
+        assert_eq!(config.db_pool.max_size, None);
+        assert_eq!(config.processing_threads, None);
+        assert!(config.signing_secrets.is_empty());
+        assert!(config.admin_tokens.is_empty());
+    }
+
+    #[test]
+    fn missing_required_settings() {
+        let problems = match Config::load_inner(true, &|_| None) {
+            Err(OtterhoundError::Config(problems)) => problems,
+            _ => panic!("Loaded without any settings"),
+        };
+        assert!(problems.starts_with("3 problem(s)"), "{}", problems);
+        assert!(problems.contains("database_url (DATABASE_URL) is missing"));
+        assert!(problems.contains("stripe_secret_key (STRIPE_SECRET_KEY) is missing"));
+        assert!(problems.contains("signing_secret (SIGNING_SECRET) is missing"));
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        let problems = problems(&[
+            ("DB_MAX_CONNECTIONS", "4294967296"),
+            ("QUEUE_WORKERS", "-1"),
+            ("RETRY_POLL_SECS", "soon"),
+        ]);
+        assert!(problems.starts_with("3 problem(s)"), "{}", problems);
+        assert!(problems.contains(
+            "db_max_connections (DB_MAX_CONNECTIONS) is not a valid number: \"4294967296\""
+        ));
+        assert!(problems.contains("queue_workers (QUEUE_WORKERS) is not a valid number"));
+        assert!(problems.contains("retry_poll_secs (RETRY_POLL_SECS) is not a valid number"));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let problems = problems(&[
+            ("STRIPE_SECRET_KEY", "pk_test_123"),
+            ("WEBHOOK_PATH", "stripe"),
+            ("PROCESSING_MODE", "later"),
+            ("DB_MAX_CONNECTIONS", "0"),
+            ("TLS_CERT_PATH", "cert.pem"),
+            ("SENTRY_DSN", ""),
+        ]);
+        assert!(problems.starts_with("6 problem(s)"), "{}", problems);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let path = std::env::temp_dir().join(format!(
+            "otterhound_config_test_{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "port = 7000\nwebhook_path = \"/hooks/stripe\"\ndb_max_connections = 20\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = load(&[("OTTERHOUND_CONFIG", path), ("PORT", "8000")]);
+        std::fs::remove_file(path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.webhook_path, "/hooks/stripe");
+        assert_eq!(config.db_pool.max_size, Some(20));
+    }
+
+    #[test]
+    fn unknown_file_settings() {
+        let path = std::env::temp_dir().join(format!(
+            "otterhound_config_unknown_test_{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "prot = 7000\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let problems = problems(&[("OTTERHOUND_CONFIG", path)]);
+        std::fs::remove_file(path).unwrap();
+        assert!(problems.contains("failed to parse"), "{}", problems);
+        assert!(problems.contains("prot"), "{}", problems);
+    }
+}
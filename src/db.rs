@@ -0,0 +1,109 @@
+//! Transaction helpers.
+
+use std::time::Duration;
+
+use crate::{DbPool, DbRetryReason, OtterhoundError};
+
+/// How many times a transaction is retried after a serialization failure, deadlock or lost
+/// connection before the error is returned.
+const MAX_TRANSACTION_RETRIES: u32 = 3;
+
+/// Runs `f` in a transaction, committing it if `f` succeeds and rolling it back if it fails.
+///
+/// A transaction failing on a serialization failure, a deadlock or a lost connection is retried
+/// from the start on a fresh connection, with a fresh clone of `f`, so `f` may run more than
+/// once. Each retry is counted in `otterhound_db_transaction_retries_total`.
+pub async fn with_transaction<T, F>(db_pool: &DbPool, f: F) -> Result<T, OtterhoundError>
+where
+    F: AsyncFnOnce(&tokio_postgres::Transaction<'_>) -> Result<T, OtterhoundError> + Clone,
+{
+    let mut retries = 0;
+    loop {
+        let mut conn = db_pool.get().await?;
+        let err = match conn.transaction().await {
+            Ok(txn) => match f.clone()(&txn).await {
+                Ok(value) => match txn.commit().await {
+                    Ok(()) => return Ok(value),
+                    Err(err) => match OtterhoundError::from(err) {
+                        // it may have been applied before the connection was lost
+                        OtterhoundError::DbRetryable(DbRetryReason::ConnReset, msg) => {
+                            return Err(OtterhoundError::Db(msg))
+                        }
+                        err => err,
+                    },
+                },
+                Err(err) => {
+                    if let Err(rollback_err) = txn.rollback().await {
+                        tracing::warn!("Failed to roll back transaction: {}", rollback_err);
+                    }
+                    err
+                }
+            },
+            Err(err) => err.into(),
+        };
+        // a lost connection is discarded by the pool when this is dropped
+        drop(conn);
+
+        let (reason, msg) = match err {
+            OtterhoundError::DbRetryable(reason, msg) if retries < MAX_TRANSACTION_RETRIES => {
+                (reason, msg)
+            }
+            err => return Err(err),
+        };
+        retries += 1;
+        tracing::warn!(
+            "Retrying transaction ({} of {}) after {}: {}",
+            retries,
+            MAX_TRANSACTION_RETRIES,
+            reason.as_str(),
+            msg
+        );
+        metrics::counter!("otterhound_db_transaction_retries_total", "reason" => reason.as_str())
+            .increment(1);
+        // staggered, so transactions that deadlocked each other don't collide again
+        tokio::time::sleep(Duration::from_millis(20) * retries).await;
+    }
+}
+
+/// Like `with_transaction`, but also records `event_id` in `stripe_events`, so an event's writes
+/// are applied at most once. Returns `None` without running `f` if the event was already
+/// processed.
+#[tracing::instrument(skip(db_pool, f))]
+pub async fn with_event_transaction<T, F>(
+    db_pool: &DbPool,
+    event_id: &str,
+    f: F,
+) -> Result<Option<T>, OtterhoundError>
+where
+    F: AsyncFnOnce(&tokio_postgres::Transaction<'_>) -> Result<T, OtterhoundError> + Clone,
+{
+    with_transaction(db_pool, async |txn| {
+        let count = txn
+            .execute(
+                "INSERT INTO stripe_events (id) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&event_id],
+            )
+            .await?;
+        if count == 0 {
+            tracing::info!("Event {} was already processed, skipping", event_id);
+            return Ok(None);
+        }
+
+        f(txn).await.map(Some)
+    })
+    .await
+}
+
+/// Runs a single statement for an event, see `with_event_transaction`.
+#[tracing::instrument(skip(db_pool, query, params))]
+pub async fn execute_for_event(
+    db_pool: &DbPool,
+    event_id: &str,
+    query: &str,
+    params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+) -> Result<Option<u64>, OtterhoundError> {
+    with_event_transaction(db_pool, event_id, async |txn| {
+        Ok(txn.execute(query, params).await?)
+    })
+    .await
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::OtterhoundError;
+
+/// An update that can absorb the one after it, e.g. by keeping the later state.
+pub trait Coalesce {
+    fn coalesce(self, later: Self) -> Self;
+}
+
+type Outcome = Option<Result<(), OtterhoundError>>;
+
+struct Batch<T> {
+    id: u64,
+    // by event time
+    updates: Vec<(u64, T)>,
+    outcome: watch::Receiver<Outcome>,
+}
+
+enum Joined {
+    /// The update started a batch with this ID, whose outcome is sent once applied.
+    Started(u64, watch::Sender<Outcome>),
+    /// The update joined a batch, whose outcome is awaited.
+    Waiting(watch::Receiver<Outcome>),
+}
+
+struct DebouncerState<T> {
+    batches: HashMap<String, Batch<T>>,
+    next_id: u64,
+}
+
+/// Coalesces updates to the same key that arrive within `window` of the first, so only one is
+/// applied. Every caller waits for that one and gets its result, so an update is only reported
+/// done once it has been written. A zero window applies each update right away.
+pub struct Debouncer<T> {
+    window: Duration,
+    state: Mutex<DebouncerState<T>>,
+    flushing: watch::Sender<bool>,
+}
+
+impl<T: Coalesce> Debouncer<T> {
+    pub fn new(window: Duration) -> Self {
+        Debouncer {
+            window,
+            state: Mutex::new(DebouncerState {
+                batches: HashMap::new(),
+                next_id: 0,
+            }),
+            flushing: watch::channel(false).0,
+        }
+    }
+
+    /// Buffers `update` for `key`, ordered by its event time `created`. The first update for a
+    /// key waits out the window and then applies what the window coalesced; later ones within
+    /// the window only wait for that.
+    pub async fn run(
+        &self,
+        key: &str,
+        created: u64,
+        update: T,
+        apply: impl AsyncFnOnce(T) -> Result<(), OtterhoundError>,
+    ) -> Result<(), OtterhoundError> {
+        if self.window.is_zero() || *self.flushing.borrow() {
+            return apply(update).await;
+        }
+
+        let joined = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+
+            match state.batches.get_mut(key) {
+                Some(batch) => {
+                    batch.updates.push((created, update));
+                    Joined::Waiting(batch.outcome.clone())
+                }
+                None => {
+                    let (sender, outcome) = watch::channel(None);
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    state.batches.insert(
+                        key.to_owned(),
+                        Batch {
+                            id,
+                            updates: vec![(created, update)],
+                            outcome,
+                        },
+                    );
+                    Joined::Started(id, sender)
+                }
+            }
+        };
+        let (id, sender) = match joined {
+            Joined::Started(id, sender) => (id, sender),
+            Joined::Waiting(mut outcome) => {
+                metrics::counter!("otterhound_coalesced_updates_total").increment(1);
+                let outcome = outcome
+                    .wait_for(Option::is_some)
+                    .await
+                    .map(|res| res.clone());
+                return match outcome {
+                    Ok(Some(res)) => res,
+                    _ => Err(OtterhoundError::Abandoned(key.to_owned())),
+                };
+            }
+        };
+
+        let mut batch = BatchGuard {
+            debouncer: self,
+            key,
+            id,
+        };
+        let mut flushing = self.flushing.subscribe();
+        tokio::select! {
+            _ = tokio::time::sleep(self.window) => {}
+            _ = flushing.wait_for(|flushing| *flushing) => {}
+        }
+
+        let mut updates = batch.take();
+        // events can arrive out of order
+        updates.sort_by_key(|(created, _)| *created);
+        let update = updates
+            .into_iter()
+            .map(|(_, update)| update)
+            .reduce(T::coalesce)
+            .expect("a batch starts with an update");
+        let res = apply(update).await;
+        let _ = sender.send(Some(res.clone()));
+        res
+    }
+
+    /// Applies whatever is buffered right away, and any later updates without waiting, for
+    /// shutting down.
+    pub fn flush(&self) {
+        self.flushing.send_replace(true);
+    }
+}
+
+/// Removes a batch from the debouncer once applied, or once its first caller is dropped, e.g.
+/// by a timeout, so later updates start a batch of their own. The other callers then see the
+/// outcome sender dropped.
+struct BatchGuard<'a, T> {
+    debouncer: &'a Debouncer<T>,
+    key: &'a str,
+    id: u64,
+}
+
+impl<T> BatchGuard<'_, T> {
+    /// The batch's updates, or none if it was already taken.
+    fn take(&mut self) -> Vec<(u64, T)> {
+        let mut state = self.debouncer.state.lock().unwrap();
+        match state.batches.get(self.key) {
+            Some(batch) if batch.id == self.id => state
+                .batches
+                .remove(self.key)
+                .map(|batch| batch.updates)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<T> Drop for BatchGuard<'_, T> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what each applied update coalesced, in order
+    impl Coalesce for Vec<u64> {
+        fn coalesce(mut self, later: Self) -> Self {
+            self.extend(later);
+            self
+        }
+    }
+
+    struct Applied(Mutex<Vec<Vec<u64>>>);
+
+    impl Applied {
+        fn new() -> Self {
+            Applied(Mutex::new(Vec::new()))
+        }
+
+        async fn apply(&self, update: Vec<u64>) -> Result<(), OtterhoundError> {
+            self.0.lock().unwrap().push(update);
+            Ok(())
+        }
+
+        fn take(&self) -> Vec<Vec<u64>> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_updates_within_window() {
+        let debouncer = Debouncer::new(Duration::from_millis(50));
+        let applied = Applied::new();
+        let run = |key: &'static str, created: u64| {
+            let applied = &applied;
+            let debouncer = &debouncer;
+            async move {
+                debouncer
+                    .run(key, created, vec![created], async |update| {
+                        applied.apply(update).await
+                    })
+                    .await
+            }
+        };
+
+        let results = futures::future::join_all([
+            run("sub_1", 1),
+            run("sub_1", 3),
+            run("sub_2", 4),
+            run("sub_1", 2),
+        ])
+        .await;
+
+        assert!(results.iter().all(Result::is_ok));
+        // ordered by event time, not arrival
+        let mut applied_updates = applied.take();
+        applied_updates.sort();
+        assert_eq!(applied_updates, vec![vec![1, 2, 3], vec![4]]);
+
+        // the window starts again with the next update
+        run("sub_1", 5).await.unwrap();
+        assert_eq!(applied.take(), vec![vec![5]]);
+    }
+
+    #[tokio::test]
+    async fn every_update_gets_the_outcome() {
+        let debouncer = Debouncer::new(Duration::from_millis(50));
+        let run = |created: u64| {
+            debouncer.run("sub_1", created, vec![created], async |_| {
+                Err(OtterhoundError::Db("connection refused".to_owned()))
+            })
+        };
+
+        let (first, second) = tokio::join!(run(1), run(2));
+
+        assert!(matches!(first, Err(OtterhoundError::Db(_))));
+        assert!(matches!(second, Err(OtterhoundError::Db(_))));
+    }
+
+    #[tokio::test]
+    async fn flush_applies_buffered_updates() {
+        let debouncer = Debouncer::new(Duration::from_secs(60));
+        let applied = Applied::new();
+
+        let (res, ()) = tokio::join!(
+            debouncer.run("sub_1", 1, vec![1], async |update| {
+                applied.apply(update).await
+            }),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                debouncer.flush();
+            }
+        );
+        res.unwrap();
+        assert_eq!(applied.take(), vec![vec![1]]);
+
+        // nothing is buffered once flushing
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            debouncer.run("sub_1", 2, vec![2], async |update| {
+                applied.apply(update).await
+            }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(applied.take(), vec![vec![2]]);
+    }
+
+    #[tokio::test]
+    async fn abandoned_batch_fails_waiting_updates() {
+        let debouncer = Debouncer::new(Duration::from_secs(60));
+        let applied = Applied::new();
+
+        let (first, second) = tokio::join!(
+            tokio::time::timeout(
+                Duration::from_millis(50),
+                debouncer.run("sub_1", 1, vec![1], async |update| {
+                    applied.apply(update).await
+                }),
+            ),
+            debouncer.run("sub_1", 2, vec![2], async |update| {
+                applied.apply(update).await
+            }),
+        );
+
+        assert!(first.is_err());
+        assert!(matches!(second, Err(OtterhoundError::Abandoned(sub)) if sub == "sub_1"));
+        assert!(applied.take().is_empty());
+        assert!(debouncer.state.lock().unwrap().batches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn zero_window_applies_right_away() {
+        let debouncer = Debouncer::new(Duration::ZERO);
+        let applied = Applied::new();
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            debouncer.run("sub_1", 1, vec![1], async |update| {
+                applied.apply(update).await
+            }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(applied.take(), vec![vec![1]]);
+    }
+}
@@ -1,4 +1,3 @@
-use futures::{Future, Stream};
 use serde_derive::Deserialize;
 
 use otterhound::EventItem;
@@ -9,81 +8,62 @@ struct EventListResponse {
 }
 
 fn main() {
-    let auth_header = otterhound::gen_auth_header();
-    let auth_header: &str = &auth_header;
+    // before the runtime starts, see `init_logging`
+    let _telemetry = otterhound::init_logging();
 
-    let mut runtime = tokio::runtime::Runtime::new().expect("Failed to initialize Tokio");
-
-    let client = {
-        let connector =
-            hyper_tls::HttpsConnector::new(2).expect("Failed to initialize HTTPS client");
-        std::sync::Arc::new(hyper::Client::builder().build(connector))
-    };
+    tokio::runtime::Runtime::new()
+        .expect("Failed to initialize Tokio")
+        .block_on(poll());
+}
 
-    let otterhound = {
-        let auth_header = auth_header.to_owned();
-        let client = client.clone();
-        runtime
-            .block_on(futures::future::lazy(|| {
-                otterhound::Otterhound::new_with_some(auth_header, client)
-            }))
-            .expect("Failed to initialize")
-    };
+async fn poll() {
+    let otterhound = std::sync::Arc::new(
+        otterhound::Otterhound::new()
+            .await
+            .expect("Failed to initialize"),
+    );
 
     let mut last_ts: Option<u64> = None;
 
     loop {
-        let result = hyper::Request::get(&format!(
-            "https://api.stripe.com/v1/events{}",
-            match last_ts {
-                Some(last_ts) => format!("?created[gt]={}", last_ts),
-                None => "".to_owned(),
-            }
-        ))
-        .header("Authorization", auth_header)
-        .body(hyper::Body::empty())
-        .map_err(|err| format!("Failed to construct request: {:?}", err))
-        .and_then(|req| {
-            runtime
-                .block_on(client.request(req).and_then(|res| {
-                    let status = res.status();
-                    res.into_body().concat2().map(move |body| (body, status))
-                }))
-                .map_err(|err| format!("Failed to send request: {:?}", err))
-        })
-        .and_then(|(body, status)| {
-            if status.is_success() {
-                serde_json::from_slice(&body)
-                    .map_err(|err| format!("Failed to parse response: {:?}", err))
-            } else {
-                Err(format!("Received error from API: {:?}", body))
-            }
-        })
-        .and_then(|resp: EventListResponse| {
+        let result = async {
+            let resp: EventListResponse = otterhound
+                .stripe()
+                .get(&format!(
+                    "events{}",
+                    match last_ts {
+                        Some(last_ts) => format!("?created[gt]={}", last_ts),
+                        None => "".to_owned(),
+                    }
+                ))
+                .await?;
+
             let new_last_ts = resp.data.iter().map(|item| item.created).max();
             if let Some(new_last_ts) = new_last_ts {
-                let old_last_ts = std::mem::replace(&mut last_ts, Some(new_last_ts));
+                let old_last_ts = last_ts.replace(new_last_ts);
 
                 if old_last_ts.is_some() {
                     for item in resp.data {
-                        runtime.spawn(
-                            otterhound
-                                .handle_event(item)
-                                .map_err(|err| eprintln!("Error handling event: {}", err)),
-                        );
+                        let otterhound = otterhound.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = otterhound.handle_event(item).await {
+                                tracing::error!("Error handling event: {}", err);
+                            }
+                        });
                     }
                 } else {
-                    println!("Got first batch, enabling");
+                    tracing::info!("Got first batch, enabling");
                 }
             }
 
-            Ok(())
-        });
+            Ok::<_, otterhound::OtterhoundError>(())
+        }
+        .await;
 
         if let Err(err) = result {
-            eprintln!("Error in loop: {:?}", err);
+            tracing::error!("Error in loop: {:?}", err);
         }
 
-        std::thread::sleep(std::time::Duration::new(2, 0));
+        tokio::time::sleep(std::time::Duration::new(2, 0)).await;
     }
 }
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// Errors returned by `Otterhound`, classified so callers can pick a response.
+#[derive(Clone, Debug)]
+pub enum OtterhoundError {
+    /// A database query or transaction failed.
+    Db(String),
+    /// A database query or transaction failed in a way that retrying the whole transaction may
+    /// get past.
+    DbRetryable(DbRetryReason, String),
+    /// A Stripe API request failed or Stripe returned an error.
+    StripeApi(String),
+    /// The Stripe circuit breaker is open, so no request was sent.
+    StripeUnavailable,
+    /// An event or object being handled couldn't be parsed or holds an impossible value.
+    Parse(String),
+    /// A webhook request was malformed, e.g. its body isn't an event.
+    InvalidRequest(String),
+    /// A webhook signature was missing or didn't match.
+    Signature(String),
+    /// A webhook signature was valid, but signed this long ago or ahead, outside the tolerance.
+    /// Either a replay or a badly skewed clock.
+    Replay(std::time::Duration),
+    /// Something the event refers to doesn't exist.
+    NotFound(String),
+    /// The configuration is invalid.
+    Config(String),
+    /// Handling an event took longer than its timeout and was abandoned.
+    TimedOut(std::time::Duration),
+    /// A handler panicked, with the panic message.
+    Panicked(String),
+    /// An update debounced together with others was never applied, because handling the event
+    /// that started the batch was abandoned. Holds the subscription.
+    Abandoned(String),
+    /// A billing event couldn't be published to the message bus.
+    Publish(String),
+}
+
+impl fmt::Display for OtterhoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OtterhoundError::Db(msg) | OtterhoundError::DbRetryable(_, msg) => {
+                write!(f, "Database error: {}", msg)
+            }
+            OtterhoundError::StripeApi(msg) => write!(f, "Stripe API error: {}", msg),
+            OtterhoundError::StripeUnavailable => {
+                write!(f, "Stripe API circuit is open, not sending request")
+            }
+            OtterhoundError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            OtterhoundError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            OtterhoundError::Signature(msg) => write!(f, "Signature error: {}", msg),
+            OtterhoundError::Replay(offset) => write!(
+                f,
+                "Signed timestamp is {}s off, outside the tolerance window",
+                offset.as_secs()
+            ),
+            OtterhoundError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            OtterhoundError::Config(msg) => write!(f, "Configuration error: {}", msg),
+            OtterhoundError::TimedOut(timeout) => {
+                write!(f, "Handling the event timed out after {:?}", timeout)
+            }
+            OtterhoundError::Panicked(msg) => write!(f, "Handler panicked: {}", msg),
+            OtterhoundError::Abandoned(sub) => {
+                write!(f, "Debounced update of {} was abandoned", sub)
+            }
+            OtterhoundError::Publish(msg) => write!(f, "Failed to publish event: {}", msg),
+        }
+    }
+}
+
+/// Why a failed transaction is worth retrying.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbRetryReason {
+    /// It conflicted with a concurrent transaction under serializable or repeatable read
+    /// isolation.
+    Serialization,
+    /// Postgres aborted it to break a deadlock.
+    Deadlock,
+    /// Its connection was lost.
+    ConnReset,
+}
+
+impl DbRetryReason {
+    /// The `reason` label of `otterhound_db_transaction_retries_total`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbRetryReason::Serialization => "serialization",
+            DbRetryReason::Deadlock => "deadlock",
+            DbRetryReason::ConnReset => "conn_reset",
+        }
+    }
+}
+
+impl std::error::Error for OtterhoundError {}
+
+impl From<tokio_postgres::Error> for OtterhoundError {
+    fn from(err: tokio_postgres::Error) -> OtterhoundError {
+        use tokio_postgres::error::SqlState;
+
+        let reason = match err.code() {
+            Some(&SqlState::T_R_SERIALIZATION_FAILURE) => Some(DbRetryReason::Serialization),
+            Some(&SqlState::T_R_DEADLOCK_DETECTED) => Some(DbRetryReason::Deadlock),
+            _ if err.is_closed() => Some(DbRetryReason::ConnReset),
+            _ => None,
+        };
+
+        match reason {
+            Some(reason) => OtterhoundError::DbRetryable(reason, format!("{:?}", err)),
+            None => OtterhoundError::Db(format!("{:?}", err)),
+        }
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for OtterhoundError {
+    fn from(err: bb8::RunError<tokio_postgres::Error>) -> OtterhoundError {
+        match err {
+            bb8::RunError::User(err) => err.into(),
+            bb8::RunError::TimedOut => {
+                tracing::warn!("Database pool exhausted, no connection became available in time");
+                OtterhoundError::Db("Timed out waiting for a connection".to_owned())
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! The optional gRPC API described in `proto/otterhound.proto`, served on `GRPC_PORT` alongside
+//! the HTTP server and sharing its state.
+//!
+//! Calls are authenticated with admin tokens like the `/internal/` API, passed in the
+//! `authorization` metadata.
+
+use std::sync::Arc;
+
+use otterhound::AdminScope;
+
+use crate::admin::{bearer_scope, epoch_secs};
+use crate::ServerState;
+
+include!(concat!(env!("OUT_DIR"), "/otterhound.Subscriptions.rs"));
+
+pub use subscriptions_server::SubscriptionsServer;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetSubscriptionRequest {
+    #[prost(int32, tag = "1")]
+    pub user_id: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetSubscriptionResponse {
+    #[prost(bool, tag = "1")]
+    pub active: bool,
+    #[prost(int32, optional, tag = "2")]
+    pub tier: Option<i32>,
+    #[prost(uint64, optional, tag = "3")]
+    pub end_timestamp: Option<u64>,
+    #[prost(string, optional, tag = "4")]
+    pub status: Option<String>,
+    #[prost(bool, optional, tag = "5")]
+    pub cancel_at_period_end: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListRecentEventsRequest {
+    #[prost(string, optional, tag = "1")]
+    pub status: Option<String>,
+    #[prost(uint32, tag = "2")]
+    pub limit: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListRecentEventsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub events: Vec<LoggedEvent>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LoggedEvent {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub event_type: String,
+    #[prost(uint64, tag = "3")]
+    pub created: u64,
+    #[prost(string, tag = "4")]
+    pub status: String,
+    #[prost(string, optional, tag = "5")]
+    pub error: Option<String>,
+    #[prost(int32, tag = "6")]
+    pub attempts: i32,
+    #[prost(uint64, tag = "7")]
+    pub updated_at: u64,
+}
+
+pub struct SubscriptionsService {
+    state: Arc<ServerState>,
+}
+
+impl SubscriptionsService {
+    pub fn new(state: Arc<ServerState>) -> Self {
+        SubscriptionsService { state }
+    }
+
+    /// Checks that the call carries a token with at least `required` scope.
+    // tonic::Status is large, but it's what every method returns anyway
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(
+        &self,
+        request: &tonic::Request<T>,
+        required: AdminScope,
+    ) -> Result<(), tonic::Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        match bearer_scope(authorization, &self.state.admin_tokens) {
+            Some(scope) if scope >= required => Ok(()),
+            Some(_) => Err(tonic::Status::permission_denied(
+                "Token lacks the scope for this request",
+            )),
+            None => Err(tonic::Status::unauthenticated("Missing or unknown token")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl subscriptions_server::Subscriptions for SubscriptionsService {
+    async fn get_subscription(
+        &self,
+        request: tonic::Request<GetSubscriptionRequest>,
+    ) -> Result<tonic::Response<GetSubscriptionResponse>, tonic::Status> {
+        self.authorize(&request, AdminScope::Status)?;
+        let user_id = request.into_inner().user_id;
+
+        let sub = self
+            .state
+            .otterhound
+            .active_subscription(user_id)
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed to load subscription status: {}", err);
+                tonic::Status::internal(err.to_string())
+            })?;
+
+        Ok(tonic::Response::new(match sub {
+            Some(sub) => GetSubscriptionResponse {
+                active: true,
+                tier: Some(sub.tier),
+                end_timestamp: Some(epoch_secs(sub.end_timestamp)),
+                status: Some(sub.status),
+                cancel_at_period_end: Some(sub.cancel_at_period_end),
+            },
+            None => GetSubscriptionResponse::default(),
+        }))
+    }
+
+    async fn list_recent_events(
+        &self,
+        request: tonic::Request<ListRecentEventsRequest>,
+    ) -> Result<tonic::Response<ListRecentEventsResponse>, tonic::Status> {
+        self.authorize(&request, AdminScope::Read)?;
+        let request = request.into_inner();
+        let limit = match request.limit {
+            0 => 100,
+            limit => i64::from(limit),
+        };
+
+        let events = self
+            .state
+            .otterhound
+            .list_logged_events(request.status.as_deref(), limit)
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed to list events: {}", err);
+                tonic::Status::internal(err.to_string())
+            })?;
+
+        Ok(tonic::Response::new(ListRecentEventsResponse {
+            events: events
+                .into_iter()
+                .map(|event| LoggedEvent {
+                    id: event.id,
+                    event_type: event.event_type,
+                    created: epoch_secs(event.created),
+                    status: event.status,
+                    error: event.error,
+                    attempts: event.attempts,
+                    updated_at: epoch_secs(event.updated_at),
+                })
+                .collect(),
+        }))
+    }
+}
@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+
+use crate::{Otterhound, OtterhoundError};
+
+/// What an `EventHandler` gets to act on an event with.
+pub struct EventContext<'a> {
+    pub otterhound: &'a Otterhound,
+    pub event_id: &'a str,
+}
+
+impl<'a> EventContext<'a> {
+    /// Deserializes the event's object, checking its `object` field names the expected type.
+    pub fn parse_object<T: serde::de::DeserializeOwned>(
+        &self,
+        object: serde_json::Value,
+        expected: &str,
+    ) -> Result<T, OtterhoundError> {
+        crate::parse_object(object, expected)
+    }
+
+    /// Runs a single statement at most once for this event. Returns `None` if the event was
+    /// already processed.
+    pub async fn execute(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<Option<u64>, OtterhoundError> {
+        crate::db::execute_for_event(&self.otterhound.db_pool, self.event_id, query, params).await
+    }
+
+    /// Runs `f` in a transaction, at most once for this event, committing it if `f` succeeds.
+    /// Returns `None` without running `f` if the event was already processed. `f` may run again
+    /// if the transaction is retried, see `db::with_transaction`.
+    pub async fn transaction<T, F>(&self, f: F) -> Result<Option<T>, OtterhoundError>
+    where
+        F: AsyncFnOnce(&tokio_postgres::Transaction<'_>) -> Result<T, OtterhoundError> + Clone,
+    {
+        crate::db::with_event_transaction(&self.otterhound.db_pool, self.event_id, f).await
+    }
+
+    /// Fetches an object from the Stripe API, e.g. `customers/cus_123`.
+    pub async fn stripe_get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, OtterhoundError> {
+        self.otterhound.stripe.get(path).await
+    }
+}
+
+/// Handles one Stripe event type. Registered with `Otterhound::register_handler`, taking precedence
+/// over the built-in handling for that type.
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    fn event_type(&self) -> &str;
+
+    async fn handle(
+        &self,
+        ctx: EventContext<'_>,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError>;
+}
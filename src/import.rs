@@ -0,0 +1,62 @@
+const USAGE: &str = "Usage: otterhound_import [--dry-run]";
+
+#[tokio::main]
+async fn main() {
+    let mut dry_run = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_ref() {
+            "--dry-run" => dry_run = true,
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let result = async {
+        let otterhound = otterhound::Otterhound::new().await?;
+        otterhound.import_subscriptions(dry_run).await
+    }
+    .await;
+
+    match result {
+        Ok(outcomes) => {
+            let mut imported = 0;
+            let mut skipped = 0;
+            for (id, outcome) in outcomes {
+                match outcome {
+                    otterhound::ImportOutcome::Imported { user_id, tier_id } => {
+                        println!(
+                            "{}: {}user {}, tier {}",
+                            id,
+                            if dry_run {
+                                "would import for "
+                            } else {
+                                "imported for "
+                            },
+                            user_id,
+                            tier_id
+                        );
+                        imported += 1;
+                    }
+                    otterhound::ImportOutcome::AlreadyRecorded => {
+                        println!("{}: already recorded", id);
+                    }
+                    otterhound::ImportOutcome::Skipped(reason) => {
+                        eprintln!("{}: skipped, {}", id, reason);
+                        skipped += 1;
+                    }
+                }
+            }
+
+            println!("{} imported, {} skipped", imported, skipped);
+            if skipped > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! The `/internal/` API, for other redirectdog services to query billing state over HTTP instead
+//! of sharing database credentials.
+//!
+//! Authenticated like the admin API, with tokens from `Config::admin_tokens`:
+//!
+//! - `GET /internal/users/<user_id>/subscription` (`status`) returns the subscription currently
+//!   granting the user access, see `Otterhound::active_subscription`. Users without one get
+//!   `"active": false` and null fields rather than a 404, so callers can tell them apart from a
+//!   mistyped path.
+//! - `POST /internal/checkout-sessions` (`billing`) creates a Stripe Checkout session for a user
+//!   to subscribe to a tier, see `Otterhound::create_checkout_session`. The body is JSON with
+//!   `user_id`, `tier`, `success_url` and optionally `cancel_url`, and the response has the
+//!   session's `id` and the `url` to send the user to.
+//! - `POST /internal/billing-portal` (`billing`) creates a Stripe customer portal session for a
+//!   user to manage their payment methods and subscriptions. The body is JSON with `user_id` and
+//!   optionally `return_url`, and the response has the `url` to send the user to, or is a 404 if
+//!   no Stripe customer is recorded for the user.
+//! - `POST /internal/subscriptions/<user_id>/cancel` (`billing`) cancels the user's active
+//!   subscription at the end of its period, or right away if the JSON body has
+//!   `"immediately": true`, see `Otterhound::cancel_user_subscription`. Users without an active
+//!   subscription get a 404.
+//! - `POST /internal/subscriptions/<user_id>/tier` (`billing`) moves the user's active
+//!   subscription to another tier, see `Otterhound::change_user_tier`. The body is JSON with
+//!   `tier` and optionally `proration_behavior` (`create_prorations`, the default,
+//!   `always_invoice` or `none`). The change is accepted with a 202 and shows up in the
+//!   subscription once Stripe's webhook for it has been handled.
+
+use std::sync::Arc;
+
+use otterhound::{AdminScope, OtterhoundError, ProrationBehavior};
+use serde_derive::Deserialize;
+
+use crate::admin::{
+    epoch_secs, error_json, forbidden_response, json_response, read_json, request_scope,
+    unauthorized_response,
+};
+use crate::{method_not_allowed_response, status_response, ServerState};
+
+#[derive(Deserialize)]
+struct CheckoutSessionRequest {
+    user_id: i32,
+    tier: i32,
+    success_url: String,
+    cancel_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CancelRequest {
+    #[serde(default)]
+    immediately: bool,
+}
+
+#[derive(Deserialize)]
+struct TierChangeRequest {
+    tier: i32,
+    proration_behavior: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BillingPortalRequest {
+    user_id: i32,
+    return_url: Option<String>,
+}
+
+pub async fn handle(
+    req: hyper::Request<hyper::Body>,
+    state: Arc<ServerState>,
+) -> hyper::Response<hyper::Body> {
+    if state.admin_tokens.is_empty() {
+        return status_response(hyper::StatusCode::NOT_FOUND);
+    }
+    let scope = match request_scope(&req, &state.admin_tokens) {
+        Some(scope) => scope,
+        None => return unauthorized_response(),
+    };
+
+    let segments: Vec<&str> = req.uri().path()["/internal/".len()..].split('/').collect();
+    match segments.as_slice() {
+        ["users", user_id, "subscription"] => {
+            if req.method() != hyper::Method::GET {
+                return method_not_allowed_response("GET");
+            }
+            if scope < AdminScope::Status {
+                return forbidden_response();
+            }
+            match user_id.parse() {
+                Ok(user_id) => subscription_status(user_id, &state).await,
+                Err(_) => error_json(hyper::StatusCode::BAD_REQUEST, "Invalid user ID"),
+            }
+        }
+        ["checkout-sessions"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Billing {
+                return forbidden_response();
+            }
+            create_checkout_session(req, &state).await
+        }
+        ["billing-portal"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Billing {
+                return forbidden_response();
+            }
+            create_billing_portal_session(req, &state).await
+        }
+        ["subscriptions", user_id, "cancel"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Billing {
+                return forbidden_response();
+            }
+            match user_id.parse() {
+                Ok(user_id) => cancel_subscription(user_id, req, &state).await,
+                Err(_) => error_json(hyper::StatusCode::BAD_REQUEST, "Invalid user ID"),
+            }
+        }
+        ["subscriptions", user_id, "tier"] => {
+            if req.method() != hyper::Method::POST {
+                return method_not_allowed_response("POST");
+            }
+            if scope < AdminScope::Billing {
+                return forbidden_response();
+            }
+            match user_id.parse() {
+                Ok(user_id) => change_tier(user_id, req, &state).await,
+                Err(_) => error_json(hyper::StatusCode::BAD_REQUEST, "Invalid user ID"),
+            }
+        }
+        _ => status_response(hyper::StatusCode::NOT_FOUND),
+    }
+}
+
+async fn create_checkout_session(
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let request: CheckoutSessionRequest = match read_json(req, state).await {
+        Ok(request) => request,
+        Err((status, message)) => return error_json(status, &message),
+    };
+
+    match state
+        .otterhound
+        .create_checkout_session(
+            request.user_id,
+            request.tier,
+            &request.success_url,
+            request.cancel_url.as_deref(),
+        )
+        .await
+    {
+        Ok(session) => json_response(
+            hyper::StatusCode::CREATED,
+            serde_json::json!({ "id": session.id, "url": session.url }),
+        ),
+        Err(err @ OtterhoundError::NotFound(_)) => {
+            error_json(hyper::StatusCode::BAD_REQUEST, &err.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to create Checkout session: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn subscription_status(user_id: i32, state: &ServerState) -> hyper::Response<hyper::Body> {
+    match state.otterhound.active_subscription(user_id).await {
+        Ok(Some(sub)) => json_response(
+            hyper::StatusCode::OK,
+            serde_json::json!({
+                "user_id": user_id,
+                "active": true,
+                "tier": sub.tier,
+                "end_timestamp": epoch_secs(sub.end_timestamp),
+                "status": sub.status,
+                "cancel_at_period_end": sub.cancel_at_period_end,
+                "trial": sub.trial,
+            }),
+        ),
+        Ok(None) => json_response(
+            hyper::StatusCode::OK,
+            serde_json::json!({
+                "user_id": user_id,
+                "active": false,
+                "tier": null,
+                "end_timestamp": null,
+                "status": null,
+                "cancel_at_period_end": null,
+                "trial": null,
+            }),
+        ),
+        Err(err) => {
+            tracing::error!("Failed to load subscription status: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn create_billing_portal_session(
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let request: BillingPortalRequest = match read_json(req, state).await {
+        Ok(request) => request,
+        Err((status, message)) => return error_json(status, &message),
+    };
+
+    match state
+        .otterhound
+        .create_billing_portal_session(request.user_id, request.return_url.as_deref())
+        .await
+    {
+        Ok(url) => json_response(
+            hyper::StatusCode::CREATED,
+            serde_json::json!({ "url": url }),
+        ),
+        Err(err @ OtterhoundError::NotFound(_)) => {
+            error_json(hyper::StatusCode::NOT_FOUND, &err.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to create billing portal session: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn cancel_subscription(
+    user_id: i32,
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let request: CancelRequest = match read_json(req, state).await {
+        Ok(request) => request,
+        Err((status, message)) => return error_json(status, &message),
+    };
+
+    match state
+        .otterhound
+        .cancel_user_subscription(user_id, request.immediately)
+        .await
+    {
+        Ok(stripe_subscription) => json_response(
+            hyper::StatusCode::OK,
+            serde_json::json!({
+                "stripe_subscription": stripe_subscription,
+                "immediately": request.immediately,
+            }),
+        ),
+        Err(err @ OtterhoundError::NotFound(_)) => {
+            error_json(hyper::StatusCode::NOT_FOUND, &err.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to cancel subscription: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
+
+async fn change_tier(
+    user_id: i32,
+    req: hyper::Request<hyper::Body>,
+    state: &ServerState,
+) -> hyper::Response<hyper::Body> {
+    let request: TierChangeRequest = match read_json(req, state).await {
+        Ok(request) => request,
+        Err((status, message)) => return error_json(status, &message),
+    };
+    let proration_behavior = match request
+        .proration_behavior
+        .as_deref()
+        .map(str::parse::<ProrationBehavior>)
+    {
+        None => Default::default(),
+        Some(Ok(proration_behavior)) => proration_behavior,
+        Some(Err(err)) => return error_json(hyper::StatusCode::BAD_REQUEST, &err),
+    };
+
+    match state
+        .otterhound
+        .change_user_tier(user_id, request.tier, proration_behavior)
+        .await
+    {
+        Ok(stripe_subscription) => json_response(
+            hyper::StatusCode::ACCEPTED,
+            serde_json::json!({
+                "stripe_subscription": stripe_subscription,
+                "tier": request.tier,
+                "proration_behavior": proration_behavior.as_str(),
+            }),
+        ),
+        Err(err @ OtterhoundError::NotFound(_)) => {
+            error_json(hyper::StatusCode::NOT_FOUND, &err.to_string())
+        }
+        Err(err) => {
+            tracing::error!("Failed to change tier: {}", err);
+            error_json(hyper::StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+        }
+    }
+}
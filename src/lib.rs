@@ -1,183 +1,2682 @@
-use futures::{Future, IntoFuture, Stream};
+use futures::FutureExt;
 use serde_derive::Deserialize;
+use tracing::Instrument;
+
+mod audit;
+mod builder;
+mod circuit_breaker;
+mod config;
+mod db;
+mod debounce;
+mod error;
+mod handler;
+mod migrate;
+mod outbound;
+mod pool;
+pub mod publishing;
+mod redelivery;
+mod repo;
+pub mod reporting;
+mod schema;
+pub mod signature;
+pub mod stripe;
+mod telemetry;
+pub mod worker;
+
+pub use builder::OtterhoundBuilder;
+pub use circuit_breaker::CircuitState;
+pub use config::{
+    AdminScope, AdminToken, Config, HandlingSettings, PoolSettings, ProcessingMode, StripeSettings,
+    TlsPaths,
+};
+use debounce::{Coalesce, Debouncer};
+pub use error::{DbRetryReason, OtterhoundError};
+pub use handler::{EventContext, EventHandler};
+use outbound::OutboundEvent;
+pub use pool::DatabaseSslMode;
+use redelivery::RedeliveryTracker;
+use repo::{NewSubscription, SubscriptionRepo, SubscriptionUpdate};
+/// Kept under its old name, see `stripe::types::Customer`.
+pub use stripe::types::Customer as StripeCustomer;
+use stripe::types::{
+    BillingPortalSession, Charge, CheckoutSession, Dispute, Expandable, Invoice, InvoicePayment,
+    List, Subscription, SubscriptionChanges,
+};
+pub use stripe::StripeClient;
+pub use telemetry::{init_logging, TelemetryGuard};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("OTTERHOUND_GIT_SHA");
+pub const BUILD_TIMESTAMP: &str = env!("OTTERHOUND_BUILD_TIMESTAMP");
 
 #[derive(Deserialize, Debug)]
 pub struct ObjectWrapper {
     object: serde_json::Value,
+    /// The fields of `object` that changed, with their values before the change. Only sent with
+    /// `*.updated` events.
+    previous_attributes: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct EventItem {
+    pub id: String,
+    pub api_version: Option<String>,
     pub created: u64,
     pub data: ObjectWrapper,
     #[serde(rename = "type")]
     pub type_: String,
 }
 
+/// The fields of an event needed for dispatch decisions, borrowed from the raw body.
+#[derive(Deserialize, Debug)]
+pub struct EventMeta<'a> {
+    pub id: &'a str,
+    #[serde(rename = "type")]
+    pub type_: &'a str,
+    pub created: u64,
+}
+
+/// Extracts the event ID, type, and creation time from a webhook body without building the
+/// event's object.
+pub fn peek_event_meta(body: &[u8]) -> Result<EventMeta<'_>, OtterhoundError> {
+    serde_json::from_slice(body)
+        .map_err(|err| OtterhoundError::InvalidRequest(format!("Failed to parse event: {:?}", err)))
+}
+
+/// Converts epoch seconds from Stripe to a time, failing for values too large to represent.
+fn to_timestamp(stamp: u64) -> Result<std::time::SystemTime, OtterhoundError> {
+    std::time::SystemTime::UNIX_EPOCH
+        .checked_add(std::time::Duration::from_secs(stamp))
+        .ok_or_else(|| OtterhoundError::Parse(format!("Timestamp {} is out of range", stamp)))
+}
+
+/// Converts a time back to epoch seconds, the inverse of `to_timestamp`.
+pub fn from_timestamp(time: std::time::SystemTime) -> Result<u64, OtterhoundError> {
+    time.duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .map_err(|err| OtterhoundError::Parse(format!("Timestamp is before the epoch: {:?}", err)))
+}
+
+/// Deserializes an event's object, first checking its `object` field names the expected type.
+fn parse_object<T: serde::de::DeserializeOwned>(
+    object: serde_json::Value,
+    expected: &str,
+) -> Result<T, OtterhoundError> {
+    match object.get("object").and_then(|value| value.as_str()) {
+        Some(found) if found == expected => {}
+        found => {
+            return Err(OtterhoundError::Parse(format!(
+                "Expected object type {}, but found {}",
+                expected,
+                found.unwrap_or("none")
+            )))
+        }
+    }
+
+    serde_json::from_value(object)
+        .map_err(|err| OtterhoundError::Parse(format!("Failed to parse object: {:?}", err)))
+}
+
+pub fn gen_auth_header(stripe_secret_key: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{}:", stripe_secret_key))
+    )
+}
+
+/// Determines which `user_subscriptions` rows count as active.
+pub struct ActiveSubscriptionFilter {
+    /// Rows whose `end_timestamp` is at or before this time are not active.
+    pub as_of: std::time::SystemTime,
+    /// Whether cancelled rows are excluded.
+    pub exclude_cancelled: bool,
+}
+
+impl Default for ActiveSubscriptionFilter {
+    fn default() -> Self {
+        ActiveSubscriptionFilter {
+            as_of: std::time::SystemTime::now(),
+            exclude_cancelled: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DuplicateSubscriptions {
+    pub user_id: i32,
+    /// Rows without a Stripe subscription count towards the duplicates but aren't listed.
+    pub stripe_subscriptions: Vec<String>,
+}
+
+/// How a subscription's `user_subscriptions` row differs from Stripe, see `Otterhound::reconcile`.
+#[derive(Debug)]
+pub enum Discrepancy {
+    /// There is no row, e.g. because the event creating it was missed.
+    Missing,
+    Status {
+        stripe: String,
+        recorded: String,
+    },
+    /// The row ends before the subscription's current period does.
+    PeriodEnd {
+        stripe: std::time::SystemTime,
+        recorded: std::time::SystemTime,
+    },
+    /// The subscription was cancelled, but the row wasn't.
+    NotCancelled,
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Discrepancy::Missing => write!(f, "not recorded"),
+            Discrepancy::Status { stripe, recorded } => {
+                write!(f, "status is {} but recorded as {}", stripe, recorded)
+            }
+            Discrepancy::PeriodEnd { stripe, recorded } => write!(
+                f,
+                "period ends at {} but recorded to end at {}",
+                from_timestamp(*stripe).unwrap_or(0),
+                from_timestamp(*recorded).unwrap_or(0)
+            ),
+            Discrepancy::NotCancelled => write!(f, "cancelled but not recorded as cancelled"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SubscriptionDiscrepancies {
+    pub stripe_subscription: String,
+    pub discrepancies: Vec<Discrepancy>,
+    /// Whether the row was updated to match Stripe.
+    pub fixed: bool,
+}
+
+/// A Checkout session created by `Otterhound::create_checkout_session`.
+#[derive(Debug)]
+pub struct CreatedCheckoutSession {
+    pub id: String,
+    /// Where to send the user to pay.
+    pub url: String,
+}
+
+/// What `Otterhound::import_subscriptions` did with a Stripe subscription.
 #[derive(Debug)]
-struct QueryError(String);
+pub enum ImportOutcome {
+    Imported {
+        user_id: i32,
+        tier_id: i32,
+    },
+    /// The subscription already has a `user_subscriptions` row.
+    AlreadyRecorded,
+    /// The user or tier couldn't be determined.
+    Skipped(String),
+}
+
+/// Which `event_log` rows to load for replaying.
+#[derive(Clone, Debug)]
+pub enum EventSelection {
+    /// Events whose last handling attempt failed.
+    Failed,
+    /// Events with the given IDs, regardless of status.
+    Ids(Vec<String>),
+}
+
+/// A row of `event_log`, without the payload.
+#[derive(Debug)]
+pub struct LoggedEvent {
+    pub id: String,
+    pub event_type: String,
+    pub created: std::time::SystemTime,
+    pub status: String,
+    /// The error from the last failed attempt.
+    pub error: Option<String>,
+    pub attempts: i32,
+    pub updated_at: std::time::SystemTime,
+}
+
+/// A row of `user_subscriptions`.
+#[derive(Debug)]
+pub struct UserSubscription {
+    pub tier: i32,
+    pub start_timestamp: std::time::SystemTime,
+    pub end_timestamp: std::time::SystemTime,
+    pub stripe_subscription: Option<String>,
+    pub status: String,
+    pub cancel_at_period_end: bool,
+    pub cancelled_at: Option<std::time::SystemTime>,
+    pub past_due_since: Option<std::time::SystemTime>,
+    pub payment_method_missing: bool,
+    /// Set while a dispute of one of its payments is open, which suspends access.
+    pub suspended_at: Option<std::time::SystemTime>,
+    /// Whether the subscription is in its trial.
+    pub trial: bool,
+    /// Kept once the trial is over.
+    pub trial_end: Option<std::time::SystemTime>,
+}
+
+impl UserSubscription {
+    /// Reads a row selected as in `Otterhound::user_subscriptions`.
+    fn from_row(row: tokio_postgres::Row) -> Self {
+        UserSubscription {
+            tier: row.get(0),
+            start_timestamp: row.get(1),
+            end_timestamp: row.get(2),
+            stripe_subscription: row.get(3),
+            status: row.get(4),
+            cancel_at_period_end: row.get(5),
+            cancelled_at: row.get(6),
+            past_due_since: row.get(7),
+            payment_method_missing: row.get(8),
+            suspended_at: row.get(9),
+            trial: row.get(10),
+            trial_end: row.get(11),
+        }
+    }
+}
+
+/// An event that failed on every attempt allowed by the retry policy.
+#[derive(Debug)]
+pub struct DeadLetterEvent {
+    pub id: String,
+    pub event_type: String,
+    pub created: std::time::SystemTime,
+    /// The error from the last attempt.
+    pub error: Option<String>,
+    pub attempts: i32,
+    pub failed_at: std::time::SystemTime,
+}
 
-impl From<tokio_postgres::Error> for QueryError {
-    fn from(err: tokio_postgres::Error) -> QueryError {
-        QueryError(format!("{:?}", err))
+/// What to do when a completed checkout session has no matching `subscription_checkout_sessions`
+/// row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingSessionBehavior {
+    /// Roll back and fail the event.
+    Error,
+    /// Log a warning and treat the event as handled.
+    Skip,
+}
+
+impl std::str::FromStr for MissingSessionBehavior {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src {
+            "error" => Ok(MissingSessionBehavior::Error),
+            "skip" => Ok(MissingSessionBehavior::Skip),
+            _ => Err(format!("Unknown missing session behavior: {}", src)),
+        }
+    }
+}
+
+/// How Stripe charges for the rest of the current period when a subscription changes tier.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProrationBehavior {
+    /// Credit or charge the difference on the next invoice.
+    #[default]
+    CreateProrations,
+    /// Invoice the difference right away.
+    AlwaysInvoice,
+    /// Charge the new price from the next period, without prorating.
+    None,
+}
+
+impl ProrationBehavior {
+    /// The value of Stripe's `proration_behavior` parameter.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProrationBehavior::CreateProrations => "create_prorations",
+            ProrationBehavior::AlwaysInvoice => "always_invoice",
+            ProrationBehavior::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for ProrationBehavior {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src {
+            "create_prorations" => Ok(ProrationBehavior::CreateProrations),
+            "always_invoice" => Ok(ProrationBehavior::AlwaysInvoice),
+            "none" => Ok(ProrationBehavior::None),
+            _ => Err(format!("Unknown proration behavior: {}", src)),
+        }
+    }
+}
+
+/// A `customer.subscription.updated` event waiting to be coalesced with later ones for the same
+/// subscription.
+struct BufferedUpdate {
+    event_id: String,
+    object: serde_json::Value,
+    previous_attributes: Option<serde_json::Value>,
+}
+
+impl Coalesce for BufferedUpdate {
+    /// The later event, with the values from before either change, so the combined change is
+    /// still classified against where the subscription started.
+    fn coalesce(self, later: Self) -> Self {
+        let previous_attributes = match (self.previous_attributes, later.previous_attributes) {
+            (
+                Some(serde_json::Value::Object(earlier)),
+                Some(serde_json::Value::Object(mut previous)),
+            ) => {
+                previous.extend(earlier);
+                Some(serde_json::Value::Object(previous))
+            }
+            (earlier, previous) => previous.or(earlier),
+        };
+
+        BufferedUpdate {
+            previous_attributes,
+            ..later
+        }
     }
 }
 
-fn tack_on<T, E, A>(src: Result<T, E>, add: A) -> Result<(T, A), (E, A)> {
-    match src {
-        Ok(value) => Ok((value, add)),
-        Err(err) => Err((err, add)),
+/// Which way a subscription moved between tiers, ranked by ID like `active_subscription`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TierChange {
+    Upgrade,
+    Downgrade,
+}
+
+impl TierChange {
+    /// `None` unless both tiers are known and differ.
+    fn between(from: Option<i32>, to: Option<i32>) -> Option<TierChange> {
+        match (from?, to?) {
+            (from, to) if to > from => Some(TierChange::Upgrade),
+            (from, to) if to < from => Some(TierChange::Downgrade),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            TierChange::Upgrade => "upgrade",
+            TierChange::Downgrade => "downgrade",
+        }
     }
 }
 
-fn to_timestamp(stamp: u64) -> std::time::SystemTime {
-    std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(stamp, 0)
+/// Policy applied to subscriptions billed in a particular currency, for regional differences.
+#[derive(Clone, Debug, Default)]
+pub struct CurrencyPolicy {
+    /// Extra access granted past the end of each billing period.
+    pub access_buffer: std::time::Duration,
+}
+
+fn currency_policy(
+    policies: &std::collections::HashMap<String, CurrencyPolicy>,
+    currency: Option<&str>,
+) -> CurrencyPolicy {
+    currency
+        .and_then(|currency| policies.get(&currency.to_lowercase()))
+        .cloned()
+        .unwrap_or_default()
 }
 
-pub fn gen_auth_header() -> String {
-    let stripe_secret_key = std::env::var("STRIPE_SECRET_KEY").expect("Missing STRIPE_SECRET_KEY");
+/// What the audit log records about a dispute.
+fn dispute_details(dispute: &stripe::types::Dispute) -> serde_json::Value {
+    serde_json::json!({
+        "dispute": dispute.id,
+        "charge": dispute.charge,
+        "amount": dispute.amount,
+        "currency": dispute.currency,
+        "reason": dispute.reason,
+        "status": dispute.status,
+    })
+}
+
+/// An idempotency key for a Stripe request that should take effect on every call. The client's
+/// own retries reuse it, so a retried request still takes effect only once.
+fn unique_idempotency_key(prefix: &str) -> String {
+    static COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    // the count keeps keys made within the same second apart
     format!(
-        "Basic {}",
-        base64::encode(&format!("{}:", stripe_secret_key))
+        "{}-{}-{}",
+        prefix,
+        from_timestamp(std::time::SystemTime::now()).unwrap_or(0),
+        COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     )
 }
 
-type OHHttpClient =
-    std::sync::Arc<hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>>;
+/// How failed events in `event_log` are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts, including the first, before an event is left failed for good.
+    pub max_attempts: u32,
+    /// Delay after the first failure, doubled after each further failure.
+    pub base_delay: std::time::Duration,
+    /// Upper bound for the delay between attempts.
+    pub max_delay: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 8,
+            base_delay: std::time::Duration::from_secs(30),
+            max_delay: std::time::Duration::from_secs(60 * 60 * 6),
+        }
+    }
+}
+
+/// How long a claimed event is hidden from other instances before it can be claimed again.
+const CLAIM_LEASE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+type DbPool = bb8::Pool<pool::PostgresConnectionManager>;
+
+async fn execute(
+    db_pool: &DbPool,
+    query: &str,
+    params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+) -> Result<u64, OtterhoundError> {
+    let conn = db_pool.get().await?;
+    Ok(conn.execute(query, params).await?)
+}
+
+async fn query(
+    db_pool: &DbPool,
+    query: &str,
+    params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+) -> Result<Vec<tokio_postgres::Row>, OtterhoundError> {
+    let conn = db_pool.get().await?;
+    Ok(conn.query(query, params).await?)
+}
+
+/// Which columns inserts into `user_subscriptions` treat as identifying a row, see
+/// `HandlingSettings::conflict_target`. Supported values are `stripe_subscription` (the default,
+/// one row per Stripe subscription), `user_id,tier` (one row per user and tier), and `none` (always
+/// insert).
+///
+/// Any target other than `none` needs a unique index over exactly those columns, and no other
+/// unique index on the table. The migrations create the one for `stripe_subscription`; the
+/// scripts in `sql/` switch the indexes to another target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConflictTarget {
+    None,
+    Columns(Vec<String>),
+}
+
+impl Default for ConflictTarget {
+    fn default() -> Self {
+        ConflictTarget::Columns(vec!["stripe_subscription".to_owned()])
+    }
+}
+
+impl std::str::FromStr for ConflictTarget {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        if src == "none" {
+            return Ok(ConflictTarget::None);
+        }
+
+        let columns: Vec<String> = src
+            .split(',')
+            .map(|column| column.trim().to_owned())
+            .collect();
+        for column in &columns {
+            if column.is_empty()
+                || !column
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(format!("Invalid conflict target column: {:?}", column));
+            }
+        }
+
+        Ok(ConflictTarget::Columns(columns))
+    }
+}
+
+impl ConflictTarget {
+    fn insert_subscription_query(&self) -> String {
+        let insert = "INSERT INTO user_subscriptions (tier, user_id, start_timestamp, end_timestamp, stripe_subscription, payment_method_missing, trial, trial_end) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+        match self {
+            ConflictTarget::None => format!("{} RETURNING user_id", insert),
+            ConflictTarget::Columns(columns) => format!(
+                "{} ON CONFLICT ({}) DO NOTHING RETURNING user_id",
+                insert,
+                columns.join(", ")
+            ),
+        }
+    }
+
+    /// Checks the unique indexes on `user_subscriptions` match the target: one over exactly its
+    /// columns, so conflicting inserts are skipped, and no other, which would make them fail.
+    async fn validate(&self, db_pool: &DbPool) -> Result<(), OtterhoundError> {
+        let rows = query(
+            db_pool,
+            "SELECT i.indexrelid::regclass::TEXT, array_agg(a.attname::TEXT ORDER BY a.attname), i.indpred IS NULL AND i.indexprs IS NULL FROM pg_index i JOIN pg_attribute a ON a.attrelid=i.indrelid AND a.attnum=ANY(i.indkey) WHERE i.indrelid='user_subscriptions'::regclass AND i.indisunique GROUP BY i.indexrelid, i.indpred, i.indexprs",
+            &[],
+        )
+        .await?;
+
+        let expected = match self {
+            ConflictTarget::None => None,
+            ConflictTarget::Columns(columns) => {
+                let mut sorted = columns.clone();
+                sorted.sort();
+                Some(sorted)
+            }
+        };
+        let script = match expected
+            .as_ref()
+            .map(|columns| columns.join(","))
+            .as_deref()
+        {
+            None => "sql/conflict_target_none.sql",
+            Some("stripe_subscription") => "sql/conflict_target_stripe_subscription.sql",
+            Some("tier,user_id") => "sql/conflict_target_user_id_tier.sql",
+            Some(_) => "the scripts in sql/",
+        };
+
+        let mut found = false;
+        for row in rows {
+            let name: String = row.get(0);
+            let columns: Vec<String> = row.get(1);
+            let plain: bool = row.get(2);
+            if plain && expected.as_ref() == Some(&columns) {
+                found = true;
+                continue;
+            }
+            return Err(OtterhoundError::Config(format!(
+                "Unique index {} on user_subscriptions ({}) conflicts with conflict target {}, see {}",
+                name,
+                columns.join(", "),
+                self,
+                script
+            )));
+        }
+        if let (Some(columns), false) = (&expected, found) {
+            return Err(OtterhoundError::Config(format!(
+                "Conflict target {} needs a unique index on user_subscriptions over exactly ({}), see {}",
+                self,
+                columns.join(", "),
+                script
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ConflictTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConflictTarget::None => f.write_str("none"),
+            ConflictTarget::Columns(columns) => f.write_str(&columns.join(",")),
+        }
+    }
+}
 
 pub struct Otterhound {
-    auth_header: String,
-    db_pool: bb8::Pool<bb8_postgres::PostgresConnectionManager<tokio_postgres::NoTls>>,
-    http_client: OHHttpClient,
+    stripe: StripeClient,
+    /// For outbound webhooks; shared with `stripe`.
+    http_client: stripe::client::HttpClient,
+    db_pool: DbPool,
+    store_cancellation_reasons: bool,
+    handle_invoice_upcoming: bool,
+    readiness_check_stripe: bool,
+    defer_downgrades: bool,
+    on_missing_session: MissingSessionBehavior,
+    currency_policies: std::collections::HashMap<String, CurrencyPolicy>,
+    /// The Stripe price of each tier, for creating Checkout sessions.
+    tier_prices: std::collections::HashMap<i32, String>,
+    subscriptions: SubscriptionRepo,
+    redeliveries: RedeliveryTracker,
+    user_id_metadata_key: String,
+    tier_metadata_key: String,
+    payment_grace_period: std::time::Duration,
+    /// How long after its end timestamp a subscription is marked expired.
+    expiry_grace_period: std::time::Duration,
+    retry_policy: RetryPolicy,
+    /// How long handling an event may take before it is abandoned as failed.
+    event_timeout: std::time::Duration,
+    /// Per event type overrides of `event_timeout`.
+    event_timeouts: std::collections::HashMap<String, std::time::Duration>,
+    /// Coalesces bursts of `customer.subscription.updated` events per subscription.
+    update_debouncer: Debouncer<BufferedUpdate>,
+    handlers: std::collections::HashMap<String, Box<dyn EventHandler>>,
+    error_reporter: Option<Box<dyn reporting::ErrorReporter>>,
+    publisher: Option<Box<dyn publishing::Publisher>>,
 }
 
 impl Otterhound {
-    pub fn new_with_some(
-        auth_header: String,
-        http_client: OHHttpClient,
-    ) -> impl Future<Item = Self, Error = String> + Send {
-        bb8::Pool::builder()
-            .build(bb8_postgres::PostgresConnectionManager::new(
-                std::env::var("DATABASE_URL").expect("Missing DATABASE_URL"),
-                tokio_postgres::NoTls,
-            ))
-            .map_err(|err| format!("Failed to initialize database pool: {:?}", err))
-            .map(|db_pool| Otterhound {
-                auth_header,
-                db_pool,
-                http_client,
-            })
+    pub fn builder() -> OtterhoundBuilder {
+        OtterhoundBuilder::new()
+    }
+
+    pub async fn new_with_some(
+        config: &Config,
+        http_client: stripe::client::HttpClient,
+    ) -> Result<Self, OtterhoundError> {
+        OtterhoundBuilder::from_config(config)
+            .http_client(http_client)
+            .build()
+            .await
+    }
+
+    pub async fn new() -> Result<Self, OtterhoundError> {
+        Otterhound::from_config(&Config::load()?).await
+    }
+
+    pub async fn from_config(config: &Config) -> Result<Self, OtterhoundError> {
+        OtterhoundBuilder::from_config(config).build().await
+    }
+
+    /// Registers a handler for its event type, replacing any handler registered for it before and
+    /// taking precedence over the built-in handling.
+    pub fn register_handler<H: EventHandler + 'static>(&mut self, handler: H) {
+        self.handlers
+            .insert(handler.event_type().to_owned(), Box::new(handler));
+    }
+
+    /// Sets where failures of `handle_event` are reported, replacing any reporter set before.
+    pub fn set_error_reporter<R: reporting::ErrorReporter + 'static>(&mut self, reporter: R) {
+        self.error_reporter = Some(Box::new(reporter));
+    }
+
+    /// Sets where committed subscription changes are published, replacing any publisher set before.
+    pub fn set_publisher<P: publishing::Publisher + 'static>(&mut self, publisher: P) {
+        self.publisher = Some(Box::new(publisher));
     }
 
-    pub fn new() -> impl Future<Item = Self, Error = String> + Send {
-        hyper_tls::HttpsConnector::new(4)
-            .map_err(|err| format!("Failed to initialize HTTPS client: {:?}", err))
-            .into_future()
-            .and_then(|connector| {
-                let http_client = std::sync::Arc::new(hyper::Client::builder().build(connector));
+    /// Queues outbound webhooks announcing a change to `stripe_subscription`, and returns the
+    /// events to publish once `txn` commits, if there is a publisher.
+    async fn announce(
+        &self,
+        txn: &tokio_postgres::Transaction<'_>,
+        event: OutboundEvent,
+        stripe_subscription: &str,
+    ) -> Result<Vec<publishing::BillingEvent>, OtterhoundError> {
+        outbound::enqueue(txn, event, stripe_subscription).await?;
+
+        if self.publisher.is_none() {
+            return Ok(Vec::new());
+        }
 
-                Otterhound::new_with_some(gen_auth_header(), http_client)
+        let rows = txn
+            .query(
+                "SELECT user_id, tier, end_timestamp, trial_end, payment_action_url FROM user_subscriptions WHERE stripe_subscription=$1",
+                &[&stripe_subscription],
+            )
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let details = publishing::SubscriptionDetails {
+                    user_id: row.get(0),
+                    tier: row.get(1),
+                    stripe_subscription: stripe_subscription.to_owned(),
+                    end_timestamp: from_timestamp(row.get(2))?,
+                    trial_end: row
+                        .get::<_, Option<std::time::SystemTime>>(3)
+                        .map(from_timestamp)
+                        .transpose()?,
+                    payment_action_url: row.get(4),
+                };
+                Ok(match event {
+                    OutboundEvent::Created => {
+                        publishing::BillingEvent::SubscriptionStarted(details)
+                    }
+                    OutboundEvent::Renewed => {
+                        publishing::BillingEvent::SubscriptionRenewed(details)
+                    }
+                    OutboundEvent::Cancelled => {
+                        publishing::BillingEvent::SubscriptionEnded(details)
+                    }
+                    OutboundEvent::TrialEnding => publishing::BillingEvent::TrialEnding(details),
+                    OutboundEvent::PaymentActionRequired => {
+                        publishing::BillingEvent::PaymentActionRequired(details)
+                    }
+                })
             })
+            .collect()
+    }
+
+    async fn publish(&self, events: Vec<publishing::BillingEvent>) {
+        if let Some(publisher) = &self.publisher {
+            for event in events {
+                if let Err(err) = publisher.publish(&event).await {
+                    tracing::error!("Failed to publish {}: {}", event.name(), err);
+                }
+            }
+        }
+    }
+
+    /// Resolves the policy for a currency, falling back to the currency-agnostic default.
+    pub fn policy_for_currency(&self, currency: Option<&str>) -> CurrencyPolicy {
+        currency_policy(&self.currency_policies, currency)
+    }
+
+    /// Current state of the circuit breaker guarding Stripe API calls.
+    pub fn stripe_circuit_state(&self) -> CircuitState {
+        self.stripe.circuit_state()
+    }
+
+    pub fn stripe(&self) -> &StripeClient {
+        &self.stripe
+    }
+
+    /// Sets the `otterhound_stripe_circuit_state` gauge, which otherwise only changes when the
+    /// circuit opens or closes, to also show when it has become half-open.
+    pub fn record_circuit_metrics(&self) {
+        metrics::gauge!("otterhound_stripe_circuit_state")
+            .set(self.stripe_circuit_state().gauge_value());
+    }
+
+    /// Sets the database pool gauges, for reporting just before metrics are scraped.
+    pub fn record_pool_metrics(&self) {
+        let state = self.db_pool.state();
+        metrics::gauge!("otterhound_db_pool_connections").set(state.connections as f64);
+        metrics::gauge!("otterhound_db_pool_idle_connections").set(state.idle_connections as f64);
+        metrics::counter!("otterhound_db_pool_waits_total").absolute(state.statistics.get_waited);
+        metrics::counter!("otterhound_db_pool_timeouts_total")
+            .absolute(state.statistics.get_timed_out);
+    }
+
+    /// Checks the database is reachable through the pool and, if `READINESS_CHECK_STRIPE` is set,
+    /// that the Stripe API answers too.
+    pub async fn check_ready(&self) -> Result<(), OtterhoundError> {
+        let conn = self.db_pool.get().await?;
+        conn.simple_query("SELECT 1").await?;
+        drop(conn);
+
+        if self.readiness_check_stripe {
+            self.stripe.ping().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Counts a webhook delivery of `event_id`, warning when the same event keeps arriving, which
+    /// points at a delivery loop. Called for every inbound delivery, whatever the processing
+    /// mode, and never for our own retries.
+    pub fn record_delivery(&self, event_id: &str) {
+        if let Some(count) = self.redeliveries.record(event_id) {
+            tracing::warn!(
+                "Event {} was delivered {} times recently, possible delivery loop",
+                event_id,
+                count
+            );
+        }
+    }
+
+    /// Writes buffered subscription updates right away instead of waiting out the debounce
+    /// window, and any later ones without buffering them, for shutting down.
+    pub fn flush_updates(&self) {
+        self.update_debouncer.flush();
+    }
+
+    /// Event types that `handle_event` acts on with the current configuration; all others are
+    /// ignored.
+    pub fn handled_event_types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = vec![
+            "charge.dispute.closed",
+            "charge.dispute.created",
+            "charge.refunded",
+            "checkout.session.completed",
+            "checkout.session.expired",
+            "customer.subscription.created",
+            "customer.subscription.deleted",
+            "customer.subscription.trial_will_end",
+            "customer.subscription.updated",
+            "customer.updated",
+            "invoice.payment_action_required",
+            "invoice.payment_failed",
+            "invoice.payment_succeeded",
+        ];
+        if self.handle_invoice_upcoming {
+            types.push("invoice.upcoming");
+        }
+        for event_type in self.handlers.keys() {
+            if !types.contains(&event_type.as_str()) {
+                types.push(event_type);
+            }
+        }
+
+        types
+    }
+
+    pub async fn handle_event(&self, evt: EventItem) -> Result<(), OtterhoundError> {
+        let span = tracing::info_span!("event", event_id = %evt.id, event_type = %evt.type_);
+        self.handle_event_in_span(evt).instrument(span).await
+    }
+
+    async fn handle_event_in_span(&self, evt: EventItem) -> Result<(), OtterhoundError> {
+        tracing::info!(
+            "Received event: {} (API version {})",
+            evt.type_,
+            evt.api_version.as_deref().unwrap_or("unknown")
+        );
+
+        // webhook payloads use the endpoint's version, which the header can't pin
+        if let Some(api_version) = &evt.api_version {
+            if api_version != self.stripe.api_version() {
+                tracing::warn!(
+                    "Event {} uses API version {}, but requests are pinned to {}",
+                    evt.id,
+                    api_version,
+                    self.stripe.api_version()
+                );
+            }
+        }
+
+        let event_type = evt.type_.clone();
+        let event_id = evt.id.clone();
+        let timeout = self
+            .event_timeouts
+            .get(&event_type)
+            .copied()
+            .unwrap_or(self.event_timeout);
+        let start = std::time::Instant::now();
+        let res = match tokio::time::timeout(
+            timeout,
+            std::panic::AssertUnwindSafe(self.dispatch_event(evt)).catch_unwind(),
+        )
+        .await
+        {
+            Ok(Ok(res)) => res,
+            Err(_) => {
+                tracing::error!("Event {} timed out after {:?}", event_id, timeout);
+                metrics::counter!("otterhound_events_timed_out_total", "event_type" => event_type.clone())
+                    .increment(1);
+                Err(OtterhoundError::TimedOut(timeout))
+            }
+            Ok(Err(panic)) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(|msg| msg.as_str()))
+                    .unwrap_or("no message");
+                tracing::error!("Handler for event {} panicked: {}", event_id, message);
+                self.report_failure(&event_type, &event_id, reporting::Failure::Panic(message));
+
+                // the panic stays contained, so the event is retried like any other failure
+                Err(OtterhoundError::Panicked(message.to_owned()))
+            }
+        };
+        match &res {
+            // already reported as a panic
+            Err(OtterhoundError::Panicked(_)) => {}
+            Err(err) => {
+                self.report_failure(&event_type, &event_id, reporting::Failure::Error(err));
+            }
+            Ok(()) => {}
+        }
+
+        metrics::histogram!(
+            "otterhound_event_handler_duration_seconds",
+            "event_type" => event_type.clone()
+        )
+        .record(start.elapsed().as_secs_f64());
+        match &res {
+            Ok(()) => {
+                metrics::counter!("otterhound_events_handled_total", "event_type" => event_type)
+                    .increment(1)
+            }
+            Err(_) => {
+                metrics::counter!("otterhound_events_failed_total", "event_type" => event_type)
+                    .increment(1)
+            }
+        }
+
+        res
+    }
+
+    fn report_failure(&self, event_type: &str, event_id: &str, failure: reporting::Failure) {
+        if let Some(reporter) = &self.error_reporter {
+            reporter.report(&reporting::FailedEvent {
+                event_type,
+                event_id,
+                failure,
+            });
+        }
     }
 
-    pub fn handle_event(&self, evt: EventItem) -> Box<Future<Item = (), Error = String> + Send> {
-        println!("Received event: {}", evt.type_);
+    async fn dispatch_event(&self, evt: EventItem) -> Result<(), OtterhoundError> {
+        let event_id = &evt.id;
+        let object = evt.data.object;
+        let previous_attributes = evt.data.previous_attributes;
+
+        if let Some(handler) = self.handlers.get(&evt.type_) {
+            let ctx = EventContext {
+                otterhound: self,
+                event_id,
+            };
+            return handler.handle(ctx, object).await;
+        }
 
         match evt.type_.as_ref() {
-            "checkout.session.completed" => {
-                println!("{:?}", evt.data);
+            "charge.dispute.closed" => self.close_dispute(event_id, object).await,
+            "charge.dispute.created" => self.open_dispute(event_id, object).await,
+            "charge.refunded" => self.refund_charge(event_id, object).await,
+            "checkout.session.completed" => self.complete_checkout(event_id, object).await,
+            "checkout.session.expired" => self.expire_checkout(event_id, object).await,
+            "customer.subscription.deleted" => self.cancel_subscription(event_id, object).await,
+            "customer.subscription.created" => {
+                self.record_created_subscription(event_id, object).await
+            }
+            "customer.subscription.trial_will_end" => {
+                self.remind_trial_ending(event_id, object).await
+            }
+            "customer.subscription.updated" => match object["id"].as_str().map(str::to_owned) {
+                Some(sub_id) => {
+                    let update = BufferedUpdate {
+                        event_id: event_id.clone(),
+                        object,
+                        previous_attributes,
+                    };
+                    self.update_debouncer
+                        .run(&sub_id, evt.created, update, async |update| {
+                            self.update_subscription(
+                                &update.event_id,
+                                update.object,
+                                update.previous_attributes,
+                            )
+                            .await
+                        })
+                        .await
+                }
+                // left to fail parsing, without waiting
+                None => {
+                    self.update_subscription(event_id, object, previous_attributes)
+                        .await
+                }
+            },
+            "customer.updated" => self.refresh_customer_payment_method(event_id, object).await,
+            "invoice.payment_action_required" => {
+                self.require_payment_action(event_id, object).await
+            }
+            "invoice.payment_failed" => self.mark_past_due(event_id, object).await,
+            "invoice.payment_succeeded" => self.extend_subscription(event_id, object).await,
+            "invoice.upcoming" if self.handle_invoice_upcoming => {
+                self.record_upcoming_invoice(event_id, object).await
+            }
+            _ => {
+                tracing::info!("Ignoring unhandled event type: {}", evt.type_);
+                metrics::counter!("otterhound_unhandled_events_total", "event_type" => evt.type_)
+                    .increment(1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records the subscription bought in a completed Checkout session against the session's user
+    /// and tier, and the session's customer against the user.
+    async fn complete_checkout(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        tracing::debug!("{:?}", object);
+
+        let session: CheckoutSession = parse_object(object, "checkout.session")?;
+        // an expanded subscription saves fetching it, unless the customer is needed to check for
+        // a default payment method and wasn't expanded too
+        let sub: Subscription = match session.subscription {
+            Some(Expandable::Object(sub))
+                if sub.default_payment_method.is_some() || sub.customer.as_object().is_some() =>
+            {
+                *sub
+            }
+            Some(sub) => {
+                self.stripe
+                    .get(&format!("subscriptions/{}?expand%5B%5D=customer", sub.id()))
+                    .await?
+            }
+            None => {
+                return Err(OtterhoundError::Parse(
+                    "Checkout session has no subscription".to_owned(),
+                ))
+            }
+        };
+        let sub_id = &sub.id;
+
+        let end_timestamp = to_timestamp(sub.period_end()?)?
+            + self
+                .policy_for_currency(sub.currency.as_deref())
+                .access_buffer;
+        let payment_method_missing = sub.payment_method_missing(sub.customer.as_object());
 
-                #[derive(Deserialize)]
-                struct CheckoutSession {
-                    id: String,
-                    subscription: String,
+        let session_id = &session.id;
+        let customer_id = session.customer.as_deref();
+        let events = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            let mut events = Vec::new();
+            match self
+                .subscriptions
+                .complete_checkout_session(txn, session_id)
+                .await?
+            {
+                Some((user_id, tier_id)) => {
+                    if let Some(customer_id) = customer_id {
+                        self.subscriptions
+                            .record_customer(txn, user_id, customer_id)
+                            .await?;
+                    }
+
+                    let count = self
+                        .subscriptions
+                        .insert_subscription(
+                            txn,
+                            &NewSubscription {
+                                tier_id,
+                                user_id,
+                                start_timestamp: to_timestamp(sub.created)?,
+                                end_timestamp,
+                                stripe_subscription: sub_id,
+                                payment_method_missing,
+                                trial_end: sub.current_trial_end().map(to_timestamp).transpose()?,
+                            },
+                        )
+                        .await?;
+                    if count > 0 {
+                        events = self.announce(txn, OutboundEvent::Created, sub_id).await?;
+                    }
                 }
+                None => match self.on_missing_session {
+                    MissingSessionBehavior::Skip => {
+                        tracing::warn!("Couldn't find the session, skipping");
+                    }
+                    MissingSessionBehavior::Error => {
+                        return Err(OtterhoundError::NotFound(
+                            "Couldn't find the session".to_owned(),
+                        ))
+                    }
+                },
+            }
 
-                Box::new(serde_json::from_value(evt.data.object)
-                         .map_err(|err| format!("Failed to parse object: {:?}", err))
-                         .and_then(|session: CheckoutSession| {
-                             let db_pool = self.db_pool.clone();
-
-                             #[derive(Deserialize)]
-                             struct Subscription {
-                                 created: u64,
-                                 current_period_end: u64,
-                             }
-
-                             let session_id = session.id;
-                             let sub_id = session.subscription;
-                             let auth_header: &str = &self.auth_header;
-
-                             hyper::Request::get(&format!("https://api.stripe.com/v1/subscriptions/{}", sub_id))
-                                 .header("Authorization", auth_header)
-                                 .body(hyper::Body::empty())
-                                 .map_err(|err| format!("Failed to construct request: {:?}", err))
-                                 .map(move |req| {
-                                     self.http_client.request(req)
-                                         .and_then(|res| {
-                                             let status = res.status();
-                                             res.into_body().concat2()
-                                                 .map(move |body| (body, status))
-                                         })
-                                     .map_err(|err| format!("Failed to send request: {:?}", err))
-                                         .and_then(|(body, status)| {
-                                             if status.is_success() {
-                                                 serde_json::from_slice(&body)
-                                                     .map_err(|err| format!("Failed to parse response: {:?}", err))
-                                             } else {
-                                                 Err(format!("Received error from API: {:?}", body))
-                                             }
-                                         })
-                                     .and_then(move |sub: Subscription| {
-                                         db_pool.run(|mut conn| {
-                                             conn.prepare("UPDATE subscription_checkout_sessions SET completed=TRUE WHERE stripe_id=$1 AND completed=FALSE RETURNING user_id, tier_id")
-                                                 .join(conn.prepare("INSERT INTO user_subscriptions (tier, user_id, start_timestamp, end_timestamp, stripe_subscription) VALUES ($1, $2, $3, $4, $5)"))
-                                                 .map_err(|err| format!("Failed to prepare queries: {:?}", err))
-                                                 .then(|res| tack_on(res, conn))
-                                                 .and_then(|((st1, st2), mut conn)| {
-                                                     conn.simple_query("BEGIN")
-                                                         .into_future()
-                                                         .map_err(|(err, _)| format!("Failed to start transaction: {:?}", err))
-                                                         .then(|res| tack_on(res, conn))
-                                                         .and_then(move |(_, mut conn)| {
-                                                             conn.query(&st1, &[&session_id])
-                                                                 .into_future()
-                                                                 .map(|(res, _)| res)
-                                                                 .map_err(|(err, _)| format!("Failed to query for session: {:?}", err))
-                                                                 .then(|res| tack_on(res, conn))
-                                                                 .and_then(|(row, conn)| {
-                                                                     match row {
-                                                                         Some(row) => {
-                                                                             Ok(((row.get(0), row.get(1)), conn))
-                                                                         },
-                                                                         None => Err(("Couldn't find the session".to_owned(), conn)),
-                                                                     }
-                                                                 })
-                                                             .and_then(move |((user_id, tier_id), mut conn): ((i32, i32), _)| {
-                                                                 conn.execute(&st2, &[&tier_id, &user_id, &to_timestamp(sub.created), &to_timestamp(sub.current_period_end), &sub_id])
-                                                                     .map_err(|err| format!("Failed to add subscription: {:?}", err))
-                                                                     .then(|res| tack_on(res, conn))
-                                                             })
-                                                         })
-                                                     .and_then(|(_, mut conn)| {
-                                                         conn.simple_query("COMMIT").into_future()
-                                                             .map(|_| ())
-                                                             .map_err(|(err, _)| format!("Failed to commit transaction: {:?}", err))
-                                                             .then(|res| tack_on(res, conn))
-                                                     })
-                                                         .or_else(|(err, mut conn)| conn.simple_query("ROLLBACK").into_future().then(|_| Err((err, conn))))
-                                                 })
-                                             .map_err(|(err, conn)| (QueryError(err), conn))
-                                         })
-                                         .map_err(|err| format!("{:?}", err))
-                                     })
-                                 })
-                         })
-                             .into_future()
-                                 .and_then(|x| x)
-                )
+            Ok(events)
+        })
+        .await?;
+        self.publish(events.unwrap_or_default()).await;
+
+        Ok(())
+    }
+
+    /// Deletes the pending row of a Checkout session that expired without being completed.
+    async fn expire_checkout(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let session: CheckoutSession = parse_object(object, "checkout.session")?;
+
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .delete_pending_checkout_session(txn, &session.id)
+                .await
+        })
+        .await?;
+        if count == Some(0) {
+            tracing::info!("No pending session found for expired checkout");
+        }
+
+        Ok(())
+    }
+
+    /// Marks a deleted subscription as cancelled so it stops granting access, and records the
+    /// cancellation reasons if enabled.
+    async fn cancel_subscription(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let sub: Subscription = parse_object(object, "subscription")?;
+
+        let events = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.record_subscription_ended(txn, &sub).await
+        })
+        .await?;
+        self.publish(events.unwrap_or_default()).await;
+
+        Ok(())
+    }
+
+    /// Marks a subscription Stripe reports as ended cancelled, announcing it unless it already
+    /// was, and stores why if `STORE_CANCELLATION_REASONS` is set.
+    async fn record_subscription_ended(
+        &self,
+        txn: &tokio_postgres::Transaction<'_>,
+        sub: &Subscription,
+    ) -> Result<Vec<publishing::BillingEvent>, OtterhoundError> {
+        let ended_at = sub
+            .ended_at
+            .map(to_timestamp)
+            .transpose()?
+            .unwrap_or_else(std::time::SystemTime::now);
+
+        let count = self
+            .subscriptions
+            .cancel_subscription(txn, &sub.id, ended_at)
+            .await?;
+        let events = if count == 0 {
+            tracing::info!("No active subscription found to cancel");
+            Vec::new()
+        } else {
+            self.announce(txn, OutboundEvent::Cancelled, &sub.id)
+                .await?
+        };
+
+        if self.store_cancellation_reasons {
+            let no_details = Default::default();
+            let details = sub.cancellation_details.as_ref().unwrap_or(&no_details);
+            self.subscriptions
+                .record_cancellation(txn, &sub.id, details)
+                .await?;
+        }
+
+        Ok(events)
+    }
+
+    /// Records subscriptions created outside of Checkout, resolving the user from the customer's
+    /// metadata and the tier from the price's metadata (`user_id` and `tier_id` by default).
+    async fn record_created_subscription(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let sub: Subscription = parse_object(object, "subscription")?;
+
+        let tier_id = match sub.tier_id(&self.tier_metadata_key)? {
+            Some(tier_id) => tier_id,
+            None => {
+                tracing::info!(
+                    "Subscription price has no {} metadata, ignoring",
+                    self.tier_metadata_key
+                );
+                return Ok(());
             }
-            _ => Box::new(futures::future::ok(())),
+        };
+
+        let end_timestamp = to_timestamp(sub.period_end()?)?
+            + self
+                .policy_for_currency(sub.currency.as_deref())
+                .access_buffer;
+
+        let customer: StripeCustomer = self
+            .stripe
+            .get(&format!("customers/{}", sub.customer.id()))
+            .await?;
+
+        if customer.deleted {
+            tracing::warn!(
+                "Customer {} was deleted, ignoring subscription",
+                customer.id
+            );
+            return Ok(());
         }
+
+        let user_id = match customer.user_id(&self.user_id_metadata_key)? {
+            Some(user_id) => user_id,
+            None => {
+                tracing::info!(
+                    "Customer {} has no {} metadata, ignoring subscription",
+                    customer.id,
+                    self.user_id_metadata_key
+                );
+                return Ok(());
+            }
+        };
+
+        let payment_method_missing = sub.payment_method_missing(Some(&customer));
+
+        let new_subscription = NewSubscription {
+            tier_id,
+            user_id,
+            start_timestamp: to_timestamp(sub.created)?,
+            end_timestamp,
+            stripe_subscription: &sub.id,
+            payment_method_missing,
+            trial_end: sub.current_trial_end().map(to_timestamp).transpose()?,
+        };
+        let result = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            let count = self
+                .subscriptions
+                .insert_subscription(txn, &new_subscription)
+                .await?;
+            let events = if count > 0 {
+                self.announce(txn, OutboundEvent::Created, &sub.id).await?
+            } else {
+                Vec::new()
+            };
+
+            Ok((count, events))
+        })
+        .await?;
+        if let Some((count, events)) = result {
+            self.publish(events).await;
+            if count == 0 {
+                tracing::info!("Subscription was already recorded");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies plan changes, period shifts and status changes. The tier is only changed if the new
+    /// price carries tier metadata, the end timestamp only moves while the subscription is in good
+    /// standing (so a past due subscription keeps its grace period), and `payment_method_missing`
+    /// is cleared once the subscription has its own default payment method. With
+    /// `defer_downgrades`, a move to a lower tier within the period only takes effect once the
+    /// period ends.
+    async fn update_subscription(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+        previous_attributes: Option<serde_json::Value>,
+    ) -> Result<(), OtterhoundError> {
+        let sub: Subscription = parse_object(object, "subscription")?;
+        let changes: SubscriptionChanges = match previous_attributes {
+            Some(value) => serde_json::from_value(value).map_err(|err| {
+                OtterhoundError::Parse(format!("Failed to parse previous_attributes: {}", err))
+            })?,
+            None => SubscriptionChanges::default(),
+        };
+
+        let mut update = self.subscription_update(&sub)?;
+        let previous_tier = changes.tier_id(&self.tier_metadata_key)?;
+        if let Some(change) = TierChange::between(previous_tier, update.tier_id) {
+            metrics::counter!("otterhound_tier_changes_total", "direction" => change.as_str())
+                .increment(1);
+
+            let period_end = sub.period_end()?;
+            // a change arriving with a new period, e.g. from a schedule, is already due
+            let renewed = changes.period_end().is_some_and(|end| end != period_end);
+            if change == TierChange::Downgrade && self.defer_downgrades && !renewed {
+                tracing::info!(
+                    "Deferring downgrade of subscription {} from tier {:?} to {:?} until {}",
+                    sub.id,
+                    previous_tier,
+                    update.tier_id,
+                    period_end
+                );
+                update.pending_tier = update.tier_id;
+                update.pending_tier_from = Some(to_timestamp(period_end)?);
+            }
+        }
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions.update_subscription(txn, &update).await
+        })
+        .await?;
+        if count == Some(0) {
+            tracing::info!("No subscription found to update");
+        }
+
+        Ok(())
+    }
+
+    /// Tells outbound webhook subscribers and the publisher that a trial ends soon, so users can
+    /// be reminded to add a payment method.
+    async fn remind_trial_ending(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let sub: Subscription = parse_object(object, "subscription")?;
+        let trial_end = sub.trial_end.map(to_timestamp).transpose()?;
+
+        let events = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            if let Some(trial_end) = trial_end {
+                self.subscriptions
+                    .record_trial_end(txn, &sub.id, trial_end)
+                    .await?;
+            }
+
+            self.announce(txn, OutboundEvent::TrialEnding, &sub.id)
+                .await
+        })
+        .await?;
+        self.publish(events.unwrap_or_default()).await;
+
+        Ok(())
+    }
+
+    /// The changes to record for a subscription Stripe reports as still ongoing.
+    fn subscription_update<'a>(
+        &self,
+        sub: &'a Subscription,
+    ) -> Result<SubscriptionUpdate<'a>, OtterhoundError> {
+        Ok(SubscriptionUpdate {
+            stripe_subscription: &sub.id,
+            tier_id: sub.tier_id(&self.tier_metadata_key)?,
+            end_timestamp: to_timestamp(sub.period_end()?)?
+                + self
+                    .policy_for_currency(sub.currency.as_deref())
+                    .access_buffer,
+            cancel_at_period_end: sub.cancel_at_period_end,
+            has_default_payment_method: sub.default_payment_method.is_some(),
+            status: &sub.status,
+            trial_end: sub.current_trial_end().map(to_timestamp).transpose()?,
+            pending_tier: None,
+            pending_tier_from: None,
+        })
+    }
+
+    /// Clears `payment_method_missing` for a customer's subscriptions once the customer has a
+    /// default payment method. The user is resolved from customer metadata, so customers without it
+    /// are skipped.
+    async fn refresh_customer_payment_method(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let customer: StripeCustomer = parse_object(object, "customer")?;
+
+        if !customer.has_default_payment_method() {
+            return Ok(());
+        }
+
+        let user_id = match customer.user_id(&self.user_id_metadata_key)? {
+            Some(user_id) => user_id,
+            None => return Ok(()),
+        };
+
+        db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .clear_payment_method_missing(txn, user_id)
+                .await
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Extends a subscription to the end of the period a paid invoice covers, for renewals.
+    async fn extend_subscription(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let invoice: Invoice = parse_object(object, "invoice")?;
+
+        let sub_id = match invoice.subscription_id() {
+            Some(sub_id) => sub_id.to_owned(),
+            None => {
+                tracing::info!("Paid invoice is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let period_end = invoice
+            .lines
+            .data
+            .iter()
+            .map(|line| line.period.end)
+            .max()
+            .ok_or_else(|| OtterhoundError::Parse("Paid invoice has no line items".to_owned()))?;
+        let end_timestamp = to_timestamp(period_end)?
+            + self
+                .policy_for_currency(Some(&invoice.currency))
+                .access_buffer;
+
+        let result = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            let count = self
+                .subscriptions
+                .extend_subscription(txn, &sub_id, end_timestamp)
+                .await?;
+            let events = if count > 0 {
+                self.announce(txn, OutboundEvent::Renewed, &sub_id).await?
+            } else {
+                Vec::new()
+            };
+
+            Ok((count, events))
+        })
+        .await?;
+        if let Some((count, events)) = result {
+            self.publish(events).await;
+            if count == 0 {
+                tracing::info!("No subscription found to extend for paid invoice");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records a refunded subscription payment. A full refund revokes access to the subscription
+    /// right away; a partial one only records the refunded amount. Both are written to the audit
+    /// log.
+    async fn refund_charge(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let charge: Charge = parse_object(object, "charge")?;
+
+        let sub_id = match self.subscription_for_charge(&charge).await? {
+            Some(sub_id) => sub_id,
+            None => {
+                tracing::info!("Refunded charge is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let details = serde_json::json!({
+            "charge": charge.id,
+            "invoice": charge.invoice,
+            "amount": charge.amount,
+            "amount_refunded": charge.amount_refunded,
+            "currency": charge.currency,
+        });
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .record_refund(txn, &sub_id, &charge)
+                .await?;
+
+            if charge.refunded {
+                let count = self
+                    .subscriptions
+                    .revoke_subscription(txn, &sub_id, std::time::SystemTime::now())
+                    .await?;
+                audit::record(
+                    txn,
+                    Some(event_id),
+                    &sub_id,
+                    "subscription.revoked",
+                    &details,
+                )
+                .await?;
+                Ok(count)
+            } else {
+                audit::record(txn, Some(event_id), &sub_id, "refund.partial", &details).await?;
+                Ok(0)
+            }
+        })
+        .await?;
+        if count.unwrap_or(0) > 0 {
+            tracing::info!("Revoked subscription {} after a full refund", sub_id);
+            metrics::counter!("otterhound_subscriptions_revoked_total").increment(1);
+        }
+
+        Ok(())
+    }
+
+    /// The subscription a charge paid for, found through the charge's invoice. Charges from API
+    /// versions since 2025-03-31.basil don't name their invoice, so it is looked up by the
+    /// charge's payment intent.
+    async fn subscription_for_charge(
+        &self,
+        charge: &Charge,
+    ) -> Result<Option<String>, OtterhoundError> {
+        let invoice_id = match (&charge.invoice, &charge.payment_intent) {
+            (Some(invoice_id), _) => invoice_id.clone(),
+            (None, Some(payment_intent)) => {
+                let payments: List<InvoicePayment> = self
+                    .stripe
+                    .get(&format!(
+                        "invoice_payments?payment%5Btype%5D=payment_intent&payment%5Bpayment_intent%5D={}",
+                        payment_intent
+                    ))
+                    .await?;
+                match payments.data.into_iter().next() {
+                    Some(payment) => payment.invoice,
+                    None => return Ok(None),
+                }
+            }
+            (None, None) => return Ok(None),
+        };
+        let invoice: Invoice = self.stripe.get(&format!("invoices/{}", invoice_id)).await?;
+
+        Ok(invoice.subscription_id().map(str::to_owned))
+    }
+
+    /// Records a newly opened dispute of a subscription payment, and suspends access to the
+    /// subscription until it is closed.
+    async fn open_dispute(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let dispute: Dispute = parse_object(object, "dispute")?;
+        let sub_id = match self.subscription_for_dispute(&dispute).await? {
+            Some(sub_id) => sub_id,
+            None => {
+                tracing::info!("Disputed charge is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let details = dispute_details(&dispute);
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .record_dispute(txn, &sub_id, &dispute, None)
+                .await?;
+            let count = self
+                .subscriptions
+                .suspend_subscription(txn, &sub_id, std::time::SystemTime::now())
+                .await?;
+            audit::record(
+                txn,
+                Some(event_id),
+                &sub_id,
+                "subscription.suspended",
+                &details,
+            )
+            .await?;
+
+            Ok(count)
+        })
+        .await?;
+        if count.unwrap_or(0) > 0 {
+            tracing::info!("Suspended subscription {} during dispute", sub_id);
+            metrics::counter!("otterhound_subscriptions_suspended_total").increment(1);
+        }
+
+        Ok(())
+    }
+
+    /// Records the outcome of a dispute. Access is restored once no dispute of the subscription is
+    /// open any more, unless this one was lost, in which case the disputed period is revoked as
+    /// for a full refund.
+    async fn close_dispute(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let dispute: Dispute = parse_object(object, "dispute")?;
+        let sub_id = match self.subscription_for_dispute(&dispute).await? {
+            Some(sub_id) => sub_id,
+            None => {
+                tracing::info!("Disputed charge is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let lost = dispute.status == "lost";
+        let details = dispute_details(&dispute);
+        db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            let now = std::time::SystemTime::now();
+            self.subscriptions
+                .record_dispute(txn, &sub_id, &dispute, Some(now))
+                .await?;
+
+            if lost {
+                self.subscriptions
+                    .revoke_subscription(txn, &sub_id, now)
+                    .await?;
+                audit::record(
+                    txn,
+                    Some(event_id),
+                    &sub_id,
+                    "subscription.revoked",
+                    &details,
+                )
+                .await?;
+            }
+
+            if self.subscriptions.lift_suspension(txn, &sub_id).await? > 0 {
+                audit::record(
+                    txn,
+                    Some(event_id),
+                    &sub_id,
+                    "subscription.unsuspended",
+                    &details,
+                )
+                .await?;
+            }
+
+            Ok(())
+        })
+        .await?;
+        tracing::info!(
+            "Dispute {} of subscription {} closed as {}",
+            dispute.id,
+            sub_id,
+            dispute.status
+        );
+
+        Ok(())
+    }
+
+    async fn subscription_for_dispute(
+        &self,
+        dispute: &Dispute,
+    ) -> Result<Option<String>, OtterhoundError> {
+        let charge: Charge = self
+            .stripe
+            .get(&format!("charges/{}", dispute.charge))
+            .await?;
+
+        self.subscription_for_charge(&charge).await
+    }
+
+    /// Records that a renewal payment needs the customer to authenticate it, as with 3D Secure
+    /// under SCA, and passes the hosted invoice URL on to outbound webhook subscribers and the
+    /// publisher, so the customer can be told before the subscription lapses.
+    async fn require_payment_action(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let invoice: Invoice = parse_object(object, "invoice")?;
+
+        let sub_id = match invoice.subscription_id() {
+            Some(sub_id) => sub_id.to_owned(),
+            None => {
+                tracing::info!("Invoice needing action is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+        let url = invoice.hosted_invoice_url;
+
+        let events = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            let count = self
+                .subscriptions
+                .record_payment_action(txn, &sub_id, std::time::SystemTime::now(), url.as_deref())
+                .await?;
+            if count == 0 {
+                tracing::info!("No subscription found for invoice needing action");
+                return Ok(Vec::new());
+            }
+
+            self.announce(txn, OutboundEvent::PaymentActionRequired, &sub_id)
+                .await
+        })
+        .await?;
+        self.publish(events.unwrap_or_default()).await;
+
+        Ok(())
+    }
+
+    /// Marks a subscription past due when a renewal payment fails, keeping access for the grace
+    /// period from the first failure. Retries within the same dunning period don't extend it
+    /// further.
+    async fn mark_past_due(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let invoice: Invoice = parse_object(object, "invoice")?;
+
+        let sub_id = match invoice.subscription_id() {
+            Some(sub_id) => sub_id.to_owned(),
+            None => {
+                tracing::info!("Failed invoice is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let now = std::time::SystemTime::now();
+
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .mark_past_due(txn, &sub_id, now, now + self.payment_grace_period)
+                .await
+        })
+        .await?;
+        if count == Some(0) {
+            tracing::info!(
+                "Subscription already past due or not found, not starting a grace period"
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn record_upcoming_invoice(
+        &self,
+        event_id: &str,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        let invoice: Invoice = parse_object(object, "invoice")?;
+
+        let sub_id = match invoice.subscription_id() {
+            Some(sub_id) => sub_id.to_owned(),
+            None => {
+                tracing::info!("Upcoming invoice is not for a subscription, ignoring");
+                return Ok(());
+            }
+        };
+
+        let due = to_timestamp(invoice.next_payment_attempt.unwrap_or(invoice.period_end))?;
+        let amount_due = invoice.amount_due;
+        let currency = &invoice.currency;
+
+        let count = db::with_event_transaction(&self.db_pool, event_id, async |txn| {
+            self.subscriptions
+                .record_upcoming_invoice(txn, &sub_id, amount_due, currency, due)
+                .await
+        })
+        .await?;
+        if count == Some(0) {
+            tracing::info!("No subscription found to record upcoming invoice for");
+        }
+
+        Ok(())
+    }
+
+    /// Stores the raw payload of a received event in `event_log` so it can be replayed later,
+    /// keyed by the fields `peek_event_meta` read from it. Redeliveries keep the originally stored
+    /// payload.
+    pub async fn log_event(
+        &self,
+        evt: &EventMeta<'_>,
+        payload: &[u8],
+    ) -> Result<(), OtterhoundError> {
+        self.insert_event_log(evt, payload, "pending").await
+    }
+
+    /// Like `log_event`, but marks the event for `claim_queued_events` instead of handling it in
+    /// the caller, so it is processed even if this process dies right after.
+    pub async fn enqueue_event(
+        &self,
+        evt: &EventMeta<'_>,
+        payload: &[u8],
+    ) -> Result<(), OtterhoundError> {
+        self.insert_event_log(evt, payload, "queued").await
+    }
+
+    async fn insert_event_log(
+        &self,
+        evt: &EventMeta<'_>,
+        payload: &[u8],
+        status: &str,
+    ) -> Result<(), OtterhoundError> {
+        let payload = String::from_utf8_lossy(payload);
+
+        execute(
+            &self.db_pool,
+            "INSERT INTO event_log (id, event_type, created, payload, status) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO NOTHING",
+            &[&evt.id, &evt.type_, &to_timestamp(evt.created)?, &payload, &status],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Claims up to `limit` events stored with `enqueue_event`, oldest first, for handling with
+    /// `handle_logged_event`. Claims expire after a lease period, so events held by a process that
+    /// died are picked up again.
+    pub async fn claim_queued_events(&self, limit: i64) -> Result<Vec<EventItem>, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "UPDATE event_log SET status='processing', next_attempt_at=now() + $2 * interval '1 second', updated_at=now() WHERE id IN (SELECT id FROM event_log WHERE status='queued' OR (status='processing' AND next_attempt_at <= now()) ORDER BY created LIMIT $1 FOR UPDATE SKIP LOCKED) RETURNING id, payload",
+            &[&limit, &CLAIM_LEASE.as_secs_f64()],
+        )
+        .await?;
+
+        let mut events = Vec::with_capacity(rows.len());
+        for row in rows {
+            let event_id: &str = row.get(0);
+            match serde_json::from_str(row.get(1)) {
+                Ok(evt) => events.push(evt),
+                Err(err) => {
+                    tracing::warn!("Failed to parse queued event {}: {:?}", event_id, err);
+                    let error = format!("Failed to parse event: {:?}", err);
+                    if let Err(err) = self.dead_letter_unparseable(event_id, &error).await {
+                        tracing::warn!("Failed to record event status: {}", err);
+                    }
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Moves a logged event straight to `dead_letter_events` when its payload isn't a valid event,
+    /// since retrying can't fix that. Only the fields `peek_event_meta` reads were checked when it
+    /// was stored.
+    async fn dead_letter_unparseable(
+        &self,
+        event_id: &str,
+        error: &str,
+    ) -> Result<u64, OtterhoundError> {
+        execute(
+            &self.db_pool,
+            "WITH moved AS (DELETE FROM event_log WHERE id=$1 RETURNING id, event_type, created, payload, attempts) INSERT INTO dead_letter_events (id, event_type, created, payload, error, attempts) SELECT id, event_type, created, payload, $2, attempts+1 FROM moved ON CONFLICT (id) DO UPDATE SET error=EXCLUDED.error, attempts=EXCLUDED.attempts, failed_at=now()",
+            &[&event_id, &error],
+        )
+        .await
+    }
+
+    /// Handles an event stored with `log_event`, recording the outcome in `event_log`. Failures are
+    /// scheduled for a retry with exponential backoff until the retry policy's attempts run out.
+    pub async fn handle_logged_event(&self, evt: EventItem) -> Result<(), OtterhoundError> {
+        let event_id = evt.id.clone();
+
+        let res = self.handle_event(evt).await;
+
+        let update = match &res {
+            Ok(()) => {
+                execute(
+                    &self.db_pool,
+                    "UPDATE event_log SET status='handled', error=NULL, attempts=attempts+1, next_attempt_at=NULL, updated_at=now() WHERE id=$1",
+                    &[&event_id],
+                )
+                .await
+            }
+            Err(err) => self.record_failure(&event_id, &err.to_string()).await,
+        };
+        if let Err(err) = update {
+            tracing::warn!("Failed to record event status: {}", err);
+        }
+
+        res
+    }
+
+    /// Schedules the next attempt for a failed event, or moves it to `dead_letter_events` once the
+    /// retry policy's attempts are used up.
+    async fn record_failure(&self, event_id: &str, error: &str) -> Result<u64, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "UPDATE event_log SET status='failed', error=$2, attempts=attempts+1, next_attempt_at=(CASE WHEN attempts+1 < $3 THEN now() + LEAST($4 * power(2, attempts), $5) * interval '1 second' ELSE NULL END), updated_at=now() WHERE id=$1 RETURNING next_attempt_at IS NULL",
+            &[
+                &event_id,
+                &error,
+                &(self.retry_policy.max_attempts as i32),
+                &self.retry_policy.base_delay.as_secs_f64(),
+                &self.retry_policy.max_delay.as_secs_f64(),
+            ],
+        )
+        .await?;
+
+        let exhausted = rows.first().map(|row| row.get(0)).unwrap_or(false);
+        if !exhausted {
+            return Ok(rows.len() as u64);
+        }
+
+        tracing::error!(
+            "Event {} failed on every attempt, moving it to dead_letter_events",
+            event_id
+        );
+
+        execute(
+            &self.db_pool,
+            "WITH moved AS (DELETE FROM event_log WHERE id=$1 RETURNING id, event_type, created, payload, error, attempts) INSERT INTO dead_letter_events (id, event_type, created, payload, error, attempts) SELECT * FROM moved ON CONFLICT (id) DO UPDATE SET error=EXCLUDED.error, attempts=EXCLUDED.attempts, failed_at=now()",
+            &[&event_id],
+        )
+        .await
+    }
+
+    /// Lists up to `limit` events from `event_log`, optionally only those with `status`, most
+    /// recently updated first.
+    pub async fn list_logged_events(
+        &self,
+        status: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<LoggedEvent>, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "SELECT id, event_type, created, status, error, attempts, updated_at FROM event_log WHERE $1::TEXT IS NULL OR status=$1 ORDER BY updated_at DESC LIMIT $2",
+            &[&status, &limit],
+        )
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| LoggedEvent {
+                id: row.get(0),
+                event_type: row.get(1),
+                created: row.get(2),
+                status: row.get(3),
+                error: row.get(4),
+                attempts: row.get(5),
+                updated_at: row.get(6),
+            })
+            .collect())
+    }
+
+    /// Lists a user's subscriptions, including ended ones, latest first.
+    pub async fn user_subscriptions(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<UserSubscription>, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "SELECT tier, start_timestamp, end_timestamp, stripe_subscription, status, cancel_at_period_end, cancelled_at, past_due_since, payment_method_missing, suspended_at, trial, trial_end FROM user_subscriptions WHERE user_id=$1 ORDER BY end_timestamp DESC",
+            &[&user_id],
+        )
+        .await?;
+
+        Ok(rows.into_iter().map(UserSubscription::from_row).collect())
+    }
+
+    /// The subscription currently granting a user access, i.e. not ended, cancelled or
+    /// suspended. If there are several, the one with the highest tier, then the latest end, is
+    /// returned.
+    pub async fn active_subscription(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<UserSubscription>, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "SELECT tier, start_timestamp, end_timestamp, stripe_subscription, status, cancel_at_period_end, cancelled_at, past_due_since, payment_method_missing, suspended_at, trial, trial_end FROM user_subscriptions WHERE user_id=$1 AND end_timestamp > now() AND cancelled_at IS NULL AND suspended_at IS NULL ORDER BY tier DESC, end_timestamp DESC LIMIT 1",
+            &[&user_id],
+        )
+        .await?;
+
+        Ok(rows.into_iter().next().map(UserSubscription::from_row))
+    }
+
+    /// Creates a subscription mode Checkout session for the tier's price from `TIER_PRICES`, and
+    /// records it so completing it grants the user the tier. A Stripe customer already recorded
+    /// for the user is reused. Returns `NotFound` for tiers without a price.
+    pub async fn create_checkout_session(
+        &self,
+        user_id: i32,
+        tier_id: i32,
+        success_url: &str,
+        cancel_url: Option<&str>,
+    ) -> Result<CreatedCheckoutSession, OtterhoundError> {
+        let price = self.tier_prices.get(&tier_id).ok_or_else(|| {
+            OtterhoundError::NotFound(format!("No price configured for tier {}", tier_id))
+        })?;
+
+        let customer = db::with_transaction(&self.db_pool, async |txn| {
+            self.subscriptions.customer_for_user(txn, user_id).await
+        })
+        .await?;
+
+        let user_id_str = user_id.to_string();
+        let user_id_param = format!("subscription_data[metadata][{}]", self.user_id_metadata_key);
+        let mut params = vec![
+            ("mode", "subscription"),
+            ("line_items[0][price]", price.as_str()),
+            ("line_items[0][quantity]", "1"),
+            ("success_url", success_url),
+            ("client_reference_id", &user_id_str),
+            (&user_id_param, &user_id_str),
+        ];
+        if let Some(cancel_url) = cancel_url {
+            params.push(("cancel_url", cancel_url));
+        }
+        if let Some(customer) = &customer {
+            params.push(("customer", customer));
+        }
+
+        let session: CheckoutSession = self
+            .stripe
+            .post(
+                "checkout/sessions",
+                &params,
+                &unique_idempotency_key(&format!("checkout-{}-{}", user_id, tier_id)),
+            )
+            .await?;
+        let url = session
+            .url
+            .ok_or_else(|| OtterhoundError::Parse("Checkout session has no url".to_owned()))?;
+
+        let session_id = &session.id;
+        db::with_transaction(&self.db_pool, async |txn| {
+            self.subscriptions
+                .insert_checkout_session(txn, session_id, user_id, tier_id)
+                .await
+        })
+        .await?;
+
+        tracing::info!(
+            "Created Checkout session {} for user {} and tier {}",
+            session.id,
+            user_id,
+            tier_id
+        );
+
+        Ok(CreatedCheckoutSession {
+            id: session.id,
+            url,
+        })
+    }
+
+    /// Creates a Stripe customer portal session for the user's recorded customer, returning its
+    /// URL. Returns `NotFound` if no customer is recorded for the user.
+    pub async fn create_billing_portal_session(
+        &self,
+        user_id: i32,
+        return_url: Option<&str>,
+    ) -> Result<String, OtterhoundError> {
+        let customer = db::with_transaction(&self.db_pool, async |txn| {
+            self.subscriptions.customer_for_user(txn, user_id).await
+        })
+        .await?
+        .ok_or_else(|| {
+            OtterhoundError::NotFound(format!("No Stripe customer recorded for user {}", user_id))
+        })?;
+
+        let mut params = vec![("customer", customer.as_str())];
+        if let Some(return_url) = return_url {
+            params.push(("return_url", return_url));
+        }
+
+        let session: BillingPortalSession = self
+            .stripe
+            .post(
+                "billing_portal/sessions",
+                &params,
+                &unique_idempotency_key(&format!("portal-{}", user_id)),
+            )
+            .await?;
+
+        tracing::info!(
+            "Created billing portal session {} for user {}",
+            session.id,
+            user_id
+        );
+
+        Ok(session.url)
+    }
+
+    /// Moves the user's active subscription to the tier's price from `TIER_PRICES`, prorating as
+    /// `proration_behavior` says. The tier in `user_subscriptions` changes once the resulting
+    /// `customer.subscription.updated` event arrives, so the price needs the tier in its
+    /// metadata like any other. Returns the Stripe subscription ID, or `NotFound` if the tier has
+    /// no price or the user has no active subscription billed through Stripe.
+    pub async fn change_user_tier(
+        &self,
+        user_id: i32,
+        tier_id: i32,
+        proration_behavior: ProrationBehavior,
+    ) -> Result<String, OtterhoundError> {
+        let price = self.tier_prices.get(&tier_id).ok_or_else(|| {
+            OtterhoundError::NotFound(format!("No price configured for tier {}", tier_id))
+        })?;
+        let stripe_subscription = self
+            .active_subscription(user_id)
+            .await?
+            .and_then(|sub| sub.stripe_subscription)
+            .ok_or_else(|| {
+                OtterhoundError::NotFound(format!(
+                    "No active Stripe subscription for user {}",
+                    user_id
+                ))
+            })?;
+        let path = format!("subscriptions/{}", stripe_subscription);
+
+        let sub: Subscription = self.stripe.get(&path).await?;
+        let item_id = sub
+            .tier_item(&self.tier_metadata_key)
+            .and_then(|item| item.id.clone())
+            .ok_or_else(|| {
+                OtterhoundError::Parse(format!(
+                    "Subscription {} has no item with {} metadata",
+                    stripe_subscription, self.tier_metadata_key
+                ))
+            })?;
+
+        let _: Subscription = self
+            .stripe
+            .post(
+                &path,
+                &[
+                    ("items[0][id]", item_id.as_str()),
+                    ("items[0][price]", price.as_str()),
+                    ("proration_behavior", proration_behavior.as_str()),
+                ],
+                &unique_idempotency_key(&format!("tier-{}-{}", stripe_subscription, tier_id)),
+            )
+            .await?;
+
+        tracing::info!(
+            "Moved subscription {} for user {} to tier {}",
+            stripe_subscription,
+            user_id,
+            tier_id
+        );
+
+        Ok(stripe_subscription)
+    }
+
+    /// Cancels the user's active subscription in Stripe, either `immediately` or at the end of
+    /// the current period, and records the result the same way the webhook Stripe sends for it
+    /// will, so that webhook changes nothing further. Returns the Stripe subscription ID, or
+    /// `NotFound` if the user has no active subscription billed through Stripe.
+    pub async fn cancel_user_subscription(
+        &self,
+        user_id: i32,
+        immediately: bool,
+    ) -> Result<String, OtterhoundError> {
+        let stripe_subscription = self
+            .active_subscription(user_id)
+            .await?
+            .and_then(|sub| sub.stripe_subscription)
+            .ok_or_else(|| {
+                OtterhoundError::NotFound(format!(
+                    "No active Stripe subscription for user {}",
+                    user_id
+                ))
+            })?;
+        let path = format!("subscriptions/{}", stripe_subscription);
+
+        if immediately {
+            let sub: Subscription = self.stripe.delete(&path).await?;
+            let events = db::with_transaction(&self.db_pool, async |txn| {
+                self.record_subscription_ended(txn, &sub).await
+            })
+            .await?;
+            self.publish(events).await;
+        } else {
+            let sub: Subscription = self
+                .stripe
+                .post(
+                    &path,
+                    &[("cancel_at_period_end", "true")],
+                    &unique_idempotency_key(&format!("cancel-{}", stripe_subscription)),
+                )
+                .await?;
+            let update = self.subscription_update(&sub)?;
+            db::with_transaction(&self.db_pool, async |txn| {
+                self.subscriptions.update_subscription(txn, &update).await
+            })
+            .await?;
+        }
+
+        tracing::info!(
+            "Cancelled subscription {} for user {}{}",
+            stripe_subscription,
+            user_id,
+            if immediately { "" } else { " at period end" }
+        );
+
+        Ok(stripe_subscription)
+    }
+
+    /// Lists events that failed on every attempt, most recent failure first.
+    pub async fn list_dead_letter_events(&self) -> Result<Vec<DeadLetterEvent>, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "SELECT id, event_type, created, error, attempts, failed_at FROM dead_letter_events ORDER BY failed_at DESC",
+            &[],
+        )
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DeadLetterEvent {
+                id: row.get(0),
+                event_type: row.get(1),
+                created: row.get(2),
+                error: row.get(3),
+                attempts: row.get(4),
+                failed_at: row.get(5),
+            })
+            .collect())
+    }
+
+    /// Moves a dead-lettered event back into `event_log` with a fresh set of attempts, due
+    /// immediately. Returns false if there is no such event.
+    pub async fn requeue_dead_letter_event(&self, id: &str) -> Result<bool, OtterhoundError> {
+        let count = execute(
+            &self.db_pool,
+            "WITH moved AS (DELETE FROM dead_letter_events WHERE id=$1 RETURNING id, event_type, created, payload, error) INSERT INTO event_log (id, event_type, created, payload, status, error, attempts, next_attempt_at) SELECT id, event_type, created, payload, 'failed', error, 0, now() FROM moved ON CONFLICT (id) DO UPDATE SET status='failed', attempts=0, next_attempt_at=now(), updated_at=now()",
+            &[&id],
+        )
+        .await?;
+
+        Ok(count > 0)
+    }
+
+    /// Retries up to `limit` failed events whose backoff has elapsed, returning how many were
+    /// attempted. Claimed events are hidden from other instances for a lease period, so several
+    /// servers can run this concurrently.
+    pub async fn retry_due_events(&self, limit: i64) -> Result<usize, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "UPDATE event_log SET next_attempt_at=now() + $2 * interval '1 second' WHERE id IN (SELECT id FROM event_log WHERE status='failed' AND next_attempt_at <= now() ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED) RETURNING id, payload",
+            &[&limit, &CLAIM_LEASE.as_secs_f64()],
+        )
+        .await?;
+
+        let count = rows.len();
+        for row in rows {
+            let event_id: &str = row.get(0);
+            let evt: EventItem = match serde_json::from_str(row.get(1)) {
+                Ok(evt) => evt,
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to parse logged event {} for retry: {:?}",
+                        event_id,
+                        err
+                    );
+                    let error = format!("Failed to parse event: {:?}", err);
+                    if let Err(err) = self.dead_letter_unparseable(event_id, &error).await {
+                        tracing::warn!("Failed to record event status: {}", err);
+                    }
+                    continue;
+                }
+            };
+
+            tracing::info!("Retrying event {}", evt.id);
+            if let Err(err) = self.handle_logged_event(evt).await {
+                tracing::error!("Retry failed: {}", err);
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Sets `expired_at` on subscriptions whose end timestamp passed more than
+    /// `EXPIRY_GRACE_SECS` ago, returning how many were expired. Each expiry is announced with
+    /// `NOTIFY subscription_expired, '<user_id>'`, as well as `subscription_changed`. Renewals
+    /// clear `expired_at` again.
+    pub async fn expire_lapsed_subscriptions(&self) -> Result<usize, OtterhoundError> {
+        let rows = query(
+            &self.db_pool,
+            "WITH expired AS (UPDATE user_subscriptions SET expired_at=now() WHERE expired_at IS NULL AND end_timestamp < now() - $1 * interval '1 second' RETURNING user_id, stripe_subscription) SELECT user_id, stripe_subscription, pg_notify('subscription_expired', user_id::TEXT), pg_notify('subscription_changed', user_id::TEXT) FROM expired",
+            &[&self.expiry_grace_period.as_secs_f64()],
+        )
+        .await?;
+
+        for row in &rows {
+            let user_id: i32 = row.get(0);
+            let stripe_subscription: Option<&str> = row.get(1);
+            tracing::info!(
+                "Subscription {} of user {} expired",
+                stripe_subscription.unwrap_or("(none)"),
+                user_id
+            );
+        }
+        metrics::counter!("otterhound_subscriptions_expired_total").increment(rows.len() as u64);
+
+        Ok(rows.len())
+    }
+
+    /// Sends up to `limit` due outbound webhooks to `webhook_subscribers`, returning how many were
+    /// attempted. Failed deliveries are retried following the same `RetryPolicy` as events.
+    pub async fn deliver_outbound_webhooks(&self, limit: i64) -> Result<usize, OtterhoundError> {
+        outbound::deliver_due(&self.db_pool, &self.http_client, &self.retry_policy, limit).await
+    }
+
+    /// Loads events from `event_log` in creation order, for replaying with `handle_logged_event`.
+    pub async fn load_logged_events(
+        &self,
+        selection: EventSelection,
+    ) -> Result<Vec<EventItem>, OtterhoundError> {
+        let rows = match selection {
+            EventSelection::Failed => {
+                query(
+                    &self.db_pool,
+                    "SELECT payload FROM event_log WHERE status='failed' ORDER BY created",
+                    &[],
+                )
+                .await?
+            }
+            EventSelection::Ids(ids) => {
+                query(
+                    &self.db_pool,
+                    "SELECT payload FROM event_log WHERE id=ANY($1) ORDER BY created",
+                    &[&ids],
+                )
+                .await?
+            }
+        };
+
+        rows.into_iter()
+            .map(|row| {
+                let payload: &str = row.get(0);
+                serde_json::from_str(payload).map_err(|err| {
+                    OtterhoundError::Parse(format!("Failed to parse logged event: {:?}", err))
+                })
+            })
+            .collect()
+    }
+
+    /// Compares every subscription in the Stripe account with its `user_subscriptions` row, to find
+    /// changes missed while webhooks weren't delivered. With `fix`, rows are updated the way the
+    /// missed `customer.subscription.updated` or `customer.subscription.deleted` event would have.
+    /// Missing rows are only reported, since the user can't be known from the subscription alone.
+    pub async fn reconcile(
+        &self,
+        fix: bool,
+    ) -> Result<Vec<SubscriptionDiscrepancies>, OtterhoundError> {
+        let recorded: std::collections::HashMap<String, (String, std::time::SystemTime, bool)> =
+            query(
+                &self.db_pool,
+                "SELECT stripe_subscription, status, end_timestamp, cancelled_at IS NOT NULL FROM user_subscriptions WHERE stripe_subscription IS NOT NULL",
+                &[],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), (row.get(1), row.get(2), row.get(3))))
+            .collect();
+
+        let subs: Vec<Subscription> = self.stripe.get_all("subscriptions?status=all").await?;
+        tracing::info!("Comparing {} subscriptions", subs.len());
+
+        let mut found = Vec::new();
+        for sub in subs {
+            let (status, end_timestamp, cancelled) = match recorded.get(&sub.id) {
+                Some(row) => row,
+                None => {
+                    // subscriptions that ended before otterhound saw them were never granted access
+                    if sub.status != "canceled" && sub.status != "incomplete_expired" {
+                        found.push(SubscriptionDiscrepancies {
+                            stripe_subscription: sub.id,
+                            discrepancies: vec![Discrepancy::Missing],
+                            fixed: false,
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            let mut discrepancies = Vec::new();
+            if sub.status == "canceled" {
+                if !cancelled {
+                    discrepancies.push(Discrepancy::NotCancelled);
+                }
+            } else {
+                if sub.status != *status {
+                    discrepancies.push(Discrepancy::Status {
+                        stripe: sub.status.clone(),
+                        recorded: status.clone(),
+                    });
+                }
+                if sub.status == "active" || sub.status == "trialing" {
+                    let period_end = to_timestamp(sub.period_end()?)?
+                        + self
+                            .policy_for_currency(sub.currency.as_deref())
+                            .access_buffer;
+                    if *end_timestamp < period_end {
+                        discrepancies.push(Discrepancy::PeriodEnd {
+                            stripe: period_end,
+                            recorded: *end_timestamp,
+                        });
+                    }
+                }
+            }
+
+            if discrepancies.is_empty() {
+                continue;
+            }
+
+            let fixed = fix && {
+                self.fix_subscription(&sub).await?;
+                true
+            };
+            found.push(SubscriptionDiscrepancies {
+                stripe_subscription: sub.id,
+                discrepancies,
+                fixed,
+            });
+        }
+
+        Ok(found)
+    }
+
+    async fn fix_subscription(&self, sub: &Subscription) -> Result<(), OtterhoundError> {
+        if sub.status == "canceled" {
+            let ended_at = sub
+                .ended_at
+                .map(to_timestamp)
+                .transpose()?
+                .unwrap_or_else(std::time::SystemTime::now);
+
+            return db::with_transaction(&self.db_pool, async |txn| {
+                self.subscriptions
+                    .cancel_subscription(txn, &sub.id, ended_at)
+                    .await
+            })
+            .await
+            .map(|_| ());
+        }
+
+        let update = self.subscription_update(sub)?;
+        db::with_transaction(&self.db_pool, async |txn| {
+            self.subscriptions.update_subscription(txn, &update).await
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Seeds `user_subscriptions` with the account's active, trialing and past due subscriptions,
+    /// for moving an existing customer base onto otterhound. The user is read from the customer's
+    /// metadata, or else from the `client_reference_id` of the Checkout session that created the
+    /// subscription, and the tier from the price's metadata. Subscriptions that already have a row
+    /// are left alone, so the import can be repeated. With `dry_run`, nothing is written.
+    pub async fn import_subscriptions(
+        &self,
+        dry_run: bool,
+    ) -> Result<Vec<(String, ImportOutcome)>, OtterhoundError> {
+        let recorded: std::collections::HashSet<String> = query(
+            &self.db_pool,
+            "SELECT stripe_subscription FROM user_subscriptions WHERE stripe_subscription IS NOT NULL",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect();
+
+        let subs: Vec<Subscription> = self
+            .stripe
+            .get_all("subscriptions?status=all&expand%5B%5D=data.customer")
+            .await?;
+
+        let mut outcomes = Vec::new();
+        for sub in subs {
+            if !["active", "trialing", "past_due"].contains(&sub.status.as_str()) {
+                continue;
+            }
+
+            let outcome = if recorded.contains(&sub.id) {
+                ImportOutcome::AlreadyRecorded
+            } else {
+                self.import_subscription(&sub, dry_run).await?
+            };
+            outcomes.push((sub.id, outcome));
+        }
+
+        Ok(outcomes)
+    }
+
+    async fn import_subscription(
+        &self,
+        sub: &Subscription,
+        dry_run: bool,
+    ) -> Result<ImportOutcome, OtterhoundError> {
+        let tier_id = match sub.tier_id(&self.tier_metadata_key)? {
+            Some(tier_id) => tier_id,
+            None => {
+                return Ok(ImportOutcome::Skipped(format!(
+                    "price has no {} metadata",
+                    self.tier_metadata_key
+                )))
+            }
+        };
+
+        let customer = sub.customer.as_object();
+        let user_id = match customer {
+            Some(customer) => customer.user_id(&self.user_id_metadata_key)?,
+            None => None,
+        };
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => {
+                let sessions: List<CheckoutSession> = self
+                    .stripe
+                    .get(&format!("checkout/sessions?subscription={}", sub.id))
+                    .await?;
+                let reference = sessions
+                    .data
+                    .iter()
+                    .find_map(|session| session.client_reference_id.as_deref());
+                match reference.map(str::parse) {
+                    Some(Ok(user_id)) => user_id,
+                    Some(Err(_)) => {
+                        return Ok(ImportOutcome::Skipped(
+                            "client_reference_id is not a user ID".to_owned(),
+                        ))
+                    }
+                    None => {
+                        return Ok(ImportOutcome::Skipped(format!(
+                            "customer has no {} metadata and no session has a client_reference_id",
+                            self.user_id_metadata_key
+                        )))
+                    }
+                }
+            }
+        };
+
+        if dry_run {
+            return Ok(ImportOutcome::Imported { user_id, tier_id });
+        }
+
+        let new_subscription = NewSubscription {
+            tier_id,
+            user_id,
+            start_timestamp: to_timestamp(sub.created)?,
+            end_timestamp: to_timestamp(sub.period_end()?)?
+                + self
+                    .policy_for_currency(sub.currency.as_deref())
+                    .access_buffer,
+            stripe_subscription: &sub.id,
+            payment_method_missing: sub.payment_method_missing(customer),
+            trial_end: sub.current_trial_end().map(to_timestamp).transpose()?,
+        };
+        db::with_transaction(&self.db_pool, async |txn| {
+            self.subscriptions
+                .insert_subscription(txn, &new_subscription)
+                .await?;
+            self.subscriptions
+                .record_customer(txn, user_id, sub.customer.id())
+                .await
+        })
+        .await?;
+
+        Ok(ImportOutcome::Imported { user_id, tier_id })
+    }
+
+    /// Finds users with more than one active subscription, for reconciliation.
+    pub async fn find_duplicate_active_subscriptions(
+        &self,
+        filter: ActiveSubscriptionFilter,
+    ) -> Result<Vec<DuplicateSubscriptions>, OtterhoundError> {
+        let query_str = if filter.exclude_cancelled {
+            "SELECT user_id, array_agg(stripe_subscription) FILTER (WHERE stripe_subscription IS NOT NULL) FROM user_subscriptions WHERE end_timestamp > $1 AND cancelled_at IS NULL GROUP BY user_id HAVING count(*) > 1"
+        } else {
+            "SELECT user_id, array_agg(stripe_subscription) FILTER (WHERE stripe_subscription IS NOT NULL) FROM user_subscriptions WHERE end_timestamp > $1 GROUP BY user_id HAVING count(*) > 1"
+        };
+
+        let rows = query(&self.db_pool, query_str, &[&filter.as_of]).await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DuplicateSubscriptions {
+                user_id: row.get(0),
+                // NULL when none of the user's rows has a Stripe subscription
+                stripe_subscriptions: row.get::<_, Option<Vec<String>>>(1).unwrap_or_default(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_not_matching_event_type() {
+        // an invoice event carrying a charge, as the invoice handlers would receive it
+        let mut event: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/charge_refunded.json")).unwrap();
+        event["type"] = "invoice.payment_succeeded".into();
+        let event: EventItem = serde_json::from_value(event).unwrap();
+
+        match parse_object::<Invoice>(event.data.object, "invoice") {
+            Err(OtterhoundError::Parse(message)) => {
+                assert_eq!(message, "Expected object type invoice, but found charge")
+            }
+            other => panic!("expected a Parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn object_without_type() {
+        let object = serde_json::json!({ "id": "in_123" });
+        match parse_object::<Invoice>(object, "invoice") {
+            Err(OtterhoundError::Parse(message)) => {
+                assert_eq!(message, "Expected object type invoice, but found none")
+            }
+            other => panic!("expected a Parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn peek_event_meta_reads_dispatch_fields() {
+        let body = include_bytes!("../tests/fixtures/checkout_session_completed.json");
+        let meta = peek_event_meta(body).unwrap();
+        assert_eq!(meta.id, "evt_1QbF2pLkdIwHu7ixKd8Xq3Ns");
+        assert_eq!(meta.type_, "checkout.session.completed");
+        assert_eq!(meta.created, 1735689612);
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        for stamp in [0, 1, 1735689577, u32::MAX as u64 + 1] {
+            assert_eq!(from_timestamp(to_timestamp(stamp).unwrap()).unwrap(), stamp);
+        }
+    }
+
+    #[test]
+    fn timestamp_drops_subseconds() {
+        let time = to_timestamp(1735689577).unwrap() + std::time::Duration::from_millis(999);
+        assert_eq!(from_timestamp(time).unwrap(), 1735689577);
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        assert!(matches!(
+            to_timestamp(u64::MAX),
+            Err(OtterhoundError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn coalesced_update_keeps_earliest_previous_values() {
+        let update = |event_id: &str, previous_attributes: serde_json::Value| BufferedUpdate {
+            event_id: event_id.to_owned(),
+            object: serde_json::json!({"id": "sub_1", "event": event_id}),
+            previous_attributes: Some(previous_attributes),
+        };
+        let earlier = update(
+            "evt_1",
+            serde_json::json!({"items": {"tier": 2}, "cancel_at_period_end": true}),
+        );
+        let later = update(
+            "evt_2",
+            serde_json::json!({"items": {"tier": 1}, "status": "trialing"}),
+        );
+
+        let coalesced = earlier.coalesce(later);
+
+        assert_eq!(coalesced.event_id, "evt_2");
+        assert_eq!(coalesced.object["event"], "evt_2");
+        assert_eq!(
+            coalesced.previous_attributes,
+            Some(serde_json::json!({
+                "items": {"tier": 2},
+                "cancel_at_period_end": true,
+                "status": "trialing",
+            }))
+        );
+    }
+
+    #[test]
+    fn tier_changes() {
+        assert_eq!(
+            TierChange::between(Some(1), Some(2)),
+            Some(TierChange::Upgrade)
+        );
+        assert_eq!(
+            TierChange::between(Some(2), Some(1)),
+            Some(TierChange::Downgrade)
+        );
+        assert_eq!(TierChange::between(Some(2), Some(2)), None);
+        // the items changed without touching the tier, or a tier isn't set
+        assert_eq!(TierChange::between(None, Some(2)), None);
+        assert_eq!(TierChange::between(Some(2), None), None);
+    }
+
+    #[test]
+    fn timestamp_before_epoch() {
+        let time = std::time::SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert!(matches!(
+            from_timestamp(time),
+            Err(OtterhoundError::Parse(_))
+        ));
     }
 }
@@ -1,146 +1,1278 @@
-use futures::{Future, IntoFuture, Stream};
-use hmac::crypto_mac::Mac;
+use futures::StreamExt;
+use otterhound::{OtterhoundError, ProcessingMode};
 use std::sync::Arc;
+use tracing::Instrument;
 
-const MAX_TIME_DIFF: std::time::Duration = std::time::Duration::from_secs(60 * 5);
+mod admin;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod internal;
+// the integration suite's database and Stripe fakes, for the server tests
+#[cfg(all(test, feature = "integration-tests"))]
+#[allow(dead_code)]
+#[path = "../tests/integration/support.rs"]
+mod support;
 
 struct ServerState {
-    signing_secret: String,
-    otterhound: otterhound::Otterhound,
+    metrics: metrics_exporter_prometheus::PrometheusHandle,
+    /// Any of these is accepted, so the old and new secrets both work while rotating.
+    signing_secrets: Vec<String>,
+    signature_tolerance: std::time::Duration,
+    /// Path Stripe posts events to.
+    webhook_path: String,
+    /// Larger webhook bodies are rejected with 413.
+    max_body_bytes: usize,
+    /// Bearer tokens for the admin and internal APIs, which are disabled without any.
+    admin_tokens: Vec<otterhound::AdminToken>,
+    /// Shared with the worker's event tasks.
+    otterhound: Arc<otterhound::Otterhound>,
+    processing_mode: ProcessingMode,
+    processing_mode_overrides: std::collections::HashMap<String, ProcessingMode>,
+    processing_handle: Option<tokio::runtime::Handle>,
+    worker: otterhound::worker::Worker,
+    /// Bounds how many events `Sync` requests and the `Queue` workers handle at once, so a burst
+    /// of webhooks waits for a permit instead of exhausting the database pool. The `Background`
+    /// worker is bounded by its own concurrency instead.
+    event_permits: tokio::sync::Semaphore,
+    /// Present in `Queue` mode.
+    queue: Option<tokio::sync::mpsc::Sender<otterhound::EventItem>>,
+    /// Epoch seconds when the last verified event was received, for the silence watchdog.
+    last_event_received: std::sync::atomic::AtomicU64,
 }
 
-fn handle_request(
+impl ServerState {
+    fn processing_mode_for(&self, event_type: &str) -> ProcessingMode {
+        self.processing_mode_overrides
+            .get(event_type)
+            .cloned()
+            .unwrap_or(self.processing_mode)
+    }
+
+    /// Seconds since the last verified event, also set as the
+    /// `otterhound_seconds_since_last_event` gauge.
+    fn record_silence(&self) -> u64 {
+        let silent_for = now_secs().saturating_sub(
+            self.last_event_received
+                .load(std::sync::atomic::Ordering::Relaxed),
+        );
+        metrics::gauge!("otterhound_seconds_since_last_event").set(silent_for as f64);
+
+        silent_for
+    }
+
+    async fn acquire_event_permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.event_permits
+            .acquire()
+            .await
+            .expect("Event permits were closed")
+    }
+
+    /// Spawns event processing on the dedicated processing runtime, if one is configured.
+    fn spawn<F>(&self, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.processing_handle {
+            Some(handle) => handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+
+    /// Handles an event and waits for the outcome, on the processing runtime if one is configured.
+    async fn process(self: Arc<Self>, evt: otterhound::EventItem) -> Result<(), OtterhoundError> {
+        let _permit = self.acquire_event_permit().await;
+
+        if self.processing_handle.is_none() {
+            return self.otterhound.handle_logged_event(evt).await;
+        }
+
+        let state = self.clone();
+        match self
+            .spawn(async move { state.otterhound.handle_logged_event(evt).await })
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    otterhound::from_timestamp(std::time::SystemTime::now()).unwrap_or(0)
+}
+
+fn version_response() -> hyper::Response<hyper::Body> {
+    let body = serde_json::json!({
+        "version": otterhound::VERSION,
+        "git_sha": otterhound::GIT_SHA,
+        "build_timestamp": otterhound::BUILD_TIMESTAMP,
+    });
+
+    let mut res = hyper::Response::new(body.to_string().into());
+    res.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+
+    res
+}
+
+/// Handles a request in a span tagged with its request ID, taken from `X-Request-Id` if the caller
+/// set one. The ID is echoed back in the response.
+async fn handle_request(
     req: hyper::Request<hyper::Body>,
     state: Arc<ServerState>,
-) -> impl Future<Item = hyper::Response<hyper::Body>, Error = hyper::Error> + Send {
-    req.headers()
+) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    static NEXT_REQUEST_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    let request_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+        .unwrap_or_else(|| {
+            format!(
+                "{:x}-{:x}",
+                now_secs(),
+                NEXT_REQUEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            )
+        });
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path()
+    );
+
+    let mut res = route_request(req, state).instrument(span).await?;
+    if let Ok(value) = hyper::header::HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert("X-Request-Id", value);
+    }
+
+    Ok(res)
+}
+
+async fn route_request(
+    req: hyper::Request<hyper::Body>,
+    state: Arc<ServerState>,
+) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    let path = req.uri().path();
+
+    if path.starts_with("/admin/") {
+        return Ok(admin::handle(req, state).await);
+    }
+    if path.starts_with("/internal/") {
+        return Ok(internal::handle(req, state).await);
+    }
+
+    if path != state.webhook_path {
+        if !matches!(path, "/version" | "/healthz" | "/metrics" | "/readyz") {
+            return Ok(status_response(hyper::StatusCode::NOT_FOUND));
+        }
+        if req.method() != hyper::Method::GET {
+            return Ok(method_not_allowed_response("GET"));
+        }
+
+        return Ok(match path {
+            "/version" => version_response(),
+            "/healthz" => status_response(hyper::StatusCode::OK),
+            "/metrics" => metrics_response(&state),
+            _ => match state.otterhound.check_ready().await {
+                Ok(()) => status_response(hyper::StatusCode::OK),
+                Err(err) => {
+                    tracing::warn!("Readiness check failed: {}", err);
+                    status_response(hyper::StatusCode::SERVICE_UNAVAILABLE)
+                }
+            },
+        });
+    }
+    if req.method() != hyper::Method::POST {
+        return Ok(method_not_allowed_response("POST"));
+    }
+
+    metrics::counter!("otterhound_webhooks_received_total").increment(1);
+
+    match handle_webhook(req, state).await {
+        Ok(res) => Ok(res),
+        Err(err) => {
+            tracing::error!("Error in request handler: {}", err);
+
+            match err {
+                OtterhoundError::Signature(_) => {
+                    metrics::counter!("otterhound_webhooks_rejected_total", "reason" => "signature")
+                        .increment(1)
+                }
+                OtterhoundError::Replay(_) => {
+                    metrics::counter!("otterhound_webhooks_rejected_total", "reason" => "replay")
+                        .increment(1);
+                    metrics::counter!("otterhound_replayed_events_total").increment(1);
+                }
+                OtterhoundError::InvalidRequest(_) => {
+                    metrics::counter!("otterhound_webhooks_rejected_total", "reason" => "parse")
+                        .increment(1)
+                }
+                _ => {}
+            }
+
+            Ok(error_response(&err))
+        }
+    }
+}
+
+fn metrics_response(state: &ServerState) -> hyper::Response<hyper::Body> {
+    state.otterhound.record_pool_metrics();
+    state.otterhound.record_circuit_metrics();
+    state.record_silence();
+    state.metrics.run_upkeep();
+
+    let mut res = hyper::Response::new(state.metrics.render().into());
+    res.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    res
+}
+
+fn status_response(status: hyper::StatusCode) -> hyper::Response<hyper::Body> {
+    let mut res = hyper::Response::new(status.canonical_reason().unwrap_or("Error").into());
+    *res.status_mut() = status;
+    res
+}
+
+fn method_not_allowed_response(allow: &'static str) -> hyper::Response<hyper::Body> {
+    let mut res = status_response(hyper::StatusCode::METHOD_NOT_ALLOWED);
+    res.headers_mut().insert(
+        hyper::header::ALLOW,
+        hyper::header::HeaderValue::from_static(allow),
+    );
+    res
+}
+
+/// Picks the response status for a failed webhook. Stripe retries anything but a 2xx, so only
+/// transient failures get a 5xx. Bad signatures and bodies get a 400, and permanent processing
+/// failures, including objects our handlers can't parse, are acknowledged since the event is
+/// already in `event_log` for our own retries.
+///
+/// Every variant is listed, so a new one needs a decision here.
+fn status_for(err: &OtterhoundError) -> hyper::StatusCode {
+    match err {
+        OtterhoundError::Signature(_)
+        | OtterhoundError::Replay(_)
+        | OtterhoundError::InvalidRequest(_) => hyper::StatusCode::BAD_REQUEST,
+        OtterhoundError::StripeUnavailable => hyper::StatusCode::SERVICE_UNAVAILABLE,
+        OtterhoundError::Db(_)
+        | OtterhoundError::DbRetryable(..)
+        | OtterhoundError::StripeApi(_)
+        | OtterhoundError::TimedOut(_)
+        | OtterhoundError::Panicked(_)
+        | OtterhoundError::Abandoned(_) => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+        OtterhoundError::Parse(_)
+        | OtterhoundError::NotFound(_)
+        | OtterhoundError::Config(_)
+        | OtterhoundError::Publish(_) => hyper::StatusCode::OK,
+    }
+}
+
+fn error_response(err: &OtterhoundError) -> hyper::Response<hyper::Body> {
+    status_response(status_for(err))
+}
+
+/// Reads a body of at most `limit` bytes, or returns `None` as soon as it is known to be larger,
+/// either from its `Content-Length` or from what has been read so far.
+async fn read_body(
+    mut body: hyper::Body,
+    limit: usize,
+) -> Result<Option<hyper::body::Bytes>, hyper::Error> {
+    use hyper::body::HttpBody;
+
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(buf.into()))
+}
+
+async fn handle_webhook(
+    req: hyper::Request<hyper::Body>,
+    state: Arc<ServerState>,
+) -> Result<hyper::Response<hyper::Body>, OtterhoundError> {
+    let sig_header = req
+        .headers()
         .get("Stripe-Signature")
-        .ok_or_else(|| "Missing Signature".to_owned())
-        .and_then(|sig_data| {
-            let mut timestamp = None;
-            let mut signatures = Vec::new();
-            sig_data
-                .to_str()
-                .map_err(|err| format!("Failed to read header: {:?}", err))?
-                .split(',')
-                .for_each(|pair| {
-                    let mut spl = pair.split('=');
-                    let key = spl.next().unwrap();
-                    if key == "t" {
-                        timestamp = spl.next().map(|x| x.to_owned());
-                    } else if key == "v1" {
-                        if let Some(sig) = spl.next() {
-                            signatures.push(sig.to_owned());
-                        }
-                    }
-                });
+        .ok_or_else(|| OtterhoundError::Signature("Missing signature".to_owned()))?
+        .to_str()
+        .map_err(|err| OtterhoundError::Signature(format!("Failed to read header: {:?}", err)))?
+        .to_owned();
 
-            timestamp
-                .ok_or_else(|| "Missing timestamp".to_owned())
-                .map(|timestamp| (timestamp, signatures))
-        })
-        .into_future()
-        .and_then({
+    let body = match read_body(req.into_body(), state.max_body_bytes)
+        .await
+        .map_err(|err| OtterhoundError::InvalidRequest(format!("Failed reading body: {:?}", err)))?
+    {
+        Some(body) => body,
+        None => {
+            tracing::warn!(
+                "Rejected webhook body larger than {} bytes",
+                state.max_body_bytes
+            );
+            metrics::counter!("otterhound_webhooks_rejected_total", "reason" => "too_large")
+                .increment(1);
+            return Ok(status_response(hyper::StatusCode::PAYLOAD_TOO_LARGE));
+        }
+    };
+
+    tracing::info_span!("verify_signature").in_scope(|| {
+        otterhound::signature::verify(
+            &sig_header,
+            &body,
+            &state.signing_secrets,
+            state.signature_tolerance,
+        )
+    })?;
+
+    metrics::counter!("otterhound_webhooks_verified_total").increment(1);
+
+    let meta = otterhound::peek_event_meta(&body)?;
+    state.otterhound.record_delivery(meta.id);
+    let processing_mode = state.processing_mode_for(meta.type_);
+    // the worker parses queued events itself, so only the other modes need the whole event here
+    let evt = if processing_mode == ProcessingMode::Background {
+        state.otterhound.enqueue_event(&meta, &body).await?;
+        None
+    } else {
+        let evt: otterhound::EventItem = serde_json::from_slice(&body).map_err(|err| {
+            OtterhoundError::InvalidRequest(format!("Failed to parse body: {:?}", err))
+        })?;
+        // `Queue` skips the write, giving up durability for it
+        if processing_mode == ProcessingMode::Sync {
+            state.otterhound.log_event(&meta, &body).await?;
+        }
+        Some(evt)
+    };
+
+    state
+        .last_event_received
+        .store(now_secs(), std::sync::atomic::Ordering::Relaxed);
+
+    match evt {
+        None => {
+            state.worker.wake();
+
+            Ok(hyper::Response::new(hyper::Body::empty()))
+        }
+        Some(evt) if processing_mode == ProcessingMode::Sync => {
+            state.process(evt).await?;
+
+            Ok(hyper::Response::new(hyper::Body::empty()))
+        }
+        Some(evt) => {
+            let queue = state.queue.as_ref().expect("Queue mode without a queue");
+            match queue.try_send(evt) {
+                Ok(()) => Ok(hyper::Response::new(hyper::Body::empty())),
+                Err(err) => {
+                    tracing::warn!("Failed to enqueue event: {:?}", err);
+                    Ok(status_response(hyper::StatusCode::SERVICE_UNAVAILABLE))
+                }
+            }
+        }
+    }
+}
+
+/// Serves requests until SIGINT or SIGTERM. In-flight requests, including `Sync` processing,
+/// finish before this returns, with debounced subscription updates written without waiting out
+/// their window.
+async fn serve<I>(builder: hyper::server::Builder<I>, state: Arc<ServerState>) -> hyper::Result<()>
+where
+    I: hyper::server::accept::Accept,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    I::Conn: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let otterhound = state.otterhound.clone();
+    builder
+        .serve(hyper::service::make_service_fn(move |_| {
             let state = state.clone();
-            |(timestamp, signatures)| {
-                req.into_body()
-                    .concat2()
-                    .map_err(|err| format!("Failed reading body: {:?}", err))
-                    .and_then(move |body| {
-                        let signed_payload = {
-                            let mut value = timestamp.as_bytes().to_vec();
-                            value.push(b'.');
-                            value.extend_from_slice(&body);
-                            value
-                        };
-
-                        let mut mac =
-                            hmac::Hmac::<sha2::Sha256>::new_varkey(state.signing_secret.as_bytes())
-                                .unwrap();
-                        mac.input(&signed_payload);
-                        let expected = mac.result();
-
-                        for sig in signatures {
-                            let sig = hex::decode(sig);
-                            if let Ok(sig) = sig {
-                                if expected
-                                    == hmac::crypto_mac::MacResult::new(
-                                        generic_array::GenericArray::clone_from_slice(&sig),
-                                    )
-                                {
-                                    return Ok((timestamp, body));
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+                    handle_request(req, state.clone())
+                }))
+            }
+        }))
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            otterhound.flush_updates();
+        })
+        .await
+}
+
+/// Loads the certificate chain and private key to serve HTTPS with.
+fn tls_acceptor(paths: &otterhound::TlsPaths) -> Result<tokio_rustls::TlsAcceptor, String> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .map_err(|err| format!("Failed to open {}: {}", path, err))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&paths.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Failed to read {}: {}", paths.cert_path, err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", paths.cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&paths.key_path)?)
+        .map_err(|err| format!("Failed to read {}: {}", paths.key_path, err))?
+        .ok_or_else(|| format!("No private key in {}", paths.key_path))?;
+
+    let mut config = tokio_rustls::rustls::ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|err| format!("Invalid TLS configuration: {}", err))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Connections accepted on `listener` that completed a TLS handshake. Failed connections and
+/// handshakes are logged and skipped rather than stopping the server.
+fn tls_incoming(
+    listener: tokio::net::TcpListener,
+    tls_acceptor: tokio_rustls::TlsAcceptor,
+) -> impl futures::Stream<
+    Item = Result<tokio_rustls::server::TlsStream<tokio::net::TcpStream>, std::io::Error>,
+> {
+    futures::stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await, listener))
+    })
+    .filter_map(|res| async move {
+        match res {
+            Ok((stream, _)) => Some(stream),
+            Err(err) => {
+                tracing::warn!("Failed to accept connection: {}", err);
+                None
+            }
+        }
+    })
+    .map(move |stream| {
+        // a client that never finishes its handshake shouldn't hold a slot forever
+        tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            tls_acceptor.accept(stream),
+        )
+    })
+    .buffer_unordered(64)
+    .filter_map(|res| async move {
+        match res {
+            Ok(Ok(stream)) => Some(Ok(stream)),
+            Ok(Err(err)) => {
+                tracing::debug!("TLS handshake failed: {}", err);
+                None
+            }
+            Err(_) => {
+                tracing::debug!("TLS handshake timed out");
+                None
+            }
+        }
+    })
+}
+
+/// Connections accepted on `listener`. Failed connections are logged and skipped rather than
+/// stopping the server.
+#[cfg(unix)]
+fn unix_incoming(
+    listener: tokio::net::UnixListener,
+) -> impl futures::Stream<Item = Result<tokio::net::UnixStream, std::io::Error>> {
+    futures::stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await, listener))
+    })
+    .filter_map(|res| async move {
+        match res {
+            Ok((stream, _)) => Some(Ok(stream)),
+            Err(err) => {
+                tracing::warn!("Failed to accept connection: {}", err);
+                None
+            }
+        }
+    })
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {}", err);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", err);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+
+    tracing::info!("Shutting down, no longer accepting connections");
+}
+
+fn main() {
+    let _telemetry = otterhound::init_logging();
+
+    // `--migrate` applies the embedded database migrations before starting. For a database built
+    // by hand, `--baseline <version>` first records the migrations up to that version as applied,
+    // then applies the rest, see `migrations/`.
+    let mut migrate = false;
+    let mut baseline = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--migrate" => migrate = true,
+            "--baseline" => match args.next().and_then(|version| version.parse().ok()) {
+                Some(version) => {
+                    baseline = Some(version);
+                    migrate = true;
+                }
+                None => {
+                    tracing::error!("--baseline needs a migration version, e.g. --baseline 11");
+                    std::process::exit(2);
+                }
+            },
+            _ => {
+                tracing::error!("Unknown argument: {}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let config = match otterhound::Config::load_server() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let bind_addr = config.bind_addr;
+    let port = config.port;
+    let unix_socket_path = config.unix_socket_path.clone();
+    let tls_acceptor = config.tls.as_ref().map(|paths| {
+        tls_acceptor(paths).unwrap_or_else(|err| {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let signing_secrets = config.signing_secrets.clone();
+    let webhook_path = config.webhook_path.clone();
+    let admin_tokens = config.admin_tokens.clone();
+    #[cfg(feature = "grpc")]
+    let grpc_port = config.grpc_port;
+    let max_body_bytes = config.max_body_bytes;
+    let processing_mode = config.processing_mode;
+    let processing_mode_overrides = config.processing_mode_overrides.clone();
+    let silence_warning = config.event_silence_warning;
+    let retry_interval = config.retry_poll_interval;
+    let expiry_interval = config.expiry_poll_interval;
+    let outbound_interval = config.outbound_poll_interval;
+    let (queue, queue_receiver) = if processing_mode == ProcessingMode::Queue {
+        let (sender, receiver) = tokio::sync::mpsc::channel(config.queue_capacity);
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    let shutdown_timeout = config.shutdown_timeout;
+    let max_concurrent_events = config.max_concurrent_events;
+    let queue_workers = config.queue_workers;
+
+    // processing shares the server runtime unless a thread count is given
+    let processing_runtime = config.processing_threads.map(|threads| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("otterhound-processing")
+            .enable_all()
+            .build()
+            .expect("Failed to initialize processing runtime")
+    });
+    let processing_handle = processing_runtime
+        .as_ref()
+        .map(|runtime| runtime.handle().clone());
+
+    tracing::info!(
+        "Starting otterhound {} ({}, built at {})",
+        otterhound::VERSION,
+        otterhound::GIT_SHA,
+        otterhound::BUILD_TIMESTAMP
+    );
+
+    let metrics = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            metrics_exporter_prometheus::Matcher::Full(
+                "otterhound_event_handler_duration_seconds".to_owned(),
+            ),
+            &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0],
+        )
+        .and_then(|builder| builder.install_recorder())
+        .expect("Failed to install metrics recorder");
+
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to initialize Tokio");
+
+    let result = runtime.block_on(async move {
+        let mut builder =
+            otterhound::OtterhoundBuilder::from_config(&config).run_migrations(migrate);
+        if let Some(version) = baseline {
+            builder = builder.baseline(version);
+        }
+        #[allow(unused_mut)]
+        let mut otterhound = builder
+            .build()
+            .await
+            .map_err(|err| format!("Failed to initialize: {}", err))?;
+
+        #[cfg(feature = "sentry-reporting")]
+        if let Some(dsn) = &config.sentry_dsn {
+            otterhound.set_error_reporter(
+                otterhound::reporting::SentryReporter::new(dsn)
+                    .map_err(|err| format!("Failed to initialize: {}", err))?,
+            );
+        }
+
+        #[cfg(feature = "nats-publishing")]
+        if let Some(url) = &config.nats_url {
+            otterhound.set_publisher(
+                otterhound::publishing::NatsPublisher::connect(
+                    url,
+                    config.nats_subject_prefix.clone(),
+                )
+                .await
+                .map_err(|err| format!("Failed to initialize: {}", err))?,
+            );
+        }
+
+        tracing::info!(
+            "Handling event types: {}; all others will be ignored",
+            otterhound.handled_event_types().join(", ")
+        );
+
+        let state = Arc::new(ServerState {
+            metrics,
+            signing_secrets,
+            signature_tolerance: config.signature_tolerance,
+            webhook_path,
+            max_body_bytes,
+            admin_tokens,
+            otterhound: Arc::new(otterhound),
+            processing_mode,
+            processing_mode_overrides,
+            processing_handle,
+            worker: otterhound::worker::Worker::new(
+                config.worker_concurrency,
+                config.worker_poll_interval,
+            ),
+            event_permits: tokio::sync::Semaphore::new(max_concurrent_events),
+            queue,
+            last_event_received: std::sync::atomic::AtomicU64::new(now_secs()),
+        });
+
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        // processing tasks waited for on shutdown
+        let mut processing_tasks = Vec::new();
+
+        if let Some(queue_receiver) = queue_receiver {
+            let workers = {
+                let state = state.clone();
+                futures::stream::unfold(
+                    (queue_receiver, shutdown.clone()),
+                    |(mut receiver, mut shutdown)| async move {
+                        if !*shutdown.borrow() {
+                            tokio::select! {
+                                evt = receiver.recv() => {
+                                    return evt.map(|evt| (evt, (receiver, shutdown)));
                                 }
-                            } else {
-                                println!("Unable to parse signature");
+                                _ = shutdown.changed() => {}
                             }
                         }
 
-                        Err("Signature validation failed".to_owned())
+                        // drain what was queued before shutting down
+                        receiver.close();
+                        receiver.recv().await.map(|evt| (evt, (receiver, shutdown)))
+                    },
+                )
+                .for_each_concurrent(queue_workers, move |evt| {
+                    let state = state.clone();
+                    async move {
+                        let _permit = state.acquire_event_permit().await;
+                        if let Err(err) = state.otterhound.handle_event(evt).await {
+                            tracing::error!("{}", err);
+                        }
+                    }
+                })
+            };
+
+            processing_tasks.push(state.spawn(workers));
+        }
+
+        {
+            let state_ref = state.clone();
+            processing_tasks
+                .push(state.spawn(async move {
+                    state_ref.worker.run(state_ref.otterhound.clone()).await
+                }));
+        }
+
+        if let Some(silence_warning) = silence_warning {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(silence_warning);
+                // the first tick completes immediately
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    let silent_for = state.record_silence();
+                    if silent_for >= silence_warning.as_secs() {
+                        tracing::warn!("No events received in the last {} seconds", silent_for);
+                    }
+                }
+            });
+        }
+
+        if retry_interval > std::time::Duration::from_secs(0) {
+            let state_ref = state.clone();
+            let mut shutdown = shutdown.clone();
+            processing_tasks.push(state.spawn(async move {
+                let mut interval = tokio::time::interval(retry_interval);
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+
+                    if let Err(err) = state_ref.otterhound.retry_due_events(100).await {
+                        tracing::error!("Failed to retry events: {}", err);
+                    }
+                }
+            }));
+        }
+
+        if expiry_interval > std::time::Duration::from_secs(0) {
+            let state_ref = state.clone();
+            let mut shutdown = shutdown.clone();
+            processing_tasks.push(state.spawn(async move {
+                let mut interval = tokio::time::interval(expiry_interval);
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+
+                    if let Err(err) = state_ref.otterhound.expire_lapsed_subscriptions().await {
+                        tracing::error!("Failed to expire subscriptions: {}", err);
+                    }
+                }
+            }));
+        }
+
+        if outbound_interval > std::time::Duration::from_secs(0) {
+            let state_ref = state.clone();
+            let mut shutdown = shutdown.clone();
+            processing_tasks.push(state.spawn(async move {
+                let mut interval = tokio::time::interval(outbound_interval);
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+
+                    if let Err(err) = state_ref.otterhound.deliver_outbound_webhooks(100).await {
+                        tracing::error!("Failed to send outbound webhooks: {}", err);
+                    }
+                }
+            }));
+        }
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_port) = grpc_port {
+            let grpc_addr = std::net::SocketAddr::from((bind_addr, grpc_port));
+            let service =
+                grpc::SubscriptionsServer::new(grpc::SubscriptionsService::new(state.clone()));
+            let mut shutdown = shutdown.clone();
+            tracing::info!("Serving gRPC on {}", grpc_addr);
+            processing_tasks.push(tokio::spawn(async move {
+                if let Err(err) = tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_shutdown(grpc_addr, async move {
+                        let _ = shutdown.changed().await;
                     })
+                    .await
+                {
+                    tracing::error!("Error running gRPC server: {}", err);
+                }
+            }));
+        }
+
+        let addr = std::net::SocketAddr::from((bind_addr, port));
+        match (unix_socket_path, tls_acceptor) {
+            #[cfg(unix)]
+            (Some(path), _) => {
+                // a socket left behind by a previous run would make binding fail
+                if let Err(err) = std::fs::remove_file(&path) {
+                    if err.kind() != std::io::ErrorKind::NotFound {
+                        return Err(format!("Failed to remove old socket {}: {}", path, err));
+                    }
+                }
+                let listener = tokio::net::UnixListener::bind(&path)
+                    .map_err(|err| format!("Failed to bind {}: {}", path, err))?;
+                tracing::info!("Listening on {}", path);
+
+                let res = serve(
+                    hyper::Server::builder(hyper::server::accept::from_stream(unix_incoming(
+                        listener,
+                    ))),
+                    state.clone(),
+                )
+                .await;
+
+                if let Err(err) = std::fs::remove_file(&path) {
+                    tracing::warn!("Failed to remove socket {}: {}", path, err);
+                }
+
+                res
             }
-        })
-        .and_then(|(timestamp, body)| {
-            let timestamp = timestamp
-                .parse()
-                .map_err(|err| format!("Failed to parse timestamp: {:?}", err))?;
-            let timestamp =
-                std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(timestamp);
-
-            let time_diff = match std::time::SystemTime::now().duration_since(timestamp) {
-                Ok(time_diff) => time_diff,
-                Err(err) => err.duration(),
-            };
+            (_, Some(tls_acceptor)) => {
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|err| format!("Failed to bind {}: {}", addr, err))?;
+                tracing::info!("Listening on {} with TLS", addr);
 
-            if time_diff > MAX_TIME_DIFF {
-                return Err("Timestamp is too far from current time".to_owned());
+                serve(
+                    hyper::Server::builder(hyper::server::accept::from_stream(tls_incoming(
+                        listener,
+                        tls_acceptor,
+                    ))),
+                    state.clone(),
+                )
+                .await
             }
+            (_, None) => {
+                let builder = hyper::Server::try_bind(&addr)
+                    .map_err(|err| format!("Failed to bind {}: {}", addr, err))?;
+                tracing::info!("Listening on {}", addr);
 
-            serde_json::from_slice(&body).map_err(|err| format!("Failed to parse body: {:?}", err))
-        })
-        .map(move |body| {
-            tokio::spawn(
-                state
-                    .otterhound
-                    .handle_event(body)
-                    .map_err(|err| eprintln!("{}", err)),
+                serve(builder, state.clone()).await
+            }
+        }
+        .map_err(|err| format!("Error running server: {:?}", err))?;
+
+        let _ = shutdown_sender.send(true);
+        state.worker.stop();
+
+        if tokio::time::timeout(
+            shutdown_timeout,
+            futures::future::join_all(processing_tasks),
+        )
+        .await
+        .is_err()
+        {
+            tracing::warn!(
+                "Event processing didn't finish within {:?}, exiting anyway",
+                shutdown_timeout
             );
+        }
 
-            hyper::Response::new(hyper::Body::empty())
-        })
-        .or_else(|err| {
-            eprintln!("Error in request handler: {}", err);
-            let mut res = hyper::Response::new("Internal Server Error".into());
-            *res.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        // closes the database pool once nothing else holds it
+        drop(state);
+        tracing::info!("Shut down");
 
-            Ok(res)
-        })
+        Ok::<_, String>(())
+    });
+
+    if let Err(err) = result {
+        panic!("Failure: {:?}", err);
+    }
 }
 
-fn main() {
-    let port: u16 = match std::env::var("PORT").ok() {
-        Some(port_str) => port_str.parse().expect("Failed to parse port"),
-        None => 6868,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_errors_are_bad_requests() {
+        assert_eq!(
+            status_for(&OtterhoundError::Signature("Missing signature".to_owned())),
+            hyper::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn replays_are_bad_requests() {
+        assert_eq!(
+            status_for(&OtterhoundError::Replay(std::time::Duration::from_secs(
+                600
+            ))),
+            hyper::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_bad_requests() {
+        assert_eq!(
+            status_for(&OtterhoundError::InvalidRequest(
+                "Failed to parse body".to_owned()
+            )),
+            hyper::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn unparseable_bodies_are_bad_requests() {
+        let err = otterhound::peek_event_meta(b"{\"id\": \"evt_1\"").unwrap_err();
+        assert_eq!(status_for(&err), hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn processing_parse_errors_are_acknowledged() {
+        assert_eq!(
+            status_for(&OtterhoundError::Parse(
+                "Expected object type invoice, but found charge".to_owned()
+            )),
+            hyper::StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn open_circuit_is_unavailable() {
+        assert_eq!(
+            status_for(&OtterhoundError::StripeUnavailable),
+            hyper::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn db_errors_are_retried() {
+        assert_eq!(
+            status_for(&OtterhoundError::Db("connection reset".to_owned())),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status_for(&OtterhoundError::DbRetryable(
+                otterhound::DbRetryReason::Deadlock,
+                "deadlock detected".to_owned()
+            )),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn stripe_api_errors_are_retried() {
+        assert_eq!(
+            status_for(&OtterhoundError::StripeApi("Received 502".to_owned())),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn timeouts_are_retried() {
+        assert_eq!(
+            status_for(&OtterhoundError::TimedOut(std::time::Duration::from_secs(
+                60
+            ))),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn panics_are_retried() {
+        assert_eq!(
+            status_for(&OtterhoundError::Panicked("oops".to_owned())),
+            hyper::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn not_found_is_acknowledged() {
+        assert_eq!(
+            status_for(&OtterhoundError::NotFound(
+                "Couldn't find the session".to_owned()
+            )),
+            hyper::StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn config_errors_are_acknowledged() {
+        assert_eq!(
+            status_for(&OtterhoundError::Config("Missing database URL".to_owned())),
+            hyper::StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn publish_errors_are_acknowledged() {
+        assert_eq!(
+            status_for(&OtterhoundError::Publish("no responders".to_owned())),
+            hyper::StatusCode::OK
+        );
+    }
+}
+
+/// Drives signed webhooks through `handle_request` against a test database, as the server handles
+/// them with `PROCESSING_MODE=sync`.
+#[cfg(all(test, feature = "integration-tests"))]
+mod server_tests {
+    use super::*;
+    use crate::support::{
+        self, count, fixture, insert_checkout_session, MockStripe, TestDatabase, CHECKOUT_SESSION,
     };
-    let signing_secret = std::env::var("SIGNING_SECRET").expect("Missing SIGNING_SECRET");
-
-    tokio::run(
-        otterhound::Otterhound::new()
-            .and_then(move |otterhound| {
-                let state = Arc::new(ServerState {
-                    signing_secret,
-                    otterhound,
-                });
-
-                hyper::Server::bind(&std::net::SocketAddr::from((
-                    std::net::Ipv6Addr::UNSPECIFIED,
-                    port,
-                )))
-                .serve(move || {
-                    let state = state.clone();
-                    hyper::service::service_fn(move |req| handle_request(req, state.clone()))
-                })
-                .map_err(|err| format!("Error running server: {:?}", err))
-            })
-            .map_err(|err| panic!("Failure: {:?}", err)),
-    );
+
+    const SECRET: &str = "whsec_server_test";
+    const WEBHOOK_PATH: &str = "/stripe/webhook";
+    const ADMIN_TOKEN: &str = "admin_server_test";
+
+    /// What the server answered a webhook with, and what became of its event.
+    struct Delivery {
+        status: hyper::StatusCode,
+        /// The `status` and `error` the event was logged with, if it was logged at all.
+        outcome: Option<(String, Option<String>)>,
+    }
+
+    struct TestServer {
+        state: Arc<ServerState>,
+        db: TestDatabase,
+        _stripe: MockStripe,
+    }
+
+    impl TestServer {
+        async fn start() -> Self {
+            let db = TestDatabase::new().await;
+            let stripe = MockStripe::start();
+            let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+
+            let state = Arc::new(ServerState {
+                metrics: metrics_exporter_prometheus::PrometheusBuilder::new()
+                    .build_recorder()
+                    .handle(),
+                signing_secrets: vec![SECRET.to_owned()],
+                signature_tolerance: otterhound::signature::DEFAULT_TOLERANCE,
+                webhook_path: WEBHOOK_PATH.to_owned(),
+                max_body_bytes: 256 * 1024,
+                admin_tokens: vec![otterhound::AdminToken {
+                    token: ADMIN_TOKEN.to_owned(),
+                    scope: otterhound::AdminScope::Replay,
+                }],
+                otterhound: Arc::new(otterhound),
+                processing_mode: ProcessingMode::Sync,
+                processing_mode_overrides: Default::default(),
+                processing_handle: None,
+                worker: otterhound::worker::Worker::new(1, std::time::Duration::from_secs(1)),
+                event_permits: tokio::sync::Semaphore::new(1),
+                queue: None,
+                last_event_received: std::sync::atomic::AtomicU64::new(now_secs()),
+            });
+
+            TestServer {
+                state,
+                db,
+                _stripe: stripe,
+            }
+        }
+
+        /// Posts `body` signed with the server's secret at the current time.
+        async fn send(&self, body: &str) -> Delivery {
+            self.send_signed(SECRET, body).await
+        }
+
+        async fn send_signed(&self, secret: &str, body: &str) -> Delivery {
+            let req = otterhound::signature::signed_request(WEBHOOK_PATH, secret.as_bytes(), body)
+                .unwrap();
+            let res = handle_request(req, self.state.clone()).await.unwrap();
+
+            let outcome = match otterhound::peek_event_meta(body.as_bytes()) {
+                Ok(meta) => self
+                    .db
+                    .connect()
+                    .await
+                    .query_opt(
+                        "SELECT status, error FROM event_log WHERE id=$1",
+                        &[&meta.id],
+                    )
+                    .await
+                    .unwrap()
+                    .map(|row| (row.get(0), row.get(1))),
+                Err(_) => None,
+            };
+
+            Delivery {
+                status: res.status(),
+                outcome,
+            }
+        }
+
+        /// Logs `body` as a received event without handling it.
+        async fn log(&self, body: &str) {
+            let meta = otterhound::peek_event_meta(body.as_bytes()).unwrap();
+            self.state
+                .otterhound
+                .log_event(&meta, body.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        /// Posts `body` to an admin endpoint, returning the status and JSON response.
+        async fn admin_post(
+            &self,
+            path: &str,
+            body: serde_json::Value,
+        ) -> (hyper::StatusCode, serde_json::Value) {
+            let req = hyper::Request::post(path)
+                .header(
+                    hyper::header::AUTHORIZATION,
+                    format!("Bearer {}", ADMIN_TOKEN),
+                )
+                .body(body.to_string().into())
+                .unwrap();
+            let res = handle_request(req, self.state.clone()).await.unwrap();
+            let status = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    /// An event whose object isn't the invoice its type promises, so handling it fails.
+    const MISMATCHED_EVENT: &str = r#"{"id": "evt_mismatched", "type": "invoice.payment_succeeded", "created": 1735689612, "data": {"object": {"object": "charge", "id": "ch_1"}}}"#;
+
+    #[tokio::test]
+    async fn handled_event_is_acknowledged() {
+        let server = TestServer::start().await;
+        let client = server.db.connect().await;
+        insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+        let delivery = server.send(&fixture("checkout_session_completed")).await;
+        assert_eq!(delivery.status, hyper::StatusCode::OK);
+        assert_eq!(delivery.outcome, Some(("handled".to_owned(), None)));
+        assert_eq!(count(&client, "user_subscriptions").await, 1);
+    }
+
+    #[tokio::test]
+    async fn wrongly_signed_event_is_rejected_unlogged() {
+        let server = TestServer::start().await;
+
+        let delivery = server
+            .send_signed("whsec_other", &fixture("checkout_session_completed"))
+            .await;
+        assert_eq!(delivery.status, hyper::StatusCode::BAD_REQUEST);
+        assert_eq!(delivery.outcome, None);
+    }
+
+    #[tokio::test]
+    async fn failed_event_is_recorded_for_retry() {
+        let server = TestServer::start().await;
+        let client = server.db.connect().await;
+        insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+        client
+            .batch_execute("ALTER TABLE stripe_customers RENAME TO stripe_customers_moved")
+            .await
+            .unwrap();
+
+        let delivery = server.send(&fixture("checkout_session_completed")).await;
+        assert_eq!(delivery.status, hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, error) = delivery.outcome.unwrap();
+        assert_eq!(status, "failed");
+        assert!(error.unwrap().contains("stripe_customers"));
+    }
+
+    #[tokio::test]
+    async fn batch_reports_each_outcome() {
+        let server = TestServer::start().await;
+        let client = server.db.connect().await;
+        insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+        let checkout = fixture("checkout_session_completed");
+        let checkout_id = otterhound::peek_event_meta(checkout.as_bytes())
+            .unwrap()
+            .id
+            .to_owned();
+        server.log(&checkout).await;
+        server.log(MISMATCHED_EVENT).await;
+
+        let (status, outcomes) = server
+            .admin_post(
+                "/admin/batch",
+                serde_json::json!({
+                    "event_ids": [checkout_id, "evt_mismatched", "evt_missing"],
+                }),
+            )
+            .await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        let outcomes = outcomes.as_array().unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(
+            outcomes[0],
+            serde_json::json!({ "event_id": checkout_id, "outcome": "handled" })
+        );
+        assert_eq!(outcomes[1]["event_id"], "evt_mismatched");
+        assert_eq!(outcomes[1]["outcome"], "failed");
+        assert!(outcomes[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Expected object type invoice"));
+        assert_eq!(outcomes[2]["event_id"], "evt_missing");
+        assert_eq!(outcomes[2]["outcome"], "not_found");
+        assert_eq!(count(&client, "user_subscriptions").await, 1);
+    }
+
+    #[tokio::test]
+    async fn batch_without_any_handled_event_fails() {
+        let server = TestServer::start().await;
+        server.log(MISMATCHED_EVENT).await;
+
+        let (status, outcomes) = server
+            .admin_post(
+                "/admin/batch",
+                serde_json::json!({ "event_ids": ["evt_mismatched", "evt_missing"] }),
+            )
+            .await;
+        assert_eq!(status, hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        let outcomes: Vec<&str> = outcomes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|outcome| outcome["outcome"].as_str().unwrap())
+            .collect();
+        assert_eq!(outcomes, ["failed", "not_found"]);
+    }
+
+    #[tokio::test]
+    async fn batch_needs_event_ids() {
+        let server = TestServer::start().await;
+
+        let (status, _) = server
+            .admin_post("/admin/batch", serde_json::json!({ "event_ids": [] }))
+            .await;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,58 @@
+//! The database schema, embedded from `migrations/`.
+
+use crate::{DbPool, OtterhoundError};
+
+refinery::embed_migrations!("migrations");
+
+/// Applies any migrations the database hasn't seen yet, recording them in
+/// `refinery_schema_history`.
+///
+/// Meant for fresh databases. One whose schema was applied by hand needs `baseline` first, or the
+/// earliest migrations will fail on tables that already exist.
+pub async fn run(db_pool: &DbPool) -> Result<(), OtterhoundError> {
+    let mut conn = db_pool.get().await?;
+
+    let report = migrations::runner()
+        .run_async(&mut *conn)
+        .await
+        .map_err(|err| OtterhoundError::Db(format!("Failed to migrate: {}", err)))?;
+
+    let applied = report.applied_migrations().len();
+    if applied > 0 {
+        tracing::info!("Applied {} migration(s)", applied);
+    }
+
+    Ok(())
+}
+
+/// Records the migrations up to and including `version` as applied without running them, for a
+/// database whose schema was built by hand, after which `run` applies only the later ones. E.g. a
+/// database with every table up to `V11__dead_letter_events.sql` is baselined at 11.
+pub async fn baseline(db_pool: &DbPool, version: u32) -> Result<(), OtterhoundError> {
+    let runner = migrations::runner();
+    let latest = runner
+        .get_migrations()
+        .iter()
+        .map(|migration| migration.version())
+        .max()
+        .unwrap_or_default();
+    if i64::from(version) > i64::from(latest) {
+        return Err(OtterhoundError::Config(format!(
+            "Can't baseline at version {}, the latest migration is V{}",
+            version, latest
+        )));
+    }
+
+    let mut conn = db_pool.get().await?;
+    runner
+        .set_target(refinery::Target::FakeVersion(
+            version as refinery::SchemaVersion,
+        ))
+        .run_async(&mut *conn)
+        .await
+        .map_err(|err| OtterhoundError::Db(format!("Failed to baseline: {}", err)))?;
+
+    tracing::info!("Recorded migrations up to V{} as applied", version);
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Webhooks sent to internal services when subscriptions change.
+//!
+//! Deliveries are queued in `outbound_deliveries` within the transaction making the change, one
+//! per active row of `webhook_subscribers`, and sent by `deliver_due`. Each is a JSON `POST`
+//! signed like Stripe's webhooks, with `Otterhound-Signature: t=<timestamp>,v1=<HMAC-SHA256 of
+//! "<timestamp>.<body>">` using the subscriber's secret, so receivers can verify it the same way.
+
+use tokio_postgres::Transaction;
+
+use crate::stripe::client::HttpClient;
+use crate::{query, signature, DbPool, OtterhoundError, RetryPolicy, CLAIM_LEASE};
+
+/// How long a subscriber has to respond before the delivery counts as failed.
+const DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A subscription change announced to subscribers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutboundEvent {
+    Created,
+    Renewed,
+    Cancelled,
+    /// A reminder that the trial ends soon, sent three days before by default.
+    TrialEnding,
+    /// A payment needs the customer to act, e.g. authenticate with 3D Secure, at
+    /// `payment_action_url`.
+    PaymentActionRequired,
+}
+
+impl OutboundEvent {
+    /// The `type` of the payload and the `Otterhound-Event` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboundEvent::Created => "subscription.created",
+            OutboundEvent::Renewed => "subscription.renewed",
+            OutboundEvent::Cancelled => "subscription.cancelled",
+            OutboundEvent::TrialEnding => "subscription.trial_ending",
+            OutboundEvent::PaymentActionRequired => "subscription.payment_action_required",
+        }
+    }
+}
+
+/// Queues `event` for the `user_subscriptions` rows of `stripe_subscription`, to be sent once
+/// `txn` commits. The payload describes the rows as they are at this point of the transaction.
+pub async fn enqueue(
+    txn: &Transaction<'_>,
+    event: OutboundEvent,
+    stripe_subscription: &str,
+) -> Result<u64, OtterhoundError> {
+    Ok(txn
+        .execute(
+            "INSERT INTO outbound_deliveries (subscriber_id, event_type, payload) SELECT webhook_subscribers.id, $2, json_build_object('type', $2::TEXT, 'user_id', user_subscriptions.user_id, 'tier', user_subscriptions.tier, 'stripe_subscription', user_subscriptions.stripe_subscription, 'end_timestamp', extract(epoch FROM user_subscriptions.end_timestamp)::BIGINT, 'cancelled_at', extract(epoch FROM user_subscriptions.cancelled_at)::BIGINT, 'trial_end', extract(epoch FROM user_subscriptions.trial_end)::BIGINT, 'payment_action_url', user_subscriptions.payment_action_url)::TEXT FROM webhook_subscribers, user_subscriptions WHERE webhook_subscribers.active AND user_subscriptions.stripe_subscription=$1",
+            &[&stripe_subscription, &event.as_str()],
+        )
+        .await?)
+}
+
+/// Sends up to `limit` deliveries that are due, returning how many were attempted. Failed
+/// deliveries are retried with the backoff of `retry_policy`, and marked `failed` once it's
+/// exhausted. Claimed deliveries are hidden from other instances for a lease period, so several
+/// servers can run this concurrently.
+pub async fn deliver_due(
+    db_pool: &DbPool,
+    http_client: &HttpClient,
+    retry_policy: &RetryPolicy,
+    limit: i64,
+) -> Result<usize, OtterhoundError> {
+    let rows = query(
+        db_pool,
+        "UPDATE outbound_deliveries SET next_attempt_at=now() + $2 * interval '1 second' FROM webhook_subscribers WHERE outbound_deliveries.id IN (SELECT id FROM outbound_deliveries WHERE status='pending' AND next_attempt_at <= now() ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED) AND webhook_subscribers.id=outbound_deliveries.subscriber_id RETURNING outbound_deliveries.id, outbound_deliveries.event_type, outbound_deliveries.payload, webhook_subscribers.url, webhook_subscribers.secret",
+        &[&limit, &CLAIM_LEASE.as_secs_f64()],
+    )
+    .await?;
+
+    for row in &rows {
+        let id: i64 = row.get(0);
+        let event_type: &str = row.get(1);
+        let payload: &str = row.get(2);
+        let url: &str = row.get(3);
+        let secret: &str = row.get(4);
+
+        match send(http_client, id, event_type, payload, url, secret).await {
+            Ok(()) => {
+                metrics::counter!("otterhound_outbound_deliveries_total", "outcome" => "delivered")
+                    .increment(1);
+                query(
+                    db_pool,
+                    "UPDATE outbound_deliveries SET status='delivered', attempts=attempts+1, last_error=NULL, next_attempt_at=NULL, delivered_at=now() WHERE id=$1",
+                    &[&id],
+                )
+                .await?;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to deliver {} to {}: {}", event_type, url, err);
+                metrics::counter!("otterhound_outbound_deliveries_total", "outcome" => "failed")
+                    .increment(1);
+                query(
+                    db_pool,
+                    "UPDATE outbound_deliveries SET attempts=attempts+1, last_error=$2, status=(CASE WHEN attempts+1 < $3 THEN 'pending' ELSE 'failed' END), next_attempt_at=(CASE WHEN attempts+1 < $3 THEN now() + LEAST($4 * power(2, attempts), $5) * interval '1 second' ELSE NULL END) WHERE id=$1",
+                    &[
+                        &id,
+                        &err,
+                        &(retry_policy.max_attempts as i32),
+                        &retry_policy.base_delay.as_secs_f64(),
+                        &retry_policy.max_delay.as_secs_f64(),
+                    ],
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(rows.len())
+}
+
+async fn send(
+    http_client: &HttpClient,
+    id: i64,
+    event_type: &str,
+    payload: &str,
+    url: &str,
+    secret: &str,
+) -> Result<(), String> {
+    let timestamp = crate::from_timestamp(std::time::SystemTime::now()).unwrap_or(0);
+    let req = hyper::Request::post(url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header("Otterhound-Delivery", id.to_string())
+        .header("Otterhound-Event", event_type)
+        .header(
+            "Otterhound-Signature",
+            signature::sign(secret.as_bytes(), timestamp, payload.as_bytes()),
+        )
+        .body(hyper::Body::from(payload.to_owned()))
+        .map_err(|err| format!("Failed to construct request: {}", err))?;
+
+    let res = tokio::time::timeout(DELIVERY_TIMEOUT, http_client.request(req))
+        .await
+        .map_err(|_| format!("Timed out after {:?}", DELIVERY_TIMEOUT))?
+        .map_err(|err| format!("Failed to send request: {}", err))?;
+
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Received {}", res.status()))
+    }
+}
@@ -0,0 +1,171 @@
+use async_trait::async_trait;
+
+use crate::OtterhoundError;
+
+/// How connections to Postgres use TLS, named like libpq's `sslmode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatabaseSslMode {
+    Disable,
+    /// Uses TLS if the server supports it, without verifying its certificate.
+    Prefer,
+    /// Requires TLS, without verifying the server's certificate.
+    Require,
+    /// Requires TLS with a certificate signed by a trusted CA, for any host name.
+    VerifyCa,
+    /// Requires TLS with a certificate signed by a trusted CA for the host connected to.
+    VerifyFull,
+}
+
+impl std::str::FromStr for DatabaseSslMode {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, String> {
+        match src {
+            "disable" => Ok(DatabaseSslMode::Disable),
+            "prefer" => Ok(DatabaseSslMode::Prefer),
+            "require" => Ok(DatabaseSslMode::Require),
+            "verify-ca" => Ok(DatabaseSslMode::VerifyCa),
+            "verify-full" => Ok(DatabaseSslMode::VerifyFull),
+            _ => Err(format!("Unknown SSL mode: {}", src)),
+        }
+    }
+}
+
+/// Builds the TLS connector for `ssl_mode`, or `None` to connect without TLS. `ca_cert_path` names
+/// a PEM file trusted in addition to the system's CAs.
+pub fn tls_connector(
+    ssl_mode: DatabaseSslMode,
+    ca_cert_path: Option<&str>,
+) -> Result<Option<postgres_native_tls::MakeTlsConnector>, OtterhoundError> {
+    let mut builder = native_tls::TlsConnector::builder();
+    match ssl_mode {
+        DatabaseSslMode::Disable => return Ok(None),
+        DatabaseSslMode::Prefer | DatabaseSslMode::Require => {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        DatabaseSslMode::VerifyCa => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        DatabaseSslMode::VerifyFull => {}
+    }
+
+    if let Some(path) = ca_cert_path {
+        let pem = std::fs::read(path).map_err(|err| {
+            OtterhoundError::Config(format!("Failed to read CA certificate {}: {}", path, err))
+        })?;
+        let cert = native_tls::Certificate::from_pem(&pem).map_err(|err| {
+            OtterhoundError::Config(format!("Failed to parse CA certificate {}: {}", path, err))
+        })?;
+        builder.add_root_certificate(cert);
+    }
+
+    let connector = builder.build().map_err(|err| {
+        OtterhoundError::Config(format!("Failed to initialize database TLS: {}", err))
+    })?;
+
+    Ok(Some(postgres_native_tls::MakeTlsConnector::new(connector)))
+}
+
+/// Logs connection failures, which bb8 otherwise drops while it keeps retrying.
+#[derive(Clone, Copy, Debug)]
+pub struct LogErrorSink;
+
+impl bb8::ErrorSink<tokio_postgres::Error> for LogErrorSink {
+    fn sink(&self, err: tokio_postgres::Error) {
+        tracing::warn!("Failed to connect to the database: {}", err);
+    }
+
+    fn boxed_clone(&self) -> Box<dyn bb8::ErrorSink<tokio_postgres::Error>> {
+        Box::new(*self)
+    }
+}
+
+/// Hands out `tokio_postgres` clients to the bb8 pool, driving each connection on its own task.
+pub struct PostgresConnectionManager {
+    config: tokio_postgres::Config,
+    tls: Option<postgres_native_tls::MakeTlsConnector>,
+}
+
+impl PostgresConnectionManager {
+    /// Connects with `tls` if given, in which case `config`'s SSL mode decides whether it is
+    /// required.
+    pub fn new(
+        config: tokio_postgres::Config,
+        tls: Option<postgres_native_tls::MakeTlsConnector>,
+    ) -> Self {
+        PostgresConnectionManager { config, tls }
+    }
+}
+
+/// Connects once, retrying with exponential backoff, so a database that is still starting up
+/// doesn't fail startup outright.
+pub async fn wait_for_database(
+    manager: &PostgresConnectionManager,
+    attempts: u32,
+    retry_delay: std::time::Duration,
+) -> Result<(), OtterhoundError> {
+    let mut delay = retry_delay;
+    let mut attempt = 1;
+
+    loop {
+        match bb8::ManageConnection::connect(manager).await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt < attempts => {
+                tracing::warn!(
+                    "Failed to connect to the database (attempt {} of {}), retrying in {:?}: {}",
+                    attempt,
+                    attempts,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                delay = std::cmp::min(delay * 2, std::time::Duration::from_secs(30));
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn spawn_connection<S, T>(connection: tokio_postgres::Connection<S, T>)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            tracing::error!("Database connection failed: {}", err);
+        }
+    });
+}
+
+#[async_trait]
+impl bb8::ManageConnection for PostgresConnectionManager {
+    type Connection = tokio_postgres::Client;
+    type Error = tokio_postgres::Error;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match &self.tls {
+            Some(tls) => {
+                let (client, connection) = self.config.connect(tls.clone()).await?;
+                spawn_connection(connection);
+                Ok(client)
+            }
+            None => {
+                let (client, connection) = self.config.connect(tokio_postgres::NoTls).await?;
+                spawn_connection(connection);
+                Ok(client)
+            }
+        }
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        conn.simple_query("").await.map(|_| ())
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.is_closed()
+    }
+}
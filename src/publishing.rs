@@ -0,0 +1,93 @@
+//! Publishing of committed subscription changes to a message bus.
+
+use async_trait::async_trait;
+use serde_derive::Serialize;
+
+use crate::OtterhoundError;
+
+/// The state of a subscription after a change.
+#[derive(Serialize, Clone, Debug)]
+pub struct SubscriptionDetails {
+    pub user_id: i32,
+    pub tier: i32,
+    pub stripe_subscription: String,
+    /// Epoch seconds.
+    pub end_timestamp: u64,
+    /// Epoch seconds, for subscriptions that started with a trial.
+    pub trial_end: Option<u64>,
+    /// Where the customer can complete a payment that needs their action.
+    pub payment_action_url: Option<String>,
+}
+
+/// A normalized billing event, serialized as JSON with its variant name under `type`.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum BillingEvent {
+    SubscriptionStarted(SubscriptionDetails),
+    SubscriptionRenewed(SubscriptionDetails),
+    SubscriptionEnded(SubscriptionDetails),
+    TrialEnding(SubscriptionDetails),
+    PaymentActionRequired(SubscriptionDetails),
+}
+
+impl BillingEvent {
+    /// Snake case name of the variant, e.g. for a subject or topic.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BillingEvent::SubscriptionStarted(_) => "subscription_started",
+            BillingEvent::SubscriptionRenewed(_) => "subscription_renewed",
+            BillingEvent::SubscriptionEnded(_) => "subscription_ended",
+            BillingEvent::TrialEnding(_) => "trial_ending",
+            BillingEvent::PaymentActionRequired(_) => "payment_action_required",
+        }
+    }
+}
+
+/// Receives every `BillingEvent` once the change it describes is committed. Set with
+/// `Otterhound::set_publisher`.
+///
+/// Events are published at most once: failures are logged, but the change stays committed and the
+/// event isn't retried. Consumers that can't miss changes should also use outbound webhooks.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish(&self, event: &BillingEvent) -> Result<(), OtterhoundError>;
+}
+
+/// Publishes to NATS, on `<prefix>.<name>`, e.g. `billing.subscription_started`. Only available
+/// with the `nats-publishing` feature.
+#[cfg(feature = "nats-publishing")]
+pub struct NatsPublisher {
+    client: async_nats::Client,
+    subject_prefix: String,
+}
+
+#[cfg(feature = "nats-publishing")]
+impl NatsPublisher {
+    pub async fn connect(url: &str, subject_prefix: String) -> Result<Self, OtterhoundError> {
+        let client = async_nats::connect(url).await.map_err(|err| {
+            OtterhoundError::Config(format!("Failed to connect to NATS: {}", err))
+        })?;
+
+        Ok(NatsPublisher {
+            client,
+            subject_prefix,
+        })
+    }
+}
+
+#[cfg(feature = "nats-publishing")]
+#[async_trait]
+impl Publisher for NatsPublisher {
+    async fn publish(&self, event: &BillingEvent) -> Result<(), OtterhoundError> {
+        let payload = serde_json::to_vec(event)
+            .map_err(|err| OtterhoundError::Publish(format!("{:?}", err)))?;
+
+        self.client
+            .publish(
+                format!("{}.{}", self.subject_prefix, event.name()),
+                payload.into(),
+            )
+            .await
+            .map_err(|err| OtterhoundError::Publish(err.to_string()))
+    }
+}
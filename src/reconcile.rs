@@ -0,0 +1,49 @@
+const USAGE: &str = "Usage: otterhound_reconcile [--fix]";
+
+#[tokio::main]
+async fn main() {
+    let mut fix = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_ref() {
+            "--fix" => fix = true,
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let result = async {
+        let otterhound = otterhound::Otterhound::new().await?;
+        otterhound.reconcile(fix).await
+    }
+    .await;
+
+    match result {
+        Ok(found) => {
+            let mut unfixed = 0;
+            for sub in &found {
+                for discrepancy in &sub.discrepancies {
+                    println!(
+                        "{}\t{}{}",
+                        sub.stripe_subscription,
+                        discrepancy,
+                        if sub.fixed { " (fixed)" } else { "" }
+                    );
+                }
+                if !sub.fixed {
+                    unfixed += 1;
+                }
+            }
+
+            println!("{} subscriptions differ from Stripe", found.len());
+            if unfixed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Reconciliation failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    count: u32,
+    window_start: Instant,
+    last_used: u64,
+}
+
+struct TrackerState {
+    entries: HashMap<String, Entry>,
+    // least recently seen first, for eviction
+    order: BTreeMap<u64, String>,
+    next_use: u64,
+}
+
+/// Counts deliveries per event ID to spot redelivery loops, remembering at most `capacity` IDs.
+pub struct RedeliveryTracker {
+    capacity: usize,
+    threshold: u32,
+    window: Duration,
+    state: Mutex<TrackerState>,
+}
+
+impl RedeliveryTracker {
+    pub fn new(capacity: usize, threshold: u32, window: Duration) -> Self {
+        RedeliveryTracker {
+            capacity,
+            threshold,
+            window,
+            state: Mutex::new(TrackerState {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_use: 0,
+            }),
+        }
+    }
+
+    /// Records a delivery, returning the count within the window if it exceeds the threshold.
+    pub fn record(&self, event_id: &str) -> Option<u32> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        let now = Instant::now();
+        let used = state.next_use;
+        state.next_use += 1;
+
+        let count = match state.entries.get_mut(event_id) {
+            Some(entry) => {
+                state.order.remove(&entry.last_used);
+                if now.duration_since(entry.window_start) > self.window {
+                    entry.count = 0;
+                    entry.window_start = now;
+                }
+                entry.count += 1;
+                entry.last_used = used;
+                entry.count
+            }
+            None => {
+                state.entries.insert(
+                    event_id.to_owned(),
+                    Entry {
+                        count: 1,
+                        window_start: now,
+                        last_used: used,
+                    },
+                );
+                1
+            }
+        };
+        state.order.insert(used, event_id.to_owned());
+
+        while state.entries.len() > self.capacity {
+            let oldest = match state.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(event_id) = state.order.remove(&oldest) {
+                state.entries.remove(&event_id);
+            }
+        }
+
+        if count > self.threshold {
+            Some(count)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_past_threshold() {
+        let tracker = RedeliveryTracker::new(10, 2, Duration::from_secs(60));
+        assert_eq!(tracker.record("evt_1"), None);
+        assert_eq!(tracker.record("evt_1"), None);
+        assert_eq!(tracker.record("evt_1"), Some(3));
+        assert_eq!(tracker.record("evt_1"), Some(4));
+        // counted per event
+        assert_eq!(tracker.record("evt_2"), None);
+    }
+
+    #[test]
+    fn count_restarts_after_window() {
+        let window = Duration::from_millis(50);
+        let tracker = RedeliveryTracker::new(10, 1, window);
+        tracker.record("evt_1");
+        assert_eq!(tracker.record("evt_1"), Some(2));
+
+        std::thread::sleep(window * 2);
+        assert_eq!(tracker.record("evt_1"), None);
+        assert_eq!(tracker.record("evt_1"), Some(2));
+    }
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let tracker = RedeliveryTracker::new(2, 1, Duration::from_secs(60));
+        tracker.record("evt_1");
+        tracker.record("evt_2");
+        // seeing evt_1 again makes evt_2 the one to go
+        assert_eq!(tracker.record("evt_1"), Some(2));
+        tracker.record("evt_3");
+
+        assert_eq!(tracker.state.lock().unwrap().entries.len(), 2);
+        assert_eq!(tracker.record("evt_2"), None);
+        assert_eq!(tracker.record("evt_3"), Some(2));
+    }
+}
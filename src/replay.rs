@@ -0,0 +1,185 @@
+use std::io::Read;
+
+const USAGE: &str = "Usage: otterhound_replay (--stdin | --failed | --event <id>...) [--dry-run]
+       otterhound_replay --list-dead
+       otterhound_replay --requeue <id>...";
+
+async fn init() -> otterhound::Otterhound {
+    match otterhound::Otterhound::new().await {
+        Ok(otterhound) => otterhound,
+        Err(err) => {
+            eprintln!("Failed to initialize: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn list_dead_letters() {
+    match init().await.list_dead_letter_events().await {
+        Ok(events) => {
+            for event in events {
+                println!(
+                    "{}\t{}\t{} attempts\t{}",
+                    event.id,
+                    event.event_type,
+                    event.attempts,
+                    event.error.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to list dead-lettered events: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn requeue_dead_letters(ids: &[String]) {
+    if ids.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let otterhound = init().await;
+    let mut failed = false;
+    for id in ids {
+        match otterhound.requeue_dead_letter_event(id).await {
+            Ok(true) => println!("{}: requeued", id),
+            Ok(false) => {
+                eprintln!("{}: not found in dead_letter_events", id);
+                failed = true;
+            }
+            Err(err) => {
+                eprintln!("{}: failed to requeue: {}", id, err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|arg| arg.as_str()) {
+        Some("--list-dead") if args.len() == 1 => return list_dead_letters().await,
+        Some("--requeue") => return requeue_dead_letters(&args[1..]).await,
+        _ => {}
+    }
+
+    let mut from_stdin = false;
+    let mut failed = false;
+    let mut event_ids = Vec::new();
+    let mut dry_run = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--stdin" => from_stdin = true,
+            "--failed" => failed = true,
+            "--event" => match args.next() {
+                Some(id) => event_ids.push(id),
+                None => {
+                    eprintln!("Missing event ID after --event");
+                    std::process::exit(2);
+                }
+            },
+            "--dry-run" => dry_run = true,
+            _ => {
+                eprintln!("Unknown argument: {}", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let selection = match (from_stdin, failed, event_ids.is_empty()) {
+        (true, false, true) => None,
+        (false, true, true) => Some(otterhound::EventSelection::Failed),
+        (false, false, false) => Some(otterhound::EventSelection::Ids(event_ids)),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let selection = match selection {
+        Some(selection) => selection,
+        None => {
+            let mut body = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut body)
+                .expect("Failed to read event from stdin");
+
+            let event: otterhound::EventItem =
+                serde_json::from_slice(&body).expect("Failed to parse event");
+
+            if dry_run {
+                println!(
+                    "Parsed {} event created at {}, not handling it (dry run)",
+                    event.type_, event.created
+                );
+                return;
+            }
+
+            let result = match otterhound::Otterhound::new().await {
+                Ok(otterhound) => otterhound.handle_event(event).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => println!("Outcome: handled"),
+                Err(err) => {
+                    eprintln!("Outcome: failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+
+            return;
+        }
+    };
+
+    let result = async {
+        let otterhound = otterhound::Otterhound::new().await?;
+        let events = otterhound.load_logged_events(selection).await?;
+        println!("Loaded {} events", events.len());
+
+        let mut failures = 0;
+        for event in events {
+            let id = event.id.clone();
+
+            if dry_run {
+                println!(
+                    "{}: {} event created at {}, not handling it (dry run)",
+                    id, event.type_, event.created
+                );
+                continue;
+            }
+
+            match otterhound.handle_logged_event(event).await {
+                Ok(()) => println!("{}: Outcome: handled", id),
+                Err(err) => {
+                    eprintln!("{}: Outcome: failed: {}", id, err);
+                    failures += 1;
+                }
+            }
+        }
+
+        Ok::<_, otterhound::OtterhoundError>(failures)
+    }
+    .await;
+
+    match result {
+        Ok(0) => {}
+        Ok(failures) => {
+            eprintln!("{} events failed", failures);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Replay failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! The queries against `subscription_checkout_sessions`, `user_subscriptions`,
+//! `stripe_customers`, `subscription_refunds` and `billing_disputes`.
+
+use std::time::SystemTime;
+
+use tokio_postgres::Transaction;
+
+use crate::stripe::types::{CancellationDetails, Charge, Dispute};
+use crate::OtterhoundError;
+
+/// A subscription to insert into `user_subscriptions`.
+pub struct NewSubscription<'a> {
+    pub tier_id: i32,
+    pub user_id: i32,
+    pub start_timestamp: SystemTime,
+    pub end_timestamp: SystemTime,
+    pub stripe_subscription: &'a str,
+    pub payment_method_missing: bool,
+    /// Set while the subscription is in a trial, which also sets `trial`.
+    pub trial_end: Option<SystemTime>,
+}
+
+/// Changes to a subscription from a `customer.subscription.updated` event, see
+/// `SubscriptionRepo::update_subscription`.
+pub struct SubscriptionUpdate<'a> {
+    pub stripe_subscription: &'a str,
+    pub tier_id: Option<i32>,
+    pub end_timestamp: SystemTime,
+    pub cancel_at_period_end: bool,
+    pub has_default_payment_method: bool,
+    pub status: &'a str,
+    pub trial_end: Option<SystemTime>,
+    /// A lower tier to move to once `pending_tier_from` has passed, instead of `tier_id` now.
+    pub pending_tier: Option<i32>,
+    pub pending_tier_from: Option<SystemTime>,
+}
+
+/// Reads and writes subscriptions within a transaction, usually the one opened by
+/// `db::with_event_transaction`. Methods that change rows return how many they changed.
+///
+/// Changes to `user_subscriptions` send `NOTIFY subscription_changed, '<user_id>'` for each user
+/// affected, which listeners receive once the transaction commits, so they can invalidate caches
+/// instead of polling the table.
+pub struct SubscriptionRepo {
+    insert_subscription_query: String,
+}
+
+impl SubscriptionRepo {
+    /// `insert_subscription_query` depends on the configured `ConflictTarget`.
+    pub fn new(insert_subscription_query: String) -> Self {
+        SubscriptionRepo {
+            insert_subscription_query,
+        }
+    }
+
+    /// Runs `statement`, which must return the `user_id` of each row it changes, and notifies
+    /// `subscription_changed` listeners of each of those users.
+    async fn execute_notifying(
+        &self,
+        txn: &Transaction<'_>,
+        statement: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<u64, OtterhoundError> {
+        let rows = txn.query(statement, params).await?;
+
+        let mut user_ids: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        for user_id in user_ids {
+            txn.execute(
+                "SELECT pg_notify('subscription_changed', $1)",
+                &[&user_id.to_string()],
+            )
+            .await?;
+        }
+
+        Ok(rows.len() as u64)
+    }
+
+    /// Records a Checkout session created for a user, so completing it grants them the tier.
+    pub async fn insert_checkout_session(
+        &self,
+        txn: &Transaction<'_>,
+        session_id: &str,
+        user_id: i32,
+        tier_id: i32,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "INSERT INTO subscription_checkout_sessions (stripe_id, user_id, tier_id) VALUES ($1, $2, $3)",
+                &[&session_id, &user_id, &tier_id],
+            )
+            .await?)
+    }
+
+    /// Marks a pending Checkout session completed, returning its user and tier, or `None` if it
+    /// doesn't exist or was already completed.
+    pub async fn complete_checkout_session(
+        &self,
+        txn: &Transaction<'_>,
+        session_id: &str,
+    ) -> Result<Option<(i32, i32)>, OtterhoundError> {
+        let row = txn
+            .query_opt(
+                "UPDATE subscription_checkout_sessions SET completed=TRUE WHERE stripe_id=$1 AND completed=FALSE RETURNING user_id, tier_id",
+                &[&session_id],
+            )
+            .await?;
+
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    /// Deletes a Checkout session that was never completed, returning whether it existed.
+    pub async fn delete_pending_checkout_session(
+        &self,
+        txn: &Transaction<'_>,
+        session_id: &str,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "DELETE FROM subscription_checkout_sessions WHERE stripe_id=$1 AND completed=FALSE",
+                &[&session_id],
+            )
+            .await?)
+    }
+
+    /// Remembers which user a Stripe customer belongs to, for events that only carry the
+    /// customer. A customer keeps the user it was first recorded for.
+    pub async fn record_customer(
+        &self,
+        txn: &Transaction<'_>,
+        user_id: i32,
+        stripe_customer_id: &str,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "INSERT INTO stripe_customers (stripe_customer_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&stripe_customer_id, &user_id],
+            )
+            .await?)
+    }
+
+    /// A Stripe customer recorded for a user, if any.
+    pub async fn customer_for_user(
+        &self,
+        txn: &Transaction<'_>,
+        user_id: i32,
+    ) -> Result<Option<String>, OtterhoundError> {
+        let row = txn
+            .query_opt(
+                "SELECT stripe_customer_id FROM stripe_customers WHERE user_id=$1 LIMIT 1",
+                &[&user_id],
+            )
+            .await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    pub async fn insert_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        sub: &NewSubscription<'_>,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            self.insert_subscription_query.as_str(),
+            &[
+                &sub.tier_id,
+                &sub.user_id,
+                &sub.start_timestamp,
+                &sub.end_timestamp,
+                &sub.stripe_subscription,
+                &sub.payment_method_missing,
+                &sub.trial_end.is_some(),
+                &sub.trial_end,
+            ],
+        )
+        .await
+    }
+
+    /// The tier is only changed if given, and the end timestamp only while the subscription is
+    /// active or trialing, and past the period revoked by a refund, if any. `trial` follows the
+    /// status, while `trial_end` is kept once the trial is over.
+    ///
+    /// With `pending_tier`, the tier is kept and the pending tier recorded instead. A pending tier
+    /// is applied by the first update once `pending_tier_from` has passed, and dropped by an update
+    /// to any other tier before then.
+    pub async fn update_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        update: &SubscriptionUpdate<'_>,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET tier=(CASE WHEN $8::INTEGER IS NOT NULL OR (pending_tier_from > now() AND ($2::INTEGER IS NULL OR $2 = pending_tier)) THEN tier ELSE COALESCE($2, pending_tier, tier) END), pending_tier=(CASE WHEN $8::INTEGER IS NOT NULL THEN $8 WHEN pending_tier_from > now() AND ($2::INTEGER IS NULL OR $2 = pending_tier) THEN pending_tier END), pending_tier_from=(CASE WHEN $8::INTEGER IS NOT NULL THEN $9 WHEN pending_tier_from > now() AND ($2::INTEGER IS NULL OR $2 = pending_tier) THEN pending_tier_from END), end_timestamp=(CASE WHEN $6 IN ('active', 'trialing') AND (revoked_until IS NULL OR $3 > revoked_until) THEN $3 ELSE end_timestamp END), expired_at=(CASE WHEN $6 IN ('active', 'trialing') AND (revoked_until IS NULL OR $3 > revoked_until) THEN NULL ELSE expired_at END), revoked_at=(CASE WHEN $6 IN ('active', 'trialing') AND $3 > revoked_until THEN NULL ELSE revoked_at END), revoked_until=(CASE WHEN $6 IN ('active', 'trialing') AND $3 > revoked_until THEN NULL ELSE revoked_until END), cancel_at_period_end=$4, payment_method_missing=(payment_method_missing AND NOT $5), status=$6, past_due_since=(CASE WHEN $6 = 'active' THEN NULL ELSE past_due_since END), trial=($6 = 'trialing'), trial_end=COALESCE($7, trial_end) WHERE stripe_subscription=$1 RETURNING user_id",
+            &[
+                &update.stripe_subscription,
+                &update.tier_id,
+                &update.end_timestamp,
+                &update.cancel_at_period_end,
+                &update.has_default_payment_method,
+                &update.status,
+                &update.trial_end,
+                &update.pending_tier,
+                &update.pending_tier_from,
+            ],
+        )
+        .await
+    }
+
+    /// Moves the end timestamp forward, never back, and marks the subscription active and
+    /// unexpired again, with no payment action pending. A period revoked by a refund is only
+    /// restored by paying for a later one.
+    pub async fn extend_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        end_timestamp: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET end_timestamp=(CASE WHEN revoked_until IS NULL OR $2 > revoked_until THEN GREATEST(end_timestamp, $2) ELSE end_timestamp END), status='active', past_due_since=NULL, payment_action_required_at=NULL, payment_action_url=NULL, expired_at=(CASE WHEN revoked_until IS NULL OR $2 > revoked_until THEN NULL ELSE expired_at END), revoked_at=(CASE WHEN $2 > revoked_until THEN NULL ELSE revoked_at END), revoked_until=(CASE WHEN $2 > revoked_until THEN NULL ELSE revoked_until END) WHERE stripe_subscription=$1 RETURNING user_id",
+            &[&stripe_subscription, &end_timestamp],
+        )
+        .await
+    }
+
+    /// Starts a grace period ending at `grace_period_end`, unless one was already started. Revoked
+    /// subscriptions get no grace period.
+    pub async fn mark_past_due(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        since: SystemTime,
+        grace_period_end: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET status='past_due', past_due_since=$2, end_timestamp=(CASE WHEN revoked_at IS NULL THEN GREATEST(end_timestamp, $3) ELSE end_timestamp END) WHERE stripe_subscription=$1 AND past_due_since IS NULL RETURNING user_id",
+            &[&stripe_subscription, &since, &grace_period_end],
+        )
+        .await
+    }
+
+    pub async fn clear_payment_method_missing(
+        &self,
+        txn: &Transaction<'_>,
+        user_id: i32,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET payment_method_missing=FALSE WHERE user_id=$1 AND payment_method_missing RETURNING user_id",
+            &[&user_id],
+        )
+        .await
+    }
+
+    pub async fn record_upcoming_invoice(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        amount: i64,
+        currency: &str,
+        due: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET upcoming_invoice_amount=$2, upcoming_invoice_currency=$3, upcoming_invoice_date=$4 WHERE stripe_subscription=$1 RETURNING user_id",
+            &[&stripe_subscription, &amount, &currency, &due],
+        )
+        .await
+    }
+
+    /// Sets `cancelled_at` unless the subscription was already cancelled.
+    pub async fn cancel_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        cancelled_at: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET cancelled_at=$2 WHERE stripe_subscription=$1 AND cancelled_at IS NULL RETURNING user_id",
+            &[&stripe_subscription, &cancelled_at],
+        )
+        .await
+    }
+
+    /// Ends access to a subscription at `revoked_at` after its payment was refunded in full.
+    /// Updates for the refunded period don't restore access, only paying for a later one does.
+    /// Subscriptions already revoked are left alone.
+    pub async fn revoke_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        revoked_at: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET revoked_at=$2, revoked_until=end_timestamp, end_timestamp=LEAST(end_timestamp, $2) WHERE stripe_subscription=$1 AND revoked_at IS NULL RETURNING user_id",
+            &[&stripe_subscription, &revoked_at],
+        )
+        .await
+    }
+
+    /// Stores the refunded amount of a charge, replacing the amount stored for it before, since
+    /// Stripe reports the total refunded so far.
+    pub async fn record_refund(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        charge: &Charge,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "INSERT INTO subscription_refunds (stripe_charge, stripe_subscription, amount, amount_refunded, currency) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (stripe_charge) DO UPDATE SET amount_refunded=excluded.amount_refunded, updated_at=now()",
+                &[
+                    &charge.id,
+                    &stripe_subscription,
+                    &charge.amount,
+                    &charge.amount_refunded,
+                    &charge.currency,
+                ],
+            )
+            .await?)
+    }
+
+    /// Stores a dispute of a payment for the subscription, or updates its status. `closed_at` is
+    /// only set once.
+    pub async fn record_dispute(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        dispute: &Dispute,
+        closed_at: Option<SystemTime>,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "INSERT INTO billing_disputes (stripe_dispute, stripe_charge, stripe_subscription, amount, currency, reason, status, closed_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (stripe_dispute) DO UPDATE SET status=excluded.status, closed_at=COALESCE(billing_disputes.closed_at, excluded.closed_at)",
+                &[
+                    &dispute.id,
+                    &dispute.charge,
+                    &stripe_subscription,
+                    &dispute.amount,
+                    &dispute.currency,
+                    &dispute.reason,
+                    &dispute.status,
+                    &closed_at,
+                ],
+            )
+            .await?)
+    }
+
+    /// Suspends access to a subscription while a dispute is open, unless it already is.
+    pub async fn suspend_subscription(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        suspended_at: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET suspended_at=$2 WHERE stripe_subscription=$1 AND suspended_at IS NULL RETURNING user_id",
+            &[&stripe_subscription, &suspended_at],
+        )
+        .await
+    }
+
+    /// Lifts a suspension once no dispute of the subscription's payments is open any more.
+    pub async fn lift_suspension(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET suspended_at=NULL WHERE stripe_subscription=$1 AND suspended_at IS NOT NULL AND NOT EXISTS (SELECT 1 FROM billing_disputes WHERE billing_disputes.stripe_subscription=$1 AND closed_at IS NULL) RETURNING user_id",
+            &[&stripe_subscription],
+        )
+        .await
+    }
+
+    /// Records that the customer must act, e.g. authenticate with 3D Secure, before a payment can
+    /// go through, until a payment succeeds.
+    pub async fn record_payment_action(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        required_at: SystemTime,
+        url: Option<&str>,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET payment_action_required_at=$2, payment_action_url=$3 WHERE stripe_subscription=$1 RETURNING user_id",
+            &[&stripe_subscription, &required_at, &url],
+        )
+        .await
+    }
+
+    /// Stores the end of a subscription's trial, which Stripe may have moved since it started.
+    pub async fn record_trial_end(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        trial_end: SystemTime,
+    ) -> Result<u64, OtterhoundError> {
+        self.execute_notifying(
+            txn,
+            "UPDATE user_subscriptions SET trial_end=$2 WHERE stripe_subscription=$1 RETURNING user_id",
+            &[&stripe_subscription, &trial_end],
+        )
+        .await
+    }
+
+    /// Stores why a subscription was cancelled in `subscription_cancellations`, once.
+    pub async fn record_cancellation(
+        &self,
+        txn: &Transaction<'_>,
+        stripe_subscription: &str,
+        details: &CancellationDetails,
+    ) -> Result<u64, OtterhoundError> {
+        Ok(txn
+            .execute(
+                "INSERT INTO subscription_cancellations (stripe_subscription, user_id, reason, feedback, comment) SELECT stripe_subscription, user_id, $2, $3, $4 FROM user_subscriptions WHERE stripe_subscription=$1 ON CONFLICT DO NOTHING",
+                &[
+                    &stripe_subscription,
+                    &details.reason,
+                    &details.feedback,
+                    &details.comment,
+                ],
+            )
+            .await?)
+    }
+}
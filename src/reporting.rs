@@ -0,0 +1,64 @@
+use crate::OtterhoundError;
+
+/// How handling an event failed.
+pub enum Failure<'a> {
+    Error(&'a OtterhoundError),
+    /// A handler panicked, with the panic message if it had one.
+    Panic(&'a str),
+}
+
+/// An event that failed, as passed to an `ErrorReporter`.
+pub struct FailedEvent<'a> {
+    pub event_type: &'a str,
+    pub event_id: &'a str,
+    pub failure: Failure<'a>,
+}
+
+/// Receives every failure of `Otterhound::handle_event`, e.g. to forward it to an error tracker.
+/// Set with `Otterhound::set_error_reporter`. Failures are logged either way.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, failed: &FailedEvent<'_>);
+}
+
+/// Reports to Sentry. Only available with the `sentry-reporting` feature.
+#[cfg(feature = "sentry-reporting")]
+pub struct SentryReporter {
+    _guard: sentry::ClientInitGuard,
+}
+
+#[cfg(feature = "sentry-reporting")]
+impl SentryReporter {
+    pub fn new(dsn: &str) -> Result<Self, OtterhoundError> {
+        let dsn = dsn.parse().map_err(|err| {
+            OtterhoundError::Config(format!("Failed to parse Sentry DSN: {}", err))
+        })?;
+
+        Ok(SentryReporter {
+            _guard: sentry::init(sentry::ClientOptions {
+                dsn: Some(dsn),
+                release: Some(crate::VERSION.into()),
+                ..Default::default()
+            }),
+        })
+    }
+}
+
+#[cfg(feature = "sentry-reporting")]
+impl ErrorReporter for SentryReporter {
+    fn report(&self, failed: &FailedEvent<'_>) {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("event_type", failed.event_type);
+                scope.set_tag("event_id", failed.event_id);
+            },
+            || match failed.failure {
+                Failure::Error(err) => {
+                    sentry::capture_error(err);
+                }
+                Failure::Panic(message) => {
+                    sentry::capture_message(message, sentry::Level::Fatal);
+                }
+            },
+        );
+    }
+}
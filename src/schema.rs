@@ -0,0 +1,168 @@
+//! A startup check that the database has the tables and columns the queries rely on.
+
+use crate::{query, DbPool, OtterhoundError};
+
+const TIMESTAMPTZ: &str = "timestamp with time zone";
+
+/// Columns the queries use, as `(table, column, data_type)` with the type as reported by
+/// `information_schema`. Parameters are bound with fixed Rust types, so the types must match
+/// exactly.
+const EXPECTED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("subscription_checkout_sessions", "stripe_id", "text"),
+    ("subscription_checkout_sessions", "user_id", "integer"),
+    ("subscription_checkout_sessions", "tier_id", "integer"),
+    ("subscription_checkout_sessions", "completed", "boolean"),
+    ("user_subscriptions", "tier", "integer"),
+    ("user_subscriptions", "user_id", "integer"),
+    ("user_subscriptions", "start_timestamp", TIMESTAMPTZ),
+    ("user_subscriptions", "end_timestamp", TIMESTAMPTZ),
+    ("user_subscriptions", "stripe_subscription", "text"),
+    ("user_subscriptions", "upcoming_invoice_amount", "bigint"),
+    ("user_subscriptions", "upcoming_invoice_currency", "text"),
+    ("user_subscriptions", "upcoming_invoice_date", TIMESTAMPTZ),
+    ("user_subscriptions", "payment_method_missing", "boolean"),
+    ("user_subscriptions", "cancelled_at", TIMESTAMPTZ),
+    ("user_subscriptions", "cancel_at_period_end", "boolean"),
+    ("user_subscriptions", "status", "text"),
+    ("user_subscriptions", "past_due_since", TIMESTAMPTZ),
+    ("user_subscriptions", "expired_at", TIMESTAMPTZ),
+    ("user_subscriptions", "revoked_at", TIMESTAMPTZ),
+    ("user_subscriptions", "revoked_until", TIMESTAMPTZ),
+    ("user_subscriptions", "suspended_at", TIMESTAMPTZ),
+    ("user_subscriptions", "trial", "boolean"),
+    ("user_subscriptions", "trial_end", TIMESTAMPTZ),
+    (
+        "user_subscriptions",
+        "payment_action_required_at",
+        TIMESTAMPTZ,
+    ),
+    ("user_subscriptions", "payment_action_url", "text"),
+    ("user_subscriptions", "pending_tier", "integer"),
+    ("user_subscriptions", "pending_tier_from", TIMESTAMPTZ),
+    ("stripe_events", "id", "text"),
+    ("event_log", "id", "text"),
+    ("event_log", "event_type", "text"),
+    ("event_log", "created", TIMESTAMPTZ),
+    ("event_log", "payload", "text"),
+    ("event_log", "status", "text"),
+    ("event_log", "error", "text"),
+    ("event_log", "updated_at", TIMESTAMPTZ),
+    ("event_log", "attempts", "integer"),
+    ("event_log", "next_attempt_at", TIMESTAMPTZ),
+    ("dead_letter_events", "id", "text"),
+    ("dead_letter_events", "event_type", "text"),
+    ("dead_letter_events", "created", TIMESTAMPTZ),
+    ("dead_letter_events", "payload", "text"),
+    ("dead_letter_events", "error", "text"),
+    ("dead_letter_events", "attempts", "integer"),
+    ("dead_letter_events", "failed_at", TIMESTAMPTZ),
+    ("stripe_customers", "stripe_customer_id", "text"),
+    ("stripe_customers", "user_id", "integer"),
+    ("webhook_subscribers", "id", "integer"),
+    ("webhook_subscribers", "url", "text"),
+    ("webhook_subscribers", "secret", "text"),
+    ("webhook_subscribers", "active", "boolean"),
+    ("outbound_deliveries", "id", "bigint"),
+    ("outbound_deliveries", "subscriber_id", "integer"),
+    ("outbound_deliveries", "event_type", "text"),
+    ("outbound_deliveries", "payload", "text"),
+    ("outbound_deliveries", "status", "text"),
+    ("outbound_deliveries", "attempts", "integer"),
+    ("outbound_deliveries", "next_attempt_at", TIMESTAMPTZ),
+    ("outbound_deliveries", "last_error", "text"),
+    ("outbound_deliveries", "delivered_at", TIMESTAMPTZ),
+    ("subscription_refunds", "stripe_charge", "text"),
+    ("subscription_refunds", "stripe_subscription", "text"),
+    ("subscription_refunds", "amount", "bigint"),
+    ("subscription_refunds", "amount_refunded", "bigint"),
+    ("subscription_refunds", "currency", "text"),
+    ("subscription_refunds", "updated_at", TIMESTAMPTZ),
+    ("audit_log", "id", "bigint"),
+    ("audit_log", "user_id", "integer"),
+    ("audit_log", "stripe_subscription", "text"),
+    ("audit_log", "action", "text"),
+    ("audit_log", "details", "text"),
+    ("audit_log", "event_id", "text"),
+    ("audit_log", "created_at", TIMESTAMPTZ),
+    ("billing_disputes", "stripe_dispute", "text"),
+    ("billing_disputes", "stripe_charge", "text"),
+    ("billing_disputes", "stripe_subscription", "text"),
+    ("billing_disputes", "amount", "bigint"),
+    ("billing_disputes", "currency", "text"),
+    ("billing_disputes", "reason", "text"),
+    ("billing_disputes", "status", "text"),
+    ("billing_disputes", "created_at", TIMESTAMPTZ),
+    ("billing_disputes", "closed_at", TIMESTAMPTZ),
+];
+
+/// Only used with `STORE_CANCELLATION_REASONS`.
+const CANCELLATION_COLUMNS: &[(&str, &str, &str)] = &[
+    ("subscription_cancellations", "stripe_subscription", "text"),
+    ("subscription_cancellations", "user_id", "integer"),
+    ("subscription_cancellations", "reason", "text"),
+    ("subscription_cancellations", "feedback", "text"),
+    ("subscription_cancellations", "comment", "text"),
+];
+
+/// Fails with every missing or mistyped column, so a broken schema shows up at startup rather
+/// than in the first event that touches it.
+pub async fn check(
+    db_pool: &DbPool,
+    store_cancellation_reasons: bool,
+) -> Result<(), OtterhoundError> {
+    let mut expected = EXPECTED_COLUMNS.to_vec();
+    if store_cancellation_reasons {
+        expected.extend_from_slice(CANCELLATION_COLUMNS);
+    }
+
+    let mut tables: Vec<&str> = expected.iter().map(|(table, _, _)| *table).collect();
+    tables.dedup();
+
+    let rows = query(
+        db_pool,
+        "SELECT table_name::TEXT, column_name::TEXT, data_type::TEXT FROM information_schema.columns WHERE table_schema = ANY(current_schemas(false)) AND table_name = ANY($1)",
+        &[&tables],
+    )
+    .await?;
+    let existing: std::collections::HashMap<(String, String), String> = rows
+        .iter()
+        .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+        .collect();
+
+    let mut problems = Vec::new();
+    for table in &tables {
+        if !existing
+            .keys()
+            .any(|(existing_table, _)| existing_table == table)
+        {
+            problems.push(format!("table {} is missing", table));
+        }
+    }
+    for (table, column, data_type) in expected {
+        let existing_table = existing
+            .keys()
+            .any(|(existing_table, _)| existing_table == table);
+        match existing.get(&(table.to_owned(), column.to_owned())) {
+            Some(existing_type) if existing_type != data_type => problems.push(format!(
+                "{}.{} is {}, expected {}",
+                table, column, existing_type, data_type
+            )),
+            Some(_) => {}
+            None if existing_table => {
+                problems.push(format!("column {}.{} is missing", table, column))
+            }
+            // already reported with the table
+            None => {}
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(OtterhoundError::Config(format!(
+            "Database schema doesn't match, {} problem(s) (run with --migrate to create it):\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        )));
+    }
+
+    Ok(())
+}
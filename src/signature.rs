@@ -0,0 +1,420 @@
+//! Verification of the `Stripe-Signature` header sent with webhooks.
+
+use hmac::Mac;
+use std::time::Duration;
+
+use crate::OtterhoundError;
+
+/// A scheme for signatures in the `Stripe-Signature` header. Adding one to `SIGNATURE_SCHEMES`
+/// is all it takes to accept its signatures.
+pub(crate) trait SignatureScheme: Sync {
+    /// Key identifying this scheme's signatures in the header, e.g. `v1`.
+    fn key(&self) -> &'static str;
+
+    /// Checks a signature over the signed payload, in constant time.
+    fn verify(&self, secret: &[u8], payload: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Stripe's current `v1` scheme, HMAC-SHA256.
+struct HmacSha256Scheme;
+
+impl SignatureScheme for HmacSha256Scheme {
+    fn key(&self) -> &'static str {
+        "v1"
+    }
+
+    fn verify(&self, secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).unwrap();
+        mac.update(payload);
+        // compares in constant time
+        mac.verify_slice(signature).is_ok()
+    }
+}
+
+/// Schemes whose signatures are accepted. Signatures for any other key, such as the `v0` test
+/// scheme, are ignored.
+static SIGNATURE_SCHEMES: &[&dyn SignatureScheme] = &[&HmacSha256Scheme];
+
+/// Stripe's recommended tolerance for the signed timestamp.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(60 * 5);
+
+/// Checks that `header` carries a valid signature of `body` by any of `secrets`, signed no more
+/// than `tolerance` away from now, and returns the signed timestamp in epoch seconds. A valid
+/// signature outside the tolerance is a `Replay` error rather than a `Signature` one.
+pub fn verify<S: AsRef<[u8]>>(
+    header: &str,
+    body: &[u8],
+    secrets: &[S],
+    tolerance: Duration,
+) -> Result<u64, OtterhoundError> {
+    let timestamp = verify_signature(header, body, secrets)?;
+
+    let offset = timestamp_offset(timestamp);
+    if offset > tolerance {
+        // the signature already validated, so this is a replay or a badly skewed clock
+        tracing::warn!(
+            "Possible replay: valid signature but timestamp {}s off",
+            offset.as_secs()
+        );
+        return Err(OtterhoundError::Replay(offset));
+    }
+
+    Ok(timestamp)
+}
+
+/// How far a signed timestamp is from now, in either direction. Timestamps too large to
+/// represent are as far off as can be.
+pub fn timestamp_offset(timestamp: u64) -> Duration {
+    let timestamp =
+        match std::time::SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(timestamp)) {
+            Some(timestamp) => timestamp,
+            None => return Duration::MAX,
+        };
+
+    match std::time::SystemTime::now().duration_since(timestamp) {
+        Ok(offset) => offset,
+        Err(err) => err.duration(),
+    }
+}
+
+/// Produces a header value in the same format, signing `body` with `secret` at `timestamp`.
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).unwrap();
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+
+    format!(
+        "t={},v1={}",
+        timestamp,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Signs `body` with `secret` as Stripe would when sending it now.
+#[cfg(any(test, feature = "integration-tests"))]
+pub fn sign_now(secret: &[u8], body: &[u8]) -> String {
+    let now = crate::from_timestamp(std::time::SystemTime::now()).unwrap_or(0);
+
+    sign(secret, now, body)
+}
+
+/// Builds the webhook request Stripe would post to `path` with `body`, signed with `secret` at
+/// the current time, for driving a server in tests. Fails if `path` isn't a valid URI.
+#[cfg(any(test, feature = "integration-tests"))]
+pub fn signed_request(
+    path: &str,
+    secret: &[u8],
+    body: impl Into<Vec<u8>>,
+) -> Result<hyper::Request<hyper::Body>, hyper::http::Error> {
+    let body = body.into();
+
+    hyper::Request::post(path)
+        .header("Stripe-Signature", sign_now(secret, &body))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+}
+
+/// Like `verify`, but leaves checking the timestamp to the caller.
+///
+/// Whitespace around pairs is ignored. A header with more than one timestamp, a timestamp that
+/// isn't a number, or a pair without `=` is rejected.
+pub fn verify_signature<S: AsRef<[u8]>>(
+    header: &str,
+    body: &[u8],
+    secrets: &[S],
+) -> Result<u64, OtterhoundError> {
+    verify_with_schemes(header, body, secrets, SIGNATURE_SCHEMES)
+}
+
+/// `verify_signature` accepting signatures of any of `schemes`.
+pub(crate) fn verify_with_schemes<S: AsRef<[u8]>>(
+    header: &str,
+    body: &[u8],
+    secrets: &[S],
+    schemes: &[&dyn SignatureScheme],
+) -> Result<u64, OtterhoundError> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+
+    for pair in header.split(',') {
+        let pair = pair.trim();
+        if pair.is_empty() {
+            continue;
+        }
+
+        let (key, value) = match pair.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                return Err(OtterhoundError::Signature(format!(
+                    "Malformed signature header element: {:?}",
+                    pair
+                )))
+            }
+        };
+
+        if key == "t" {
+            if timestamp.is_some() {
+                return Err(OtterhoundError::Signature(
+                    "Duplicate timestamp in signature header".to_owned(),
+                ));
+            }
+            let parsed: u64 = value.parse().map_err(|err| {
+                OtterhoundError::Signature(format!("Failed to parse timestamp: {:?}", err))
+            })?;
+            timestamp = Some((value, parsed));
+        } else if let Some(scheme) = schemes.iter().find(|scheme| scheme.key() == key) {
+            match hex::decode(value) {
+                Ok(sig) => signatures.push((*scheme, sig)),
+                Err(_) => tracing::debug!("Unable to parse signature"),
+            }
+        }
+    }
+
+    let (timestamp_str, timestamp) =
+        timestamp.ok_or_else(|| OtterhoundError::Signature("Missing timestamp".to_owned()))?;
+    if signatures.is_empty() {
+        return Err(OtterhoundError::Signature("Missing signature".to_owned()));
+    }
+
+    let signed_payload = {
+        let mut value = timestamp_str.as_bytes().to_vec();
+        value.push(b'.');
+        value.extend_from_slice(body);
+        value
+    };
+
+    let verified = signatures.iter().any(|(scheme, sig)| {
+        secrets
+            .iter()
+            .any(|secret| scheme.verify(secret.as_ref(), &signed_payload, sig))
+    });
+    if verified {
+        Ok(timestamp)
+    } else {
+        Err(OtterhoundError::Signature(
+            "Signature validation failed".to_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Signs by prefixing the payload with the secret, so tests can tell which scheme verified.
+    struct StubScheme;
+
+    impl SignatureScheme for StubScheme {
+        fn key(&self) -> &'static str {
+            "v9"
+        }
+
+        fn verify(&self, secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+            signature == [secret, payload].concat().as_slice()
+        }
+    }
+
+    fn stub_header(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+        let payload = [format!("{}.", timestamp).as_bytes(), body].concat();
+        format!(
+            "t={},v9={}",
+            timestamp,
+            hex::encode([secret, &payload].concat())
+        )
+    }
+
+    const SECRET: &[u8] = b"whsec_test";
+    const BODY: &[u8] = br#"{"id":"evt_1"}"#;
+
+    /// A correctly signed header for `BODY` at the current time.
+    fn valid_header() -> String {
+        signed_request("/stripe/webhook", SECRET, BODY)
+            .unwrap()
+            .headers()["Stripe-Signature"]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn check(header: &str) -> Result<u64, OtterhoundError> {
+        verify(header, BODY, &[SECRET], DEFAULT_TOLERANCE)
+    }
+
+    fn assert_rejected(header: &str) {
+        match check(header) {
+            Err(OtterhoundError::Signature(_)) => {}
+            res => panic!("Expected {:?} to be rejected, got {:?}", header, res),
+        }
+    }
+
+    #[test]
+    fn valid_header_is_accepted() {
+        assert!(check(&valid_header()).is_ok());
+    }
+
+    #[test]
+    fn duplicate_timestamp_is_rejected() {
+        let header = valid_header();
+        let (timestamp, _) = header.split_once(',').unwrap();
+
+        assert_rejected(&format!("{},{}", timestamp, header));
+    }
+
+    #[test]
+    fn non_numeric_timestamp_is_rejected() {
+        let header = valid_header();
+        let (_, signature) = header.split_once(',').unwrap();
+
+        assert_rejected(&format!("t=yesterday,{}", signature));
+        assert_rejected(&format!("t=,{}", signature));
+        assert_rejected(&format!("t=-5,{}", signature));
+    }
+
+    #[test]
+    fn whitespace_around_pairs_is_ignored() {
+        let header = valid_header();
+        let (timestamp, signature) = header.split_once(',').unwrap();
+
+        assert!(check(&format!("  {} , {}  ", timestamp, signature)).is_ok());
+        assert!(check(&format!("{},\t{},", timestamp, signature)).is_ok());
+    }
+
+    #[test]
+    fn v0_only_is_rejected() {
+        let header = valid_header();
+        let (timestamp, signature) = header.split_once(',').unwrap();
+        let v0 = signature.replacen("v1=", "v0=", 1);
+
+        assert_rejected(&format!("{},{}", timestamp, v0));
+    }
+
+    #[test]
+    fn v0_alongside_v1_is_ignored() {
+        let header = valid_header();
+        let (timestamp, signature) = header.split_once(',').unwrap();
+
+        assert!(check(&format!("{},v0=deadbeef,{}", timestamp, signature)).is_ok());
+    }
+
+    #[test]
+    fn pair_without_equals_is_rejected() {
+        let header = valid_header();
+
+        assert_rejected(&format!("{},v1", header));
+        assert_rejected(&format!("garbage,{}", header));
+    }
+
+    #[test]
+    fn missing_timestamp_is_rejected() {
+        let header = valid_header();
+        let (_, signature) = header.split_once(',').unwrap();
+
+        assert_rejected(signature);
+        assert_rejected("");
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let header = sign_now(b"whsec_other", BODY);
+
+        assert_rejected(&header);
+    }
+
+    #[test]
+    fn modified_body_is_rejected() {
+        let header = valid_header();
+
+        assert!(verify(&header, br#"{"id":"evt_2"}"#, &[SECRET], DEFAULT_TOLERANCE).is_err());
+    }
+
+    #[test]
+    fn any_of_several_secrets_is_accepted() {
+        let header = valid_header();
+
+        assert!(verify(
+            &header,
+            BODY,
+            &[&b"whsec_old"[..], SECRET],
+            DEFAULT_TOLERANCE
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn expired_timestamp_is_rejected() {
+        let an_hour_ago = crate::from_timestamp(std::time::SystemTime::now()).unwrap() - 60 * 60;
+        let header = sign(SECRET, an_hour_ago, BODY);
+
+        match check(&header) {
+            Err(OtterhoundError::Replay(offset)) => assert!(offset.as_secs() >= 60 * 60),
+            res => panic!("Expected a replay error, got {:?}", res),
+        }
+        // the signature itself is fine, only the timestamp is too old
+        assert_eq!(
+            verify_signature(&header, BODY, &[SECRET]).unwrap(),
+            an_hour_ago
+        );
+    }
+
+    #[test]
+    fn timestamp_out_of_range_is_a_replay() {
+        let header = sign(SECRET, u64::MAX, BODY);
+
+        match check(&header) {
+            Err(OtterhoundError::Replay(offset)) => assert_eq!(offset, Duration::MAX),
+            res => panic!("Expected a replay error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn signed_request_verifies() {
+        let req = signed_request("/stripe/webhook", b"whsec_test", &b"{}"[..]).unwrap();
+        let header = req.headers()["Stripe-Signature"].to_str().unwrap();
+
+        assert_eq!(req.method(), hyper::Method::POST);
+        assert_eq!(req.uri().path(), "/stripe/webhook");
+        assert!(verify(header, b"{}", &["whsec_test"], DEFAULT_TOLERANCE).is_ok());
+    }
+
+    #[test]
+    fn stub_scheme_is_accepted_when_enabled() {
+        let header = stub_header(b"whsec_test", 1234, b"{}");
+
+        let timestamp =
+            verify_with_schemes(&header, b"{}", &["whsec_test"], &[&StubScheme]).unwrap();
+        assert_eq!(timestamp, 1234);
+    }
+
+    #[test]
+    fn stub_scheme_checks_the_secret() {
+        let header = stub_header(b"whsec_other", 1234, b"{}");
+
+        assert!(verify_with_schemes(&header, b"{}", &["whsec_test"], &[&StubScheme]).is_err());
+    }
+
+    #[test]
+    fn stub_scheme_is_ignored_by_default() {
+        let header = stub_header(b"whsec_test", 1234, b"{}");
+
+        assert!(verify_signature(&header, b"{}", &["whsec_test"]).is_err());
+    }
+
+    #[test]
+    fn any_enabled_scheme_may_verify() {
+        // the v1 signature is by another secret, so only the stub's can verify
+        let header = format!(
+            "{},{}",
+            sign(b"whsec_other", 1234, b"{}"),
+            stub_header(b"whsec_test", 1234, b"{}")
+                .split_once(',')
+                .unwrap()
+                .1
+        );
+        let schemes: &[&dyn SignatureScheme] = &[&HmacSha256Scheme, &StubScheme];
+
+        assert_eq!(
+            verify_with_schemes(&header, b"{}", &["whsec_test"], schemes).unwrap(),
+            1234
+        );
+    }
+}
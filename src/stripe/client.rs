@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::stripe::types::{HasId, List};
+use crate::{CircuitState, OtterhoundError, StripeSettings};
+
+/// Where requests go unless `StripeSettings::api_base` says otherwise.
+pub const DEFAULT_API_BASE: &str = "https://api.stripe.com/v1/";
+
+/// The API version the models in `stripe::types` are tested against. Later versions move fields
+/// otterhound reads, e.g. `invoice.subscription` in 2025-03-31.basil; webhook payloads follow the
+/// endpoint's version regardless, so the models accept both layouts where they differ.
+pub const DEFAULT_API_VERSION: &str = "2024-12-18.acacia";
+
+pub type HttpClient =
+    std::sync::Arc<hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>>;
+
+/// Client for the Stripe API.
+///
+/// Requests failing with a connection error, 429 or 5xx are retried with exponential backoff, up
+/// to `StripeSettings::max_retries` times (default 2). All requests go through a circuit breaker,
+/// configured by `breaker_threshold` and `breaker_cooldown`, that fails fast while Stripe keeps
+/// failing.
+///
+/// Every request is pinned to `StripeSettings::api_version` (default `DEFAULT_API_VERSION`) with
+/// the `Stripe-Version` header, so changing the account's default version doesn't change
+/// responses.
+pub struct StripeClient {
+    auth_header: String,
+    api_base: String,
+    api_version: String,
+    http_client: HttpClient,
+    breaker: CircuitBreaker,
+    max_retries: u32,
+    retry_base_delay: Duration,
+}
+
+impl StripeClient {
+    pub fn new(auth_header: String, http_client: HttpClient, settings: &StripeSettings) -> Self {
+        let breaker = CircuitBreaker::new(
+            settings.breaker_threshold.unwrap_or(5),
+            settings.breaker_cooldown.unwrap_or(Duration::from_secs(30)),
+        );
+
+        StripeClient {
+            auth_header,
+            api_base: settings
+                .api_base
+                .clone()
+                .unwrap_or_else(|| DEFAULT_API_BASE.to_owned()),
+            api_version: settings
+                .api_version
+                .clone()
+                .unwrap_or_else(|| DEFAULT_API_VERSION.to_owned()),
+            http_client,
+            breaker,
+            max_retries: settings.max_retries.unwrap_or(2),
+            retry_base_delay: Duration::from_millis(500),
+        }
+    }
+
+    /// The version sent in the `Stripe-Version` header.
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    /// Current state of the circuit breaker guarding requests.
+    pub fn circuit_state(&self) -> CircuitState {
+        self.breaker.state()
+    }
+
+    /// Fetches `path`, relative to the API base, e.g. `customers/cus_123`.
+    pub async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, OtterhoundError> {
+        self.send(hyper::Method::GET, path, None, None).await
+    }
+
+    /// Deletes the object at `path`, which for subscriptions cancels them immediately.
+    pub async fn delete<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, OtterhoundError> {
+        self.send(hyper::Method::DELETE, path, None, None).await
+    }
+
+    /// Fetches every page of the list at `path`, which may already have query parameters, e.g.
+    /// `subscriptions?status=all`.
+    pub async fn get_all<T: serde::de::DeserializeOwned + HasId>(
+        &self,
+        path: &str,
+    ) -> Result<Vec<T>, OtterhoundError> {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let mut items: Vec<T> = Vec::new();
+
+        loop {
+            let page_path = match items.last() {
+                Some(last) => format!(
+                    "{}{}limit=100&starting_after={}",
+                    path,
+                    separator,
+                    last.id()
+                ),
+                None => format!("{}{}limit=100", path, separator),
+            };
+            let page: List<T> = self.get(&page_path).await?;
+
+            let done = !page.has_more || page.data.is_empty();
+            items.extend(page.data);
+            if done {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Makes a cheap authenticated request, to check the API is reachable and the key works.
+    pub async fn ping(&self) -> Result<(), OtterhoundError> {
+        self.get::<serde_json::Value>("balance").await.map(|_| ())
+    }
+
+    /// Posts form parameters to `path`. The same `idempotency_key` must be passed when repeating
+    /// a request, so Stripe applies it only once; retries here reuse it automatically.
+    pub async fn post<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(&str, &str)],
+        idempotency_key: &str,
+    ) -> Result<T, OtterhoundError> {
+        let body = params
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "{}={}",
+                    percent_encoding::utf8_percent_encode(key, percent_encoding::NON_ALPHANUMERIC),
+                    percent_encoding::utf8_percent_encode(
+                        value,
+                        percent_encoding::NON_ALPHANUMERIC
+                    )
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        self.send(hyper::Method::POST, path, Some(body), Some(idempotency_key))
+            .await
+    }
+
+    #[tracing::instrument(name = "stripe_request", skip(self, body, idempotency_key))]
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        method: hyper::Method,
+        path: &str,
+        body: Option<String>,
+        idempotency_key: Option<&str>,
+    ) -> Result<T, OtterhoundError> {
+        let mut attempt = 0;
+
+        loop {
+            let res = self
+                .send_once(&method, path, body.as_deref(), idempotency_key)
+                .await;
+
+            match res {
+                Err(ref err) if err.is_retryable() && attempt < self.max_retries => {
+                    let delay = self.retry_base_delay * 2u32.pow(attempt);
+                    tracing::warn!(
+                        "Stripe request to {} failed, retrying in {:?}: {}",
+                        path,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+                Ok(body) => {
+                    return serde_json::from_slice(&body).map_err(|err| {
+                        OtterhoundError::StripeApi(format!("Failed to parse response: {:?}", err))
+                    })
+                }
+            }
+        }
+    }
+
+    async fn send_once(
+        &self,
+        method: &hyper::Method,
+        path: &str,
+        body: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<hyper::body::Bytes, RequestError> {
+        let mut req = hyper::Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.api_base, path))
+            .header("Authorization", self.auth_header.as_str())
+            .header("Stripe-Version", self.api_version.as_str());
+        if body.is_some() {
+            req = req.header("Content-Type", "application/x-www-form-urlencoded");
+        }
+        if let Some(idempotency_key) = idempotency_key {
+            req = req.header("Idempotency-Key", idempotency_key);
+        }
+        let req = req
+            .body(hyper::Body::from(body.unwrap_or("").to_owned()))
+            .map_err(|err| RequestError::Build(format!("{:?}", err)))?;
+
+        // checked once the request is built, so a trial request while half-open is always sent
+        if !self.breaker.allow() {
+            return Err(RequestError::Unavailable);
+        }
+
+        let res = async {
+            let res = self.http_client.request(req).await?;
+            let status = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+
+            Ok::<_, hyper::Error>((body, status))
+        }
+        .await;
+
+        match &res {
+            Ok((_, status))
+                if status.is_server_error() || *status == hyper::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                self.breaker.record_failure()
+            }
+            Ok(_) => self.breaker.record_success(),
+            Err(_) => self.breaker.record_failure(),
+        }
+
+        let (body, status) = res.map_err(|err| RequestError::Send(format!("{:?}", err)))?;
+
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(RequestError::Status(status, body))
+        }
+    }
+}
+
+/// Why a single attempt failed, to decide whether to retry it.
+enum RequestError {
+    Build(String),
+    Send(String),
+    Status(hyper::StatusCode, hyper::body::Bytes),
+    Unavailable,
+}
+
+impl RequestError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            RequestError::Send(_) => true,
+            RequestError::Status(status, _) => {
+                status.is_server_error() || *status == hyper::StatusCode::TOO_MANY_REQUESTS
+            }
+            RequestError::Build(_) | RequestError::Unavailable => false,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::Build(msg) => write!(f, "Failed to construct request: {}", msg),
+            RequestError::Send(msg) => write!(f, "Failed to send request: {}", msg),
+            RequestError::Status(status, body) => {
+                write!(f, "Received {} from API: {:?}", status, body)
+            }
+            RequestError::Unavailable => write!(f, "Stripe circuit breaker is open"),
+        }
+    }
+}
+
+impl From<RequestError> for OtterhoundError {
+    fn from(err: RequestError) -> OtterhoundError {
+        match err {
+            RequestError::Unavailable => OtterhoundError::StripeUnavailable,
+            RequestError::Status(hyper::StatusCode::NOT_FOUND, _) => {
+                OtterhoundError::NotFound(err.to_string())
+            }
+            _ => OtterhoundError::StripeApi(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! Models of the Stripe API objects otterhound reads.
+
+pub mod client;
+pub mod types;
+
+pub use client::StripeClient;
@@ -0,0 +1,618 @@
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+use crate::OtterhoundError;
+
+/// A page of a list, e.g. a subscription's items. Only the fields otterhound reads are modelled.
+#[derive(Deserialize, Debug)]
+pub struct List<T> {
+    pub data: Vec<T>,
+    /// Whether there are further pages, see `StripeClient::get_all`.
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+/// A field Stripe returns either as an ID or, when expanded, as the full object.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Expandable<T> {
+    Id(String),
+    Object(Box<T>),
+}
+
+impl<T> Expandable<T> {
+    pub fn as_object(&self) -> Option<&T> {
+        match self {
+            Expandable::Id(_) => None,
+            Expandable::Object(object) => Some(object),
+        }
+    }
+}
+
+impl<T: HasId> Expandable<T> {
+    pub fn id(&self) -> &str {
+        match self {
+            Expandable::Id(id) => id,
+            Expandable::Object(object) => object.id(),
+        }
+    }
+}
+
+/// Objects that can appear in an `Expandable` field.
+pub trait HasId {
+    fn id(&self) -> &str;
+}
+
+impl HasId for Customer {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl HasId for Subscription {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+fn parse_metadata_id(
+    metadata: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<i32>, OtterhoundError> {
+    match metadata.get(key) {
+        Some(value) => value.parse().map(Some).map_err(|err| {
+            OtterhoundError::Parse(format!("Failed to parse {} metadata: {:?}", key, err))
+        }),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct InvoiceSettings {
+    pub default_payment_method: Option<String>,
+}
+
+/// A Stripe customer, as delivered in customer events or fetched to resolve a user.
+#[derive(Deserialize)]
+pub struct Customer {
+    pub id: String,
+    /// Personal data, so it is redacted from `Debug` output.
+    pub email: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Set on the stub Stripe returns for customers that have been deleted.
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub invoice_settings: InvoiceSettings,
+}
+
+impl std::fmt::Debug for Customer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Customer")
+            .field("id", &self.id)
+            .field("email", &self.email.as_ref().map(|_| "<redacted>"))
+            .field("metadata", &self.metadata)
+            .field("deleted", &self.deleted)
+            .finish()
+    }
+}
+
+impl Customer {
+    /// Reads the user ID stored in the customer's metadata under `key`.
+    pub fn user_id(&self, key: &str) -> Result<Option<i32>, OtterhoundError> {
+        parse_metadata_id(&self.metadata, key)
+    }
+
+    pub fn has_default_payment_method(&self) -> bool {
+        self.invoice_settings.default_payment_method.is_some()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CheckoutSession {
+    pub id: String,
+    /// Only set for sessions in `subscription` mode.
+    pub subscription: Option<Expandable<Subscription>>,
+    pub customer: Option<String>,
+    pub client_reference_id: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Where to send the customer to pay, while the session is open.
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BillingPortalSession {
+    pub id: String,
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Price {
+    pub id: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubscriptionItem {
+    /// Always sent by Stripe; nothing reads it yet.
+    pub id: Option<String>,
+    pub price: Price,
+    /// Where API versions since 2025-03-31.basil put the period end, see
+    /// `Subscription::period_end`.
+    pub current_period_end: Option<u64>,
+}
+
+/// The fields a `customer.subscription.updated` event's `previous_attributes` had before the
+/// update, of those otterhound reads. Stripe only includes the fields that changed.
+#[derive(Deserialize, Debug, Default)]
+pub struct SubscriptionChanges {
+    pub current_period_end: Option<u64>,
+    pub items: Option<List<SubscriptionItem>>,
+}
+
+impl SubscriptionChanges {
+    /// The tier before the update, read like `Subscription::tier_id`. `None` if the items didn't
+    /// change or no item had a tier.
+    pub fn tier_id(&self, key: &str) -> Result<Option<i32>, OtterhoundError> {
+        match self
+            .items
+            .as_ref()
+            .and_then(|items| tier_item(&items.data, key))
+        {
+            Some(item) => parse_metadata_id(&item.price.metadata, key),
+            None => Ok(None),
+        }
+    }
+
+    /// The end of the period before the update, if the update started a new one.
+    pub fn period_end(&self) -> Option<u64> {
+        self.current_period_end.or_else(|| {
+            self.items
+                .as_ref()?
+                .data
+                .iter()
+                .filter_map(|item| item.current_period_end)
+                .max()
+        })
+    }
+}
+
+fn tier_item<'a>(items: &'a [SubscriptionItem], key: &str) -> Option<&'a SubscriptionItem> {
+    items
+        .iter()
+        .find(|item| item.price.metadata.contains_key(key))
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CancellationDetails {
+    pub comment: Option<String>,
+    pub feedback: Option<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Subscription {
+    pub id: String,
+    pub customer: Expandable<Customer>,
+    pub created: u64,
+    pub current_period_end: Option<u64>,
+    pub currency: Option<String>,
+    pub items: List<SubscriptionItem>,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    pub default_payment_method: Option<String>,
+    pub status: String,
+    pub ended_at: Option<u64>,
+    pub cancellation_details: Option<CancellationDetails>,
+    pub trial_end: Option<u64>,
+}
+
+impl Subscription {
+    /// The end of the trial, while the subscription is in one.
+    pub fn current_trial_end(&self) -> Option<u64> {
+        self.trial_end.filter(|_| self.status == "trialing")
+    }
+
+    /// Whether neither the subscription nor its customer has a default payment method, so the
+    /// next renewal can't be charged. `customer` is `None` when it wasn't fetched or expanded.
+    pub fn payment_method_missing(&self, customer: Option<&Customer>) -> bool {
+        self.default_payment_method.is_none()
+            && !customer.is_some_and(Customer::has_default_payment_method)
+    }
+
+    /// Reads the tier ID from the first item whose price has metadata under `key`.
+    pub fn tier_id(&self, key: &str) -> Result<Option<i32>, OtterhoundError> {
+        match self.tier_item(key) {
+            Some(item) => parse_metadata_id(&item.price.metadata, key),
+            None => Ok(None),
+        }
+    }
+
+    /// The first item whose price has metadata under `key`, i.e. the one deciding the tier.
+    pub fn tier_item(&self, key: &str) -> Option<&SubscriptionItem> {
+        tier_item(&self.items.data, key)
+    }
+
+    /// Finds the end of the current period. API versions since 2025-03-31.basil moved
+    /// `current_period_end` from the subscription onto its items, so both layouts are accepted.
+    pub fn period_end(&self) -> Result<u64, OtterhoundError> {
+        self.current_period_end
+            .or_else(|| {
+                self.items
+                    .data
+                    .iter()
+                    .filter_map(|item| item.current_period_end)
+                    .max()
+            })
+            .ok_or_else(|| {
+                OtterhoundError::Parse("Subscription has no current_period_end".to_owned())
+            })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Period {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InvoiceLine {
+    pub period: Period,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubscriptionDetails {
+    pub subscription: Option<String>,
+}
+
+/// What created an invoice, in API versions since 2025-03-31.basil.
+#[derive(Deserialize, Debug)]
+pub struct InvoiceParent {
+    pub subscription_details: Option<SubscriptionDetails>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Invoice {
+    /// Absent on upcoming invoices, which are previews.
+    pub id: Option<String>,
+    /// Where API versions before 2025-03-31.basil put the subscription, see
+    /// `Invoice::subscription_id`.
+    pub subscription: Option<String>,
+    pub parent: Option<InvoiceParent>,
+    pub customer: Option<String>,
+    pub currency: String,
+    pub amount_due: i64,
+    pub next_payment_attempt: Option<u64>,
+    pub period_end: u64,
+    pub lines: List<InvoiceLine>,
+    /// Where the customer can pay the invoice, e.g. to complete 3D Secure authentication.
+    pub hosted_invoice_url: Option<String>,
+}
+
+impl Invoice {
+    /// The subscription the invoice is for, if any. Webhook payloads use the endpoint's API
+    /// version rather than the `Stripe-Version` pin, and 2025-03-31.basil moved the subscription
+    /// under `parent.subscription_details`, so both layouts are accepted.
+    pub fn subscription_id(&self) -> Option<&str> {
+        self.subscription.as_deref().or_else(|| {
+            self.parent
+                .as_ref()
+                .and_then(|parent| parent.subscription_details.as_ref())
+                .and_then(|details| details.subscription.as_deref())
+        })
+    }
+}
+
+/// Links an invoice to a payment of it, see `Otterhound::subscription_for_charge`.
+#[derive(Deserialize, Debug)]
+pub struct InvoicePayment {
+    pub id: String,
+    pub invoice: String,
+}
+
+impl HasId for InvoicePayment {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Charge {
+    pub id: String,
+    pub amount: i64,
+    pub amount_refunded: i64,
+    pub currency: String,
+    pub customer: Option<String>,
+    /// Removed in API versions since 2025-03-31.basil, where the invoice is found through the
+    /// payment intent instead.
+    pub invoice: Option<String>,
+    pub payment_intent: Option<String>,
+    #[serde(default)]
+    pub refunded: bool,
+    pub status: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Dispute {
+    pub id: String,
+    /// The disputed charge's ID.
+    pub charge: String,
+    pub amount: i64,
+    pub currency: String,
+    pub reason: Option<String>,
+    /// Closed disputes are `won`, `lost` or, for inquiries, `warning_closed`.
+    pub status: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventItem;
+
+    /// Parses a recorded webhook event and its object, the way `dispatch_event` does.
+    fn parse_fixture<T: serde::de::DeserializeOwned>(json: &str, expected: &str) -> T {
+        let event: EventItem = serde_json::from_str(json).unwrap();
+        crate::parse_object(event.data.object, expected).unwrap()
+    }
+
+    #[test]
+    fn customer_updated() {
+        let customer: Customer = parse_fixture(
+            include_str!("../../tests/fixtures/customer_updated.json"),
+            "customer",
+        );
+        assert_eq!(customer.id, "cus_RVLp2WcYh8Qd0e");
+        assert_eq!(customer.user_id("user_id").unwrap(), Some(42));
+        assert_eq!(customer.user_id("account_id").unwrap(), None);
+        assert!(!customer.deleted);
+        assert_eq!(customer.email.as_deref(), Some("jenny.rosen@example.com"));
+        assert!(!format!("{:?}", customer).contains("jenny.rosen"));
+    }
+
+    #[test]
+    fn customer_deleted() {
+        // what `GET /v1/customers/{id}` returns once the customer is deleted
+        let customer: Customer =
+            serde_json::from_str(include_str!("../../tests/fixtures/customer_deleted.json"))
+                .unwrap();
+        assert_eq!(customer.id, "cus_RVLp2WcYh8Qd0e");
+        assert!(customer.deleted);
+        assert_eq!(customer.email, None);
+        assert_eq!(customer.user_id("user_id").unwrap(), None);
+        assert!(!customer.has_default_payment_method());
+    }
+
+    #[test]
+    fn customer_with_invalid_user_id() {
+        let mut customer: Customer = parse_fixture(
+            include_str!("../../tests/fixtures/customer_created.json"),
+            "customer",
+        );
+        customer
+            .metadata
+            .insert("user_id".to_owned(), "user_42".to_owned());
+        assert!(matches!(
+            customer.user_id("user_id"),
+            Err(OtterhoundError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn checkout_session_completed() {
+        let session: CheckoutSession = parse_fixture(
+            include_str!("../../tests/fixtures/checkout_session_completed.json"),
+            "checkout.session",
+        );
+        assert_eq!(
+            session.id,
+            "cs_test_a1Yb0mQ6c9kZs8xWvTq2LpN4rE7uH3jF5gD8sA6kB2nM9"
+        );
+        assert_eq!(
+            session.subscription.as_ref().map(Expandable::id),
+            Some("sub_1QbF2nLkdIwHu7ixYv3sQ8pW")
+        );
+        assert_eq!(session.customer.as_deref(), Some("cus_RUuTfzNcPvXa1b"));
+        assert_eq!(session.client_reference_id.as_deref(), Some("42"));
+        assert!(session.metadata.is_empty());
+        assert_eq!(session.url, None);
+    }
+
+    #[test]
+    fn subscription_updated() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_updated.json"),
+            "subscription",
+        );
+        assert_eq!(subscription.id, "sub_1QbF2nLkdIwHu7ixYv3sQ8pW");
+        assert_eq!(subscription.customer.id(), "cus_RUuTfzNcPvXa1b");
+        assert_eq!(subscription.status, "active");
+        assert!(subscription.cancel_at_period_end);
+        assert_eq!(subscription.currency.as_deref(), Some("usd"));
+        assert_eq!(subscription.tier_id("tier_id").unwrap(), Some(2));
+        assert_eq!(
+            subscription.tier_item("tier_id").unwrap().id.as_deref(),
+            Some("si_RUuTqLmP0aXy7c")
+        );
+        let details = subscription.cancellation_details.unwrap();
+        assert_eq!(details.reason.as_deref(), Some("cancellation_requested"));
+        assert_eq!(details.feedback.as_deref(), Some("too_expensive"));
+        assert_eq!(details.comment, None);
+    }
+
+    #[test]
+    fn subscription_downgraded() {
+        let event: EventItem = serde_json::from_str(include_str!(
+            "../../tests/fixtures/subscription_downgraded.json"
+        ))
+        .unwrap();
+        let changes: SubscriptionChanges =
+            serde_json::from_value(event.data.previous_attributes.unwrap()).unwrap();
+        let subscription: Subscription =
+            crate::parse_object(event.data.object, "subscription").unwrap();
+        assert_eq!(changes.tier_id("tier_id").unwrap(), Some(2));
+        assert_eq!(subscription.tier_id("tier_id").unwrap(), Some(1));
+        // the items changed within the period
+        assert_eq!(changes.current_period_end, None);
+        assert_eq!(changes.period_end(), None);
+    }
+
+    #[test]
+    fn unrelated_changes() {
+        let event: EventItem = serde_json::from_str(include_str!(
+            "../../tests/fixtures/subscription_updated.json"
+        ))
+        .unwrap();
+        let changes: SubscriptionChanges =
+            serde_json::from_value(event.data.previous_attributes.unwrap()).unwrap();
+        assert_eq!(changes.tier_id("tier_id").unwrap(), None);
+        assert_eq!(changes.period_end(), None);
+    }
+
+    #[test]
+    fn period_end_on_subscription() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_updated.json"),
+            "subscription",
+        );
+        assert_eq!(subscription.current_period_end, Some(1738367977));
+        assert_eq!(subscription.items.data[0].current_period_end, None);
+        assert_eq!(subscription.period_end().unwrap(), 1738367977);
+    }
+
+    #[test]
+    fn period_end_on_items() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_updated_basil.json"),
+            "subscription",
+        );
+        assert_eq!(subscription.current_period_end, None);
+        assert_eq!(subscription.period_end().unwrap(), 1755078777);
+        assert_eq!(subscription.tier_id("tier_id").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn payment_method_on_subscription() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_updated.json"),
+            "subscription",
+        );
+        assert!(!subscription.payment_method_missing(None));
+    }
+
+    #[test]
+    fn payment_method_on_customer() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_created.json"),
+            "subscription",
+        );
+        let customer: Customer = parse_fixture(
+            include_str!("../../tests/fixtures/customer_updated.json"),
+            "customer",
+        );
+        assert_eq!(subscription.default_payment_method, None);
+        assert!(customer.has_default_payment_method());
+        assert!(!subscription.payment_method_missing(Some(&customer)));
+    }
+
+    #[test]
+    fn payment_method_missing() {
+        let subscription: Subscription = parse_fixture(
+            include_str!("../../tests/fixtures/subscription_created.json"),
+            "subscription",
+        );
+        let customer: Customer = parse_fixture(
+            include_str!("../../tests/fixtures/customer_created.json"),
+            "customer",
+        );
+        assert!(!customer.has_default_payment_method());
+        assert!(subscription.payment_method_missing(Some(&customer)));
+        // an unfetched customer can't be relied on to have one
+        assert!(subscription.payment_method_missing(None));
+    }
+
+    #[test]
+    fn invoice_payment_succeeded() {
+        let invoice: Invoice = parse_fixture(
+            include_str!("../../tests/fixtures/invoice_payment_succeeded.json"),
+            "invoice",
+        );
+        assert_eq!(invoice.id.as_deref(), Some("in_1QbF2nLkdIwHu7ixR0mB5vTq"));
+        assert_eq!(
+            invoice.subscription_id(),
+            Some("sub_1QbF2nLkdIwHu7ixYv3sQ8pW")
+        );
+        assert_eq!(invoice.currency, "usd");
+        assert_eq!(invoice.amount_due, 900);
+        assert_eq!(invoice.next_payment_attempt, None);
+        assert_eq!(invoice.lines.data.len(), 1);
+        assert_eq!(invoice.lines.data[0].period.end, 1738367977);
+        assert!(!invoice.lines.has_more);
+    }
+
+    #[test]
+    fn invoice_payment_succeeded_basil() {
+        let invoice: Invoice = parse_fixture(
+            include_str!("../../tests/fixtures/invoice_payment_succeeded_basil.json"),
+            "invoice",
+        );
+        assert_eq!(invoice.subscription, None);
+        assert_eq!(
+            invoice.subscription_id(),
+            Some("sub_1QbF2nLkdIwHu7ixYv3sQ8pW")
+        );
+        assert_eq!(invoice.lines.data[0].period.end, 1755078777);
+    }
+
+    #[test]
+    fn charge_refunded() {
+        let charge: Charge = parse_fixture(
+            include_str!("../../tests/fixtures/charge_refunded.json"),
+            "charge",
+        );
+        assert_eq!(charge.id, "ch_3QbF2oLkdIwHu7ix0Z8cF4aR");
+        assert_eq!(charge.amount, 900);
+        assert_eq!(charge.amount_refunded, 900);
+        assert!(charge.refunded);
+        assert_eq!(
+            charge.invoice.as_deref(),
+            Some("in_1QbF2nLkdIwHu7ixR0mB5vTq")
+        );
+        assert_eq!(charge.status, "succeeded");
+    }
+
+    #[test]
+    fn charge_refunded_basil() {
+        let charge: Charge = parse_fixture(
+            include_str!("../../tests/fixtures/charge_refunded_basil.json"),
+            "charge",
+        );
+        assert_eq!(charge.invoice, None);
+        assert_eq!(
+            charge.payment_intent.as_deref(),
+            Some("pi_3RkM3YLkdIwHu7ix1tG6nA0k")
+        );
+
+        let payments: List<InvoicePayment> = serde_json::from_str(include_str!(
+            "../../tests/fixtures/invoice_payments_list_basil.json"
+        ))
+        .unwrap();
+        assert_eq!(payments.data[0].invoice, "in_1RkM3XLkdIwHu7ixb8Kw1ZoP");
+    }
+
+    #[test]
+    fn dispute_created() {
+        let dispute: Dispute = parse_fixture(
+            include_str!("../../tests/fixtures/dispute_created.json"),
+            "dispute",
+        );
+        assert_eq!(dispute.id, "dp_1QbG6wLkdIwHu7ixTn4kP0sB");
+        assert_eq!(dispute.charge, "ch_3QbF2oLkdIwHu7ix0Z8cF4aR");
+        assert_eq!(dispute.amount, 900);
+        assert_eq!(dispute.reason.as_deref(), Some("fraudulent"));
+        assert_eq!(dispute.status, "needs_response");
+    }
+}
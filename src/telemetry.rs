@@ -0,0 +1,61 @@
+use opentelemetry::trace::TracerProvider;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Flushes exported spans when dropped, so keep it alive until the process exits.
+pub struct TelemetryGuard {
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(err) = tracer_provider.shutdown() {
+                eprintln!("Failed to flush spans: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Sets up logging for the binaries. `RUST_LOG` filters as usual, defaulting to `info`, and
+/// `LOG_FORMAT=json` switches to one JSON object per line for log aggregation.
+///
+/// If `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are also exported there over OTLP/HTTP. The
+/// exporter blocks on its own thread, so call this before starting a Tokio runtime.
+pub fn init_logging() -> TelemetryGuard {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+
+    let tracer_provider = if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .expect("Failed to initialize OTLP exporter");
+
+        Some(
+            opentelemetry_sdk::trace::SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    opentelemetry_sdk::Resource::builder()
+                        .with_service_name("otterhound")
+                        .build(),
+                )
+                .build(),
+        )
+    } else {
+        None
+    };
+    let otel_layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("otterhound"))
+    });
+
+    let registry = tracing_subscriber::registry().with(filter).with(otel_layer);
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+        _ => registry.with(tracing_subscriber::fmt::layer()).init(),
+    }
+
+    TelemetryGuard { tracer_provider }
+}
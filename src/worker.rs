@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Otterhound;
+
+/// Processes events queued in `event_log` with a bounded number running at once.
+///
+/// Events are claimed with a lease, so several instances can share the queue, and an event
+/// abandoned by a crashed worker is picked up again once its lease expires. Each event holds a
+/// slot while it is handled, and new events are claimed as slots free up, so one slow event
+/// doesn't hold back the rest.
+pub struct Worker {
+    concurrency: usize,
+    poll_interval: Duration,
+    wake: tokio::sync::Notify,
+    stopping: std::sync::atomic::AtomicBool,
+}
+
+impl Worker {
+    pub fn new(concurrency: usize, poll_interval: Duration) -> Self {
+        Worker {
+            concurrency: concurrency.max(1),
+            poll_interval,
+            wake: tokio::sync::Notify::new(),
+            stopping: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Signals that an event was queued, so it is picked up without waiting for the next poll.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Makes `run` return once the events it is handling are done.
+    pub fn stop(&self) {
+        self.stopping
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        self.wake.notify_one();
+    }
+
+    /// Runs the worker loop until `stop` is called.
+    pub async fn run(&self, otterhound: Arc<Otterhound>) {
+        let slots = Arc::new(tokio::sync::Semaphore::new(self.concurrency));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        while !self.stopping.load(std::sync::atomic::Ordering::Relaxed) {
+            while let Some(res) = tasks.try_join_next() {
+                log_task_result(res);
+            }
+
+            let free = slots.available_permits();
+            if free == 0 {
+                if let Some(res) = tasks.join_next().await {
+                    log_task_result(res);
+                }
+                continue;
+            }
+
+            let events = match otterhound.claim_queued_events(free as i64).await {
+                Ok(events) => events,
+                Err(err) => {
+                    tracing::error!("Failed to claim queued events: {}", err);
+                    Vec::new()
+                }
+            };
+            let claimed = events.len();
+
+            for evt in events {
+                let slot = slots
+                    .clone()
+                    .try_acquire_owned()
+                    .expect("Claimed more events than free slots");
+                let otterhound = otterhound.clone();
+                tasks.spawn(async move {
+                    let _slot = slot;
+                    if let Err(err) = otterhound.handle_logged_event(evt).await {
+                        tracing::error!("{}", err);
+                    }
+                });
+            }
+
+            // filling every free slot means there may be more waiting
+            if claimed < free {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.poll_interval) => {}
+                    _ = self.wake.notified() => {}
+                }
+            }
+        }
+
+        while let Some(res) = tasks.join_next().await {
+            log_task_result(res);
+        }
+    }
+}
+
+fn log_task_result(res: Result<(), tokio::task::JoinError>) {
+    if let Err(err) = res {
+        tracing::error!("Event task failed: {}", err);
+    }
+}
@@ -0,0 +1,128 @@
+use otterhound::EventItem;
+
+use crate::support::{
+    self, count, event, fixture, insert_subscription, MockStripe, TestDatabase,
+    CHECKOUT_SUBSCRIPTION,
+};
+
+/// `subscription_deleted.json` with its `cancellation_details` edited.
+fn deleted_event(edit: impl FnOnce(&mut serde_json::Value)) -> EventItem {
+    let mut value: serde_json::Value =
+        serde_json::from_str(&fixture("subscription_deleted")).unwrap();
+    edit(&mut value["data"]["object"]);
+    serde_json::from_value(value).unwrap()
+}
+
+async fn cancellation(
+    client: &tokio_postgres::Client,
+) -> (i32, Option<String>, Option<String>, Option<String>) {
+    let row = client
+        .query_one(
+            "SELECT user_id, reason, feedback, comment FROM subscription_cancellations WHERE stripe_subscription=$1",
+            &[&CHECKOUT_SUBSCRIPTION],
+        )
+        .await
+        .unwrap();
+
+    (row.get(0), row.get(1), row.get(2), row.get(3))
+}
+
+#[tokio::test]
+async fn deletion_records_cancellation_details() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .store_cancellation_reasons(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    otterhound
+        .handle_event(event("subscription_deleted"))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        cancellation(&client).await,
+        (
+            42,
+            Some("cancellation_requested".to_owned()),
+            Some("too_expensive".to_owned()),
+            None
+        )
+    );
+    assert_eq!(
+        count(&client, "user_subscriptions WHERE cancelled_at IS NOT NULL").await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn deletion_without_details_records_empty_cancellation() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .store_cancellation_reasons(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    // older API versions don't send the details at all
+    otterhound
+        .handle_event(deleted_event(|sub| {
+            sub.as_object_mut().unwrap().remove("cancellation_details");
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(cancellation(&client).await, (42, None, None, None));
+}
+
+#[tokio::test]
+async fn deletion_with_partial_details_keeps_what_was_sent() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .store_cancellation_reasons(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    otterhound
+        .handle_event(deleted_event(|sub| {
+            sub["cancellation_details"] = serde_json::json!({ "reason": "payment_failed" });
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        cancellation(&client).await,
+        (42, Some("payment_failed".to_owned()), None, None)
+    );
+}
+
+#[tokio::test]
+async fn deletion_skips_cancellation_details_unless_enabled() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    otterhound
+        .handle_event(event("subscription_deleted"))
+        .await
+        .unwrap();
+
+    assert_eq!(count(&client, "subscription_cancellations").await, 0);
+    assert_eq!(
+        count(&client, "user_subscriptions WHERE cancelled_at IS NOT NULL").await,
+        1
+    );
+}
@@ -0,0 +1,195 @@
+use otterhound::{MissingSessionBehavior, OtterhoundError};
+
+use crate::support::{
+    self, count, event, insert_checkout_session, MockStripe, TestDatabase, CHECKOUT_CUSTOMER,
+    CHECKOUT_SESSION, CHECKOUT_SUBSCRIPTION,
+};
+
+const EVENT_ID: &str = "evt_1QbF2pLkdIwHu7ixKd8Xq3Ns";
+
+#[tokio::test]
+async fn checkout_records_subscription() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await
+        .unwrap();
+
+    let completed: bool = client
+        .query_one(
+            "SELECT completed FROM subscription_checkout_sessions WHERE stripe_id=$1",
+            &[&CHECKOUT_SESSION],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert!(completed);
+
+    let row = client
+        .query_one(
+            "SELECT user_id, tier, payment_method_missing FROM user_subscriptions WHERE stripe_subscription=$1",
+            &[&CHECKOUT_SUBSCRIPTION],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i32>(0), 42);
+    assert_eq!(row.get::<_, i32>(1), 2);
+    assert!(!row.get::<_, bool>(2));
+
+    let customer_user: i32 = client
+        .query_one(
+            "SELECT user_id FROM stripe_customers WHERE stripe_customer_id=$1",
+            &[&CHECKOUT_CUSTOMER],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(customer_user, 42);
+
+    let processed = client
+        .query_opt("SELECT 1 FROM stripe_events WHERE id=$1", &[&EVENT_ID])
+        .await
+        .unwrap();
+    assert!(processed.is_some());
+}
+
+#[tokio::test]
+async fn checkout_redelivery_is_idempotent() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    for _ in 0..2 {
+        otterhound
+            .handle_event(event("checkout_session_completed"))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(count(&client, "user_subscriptions").await, 1);
+    assert_eq!(count(&client, "stripe_customers").await, 1);
+    assert_eq!(count(&client, "stripe_events").await, 1);
+}
+
+#[tokio::test]
+async fn checkout_failure_rolls_back() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    // recording the customer fails after the session was marked completed
+    client
+        .batch_execute("ALTER TABLE stripe_customers RENAME TO stripe_customers_moved")
+        .await
+        .unwrap();
+
+    let res = otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await;
+    assert!(matches!(res, Err(OtterhoundError::Db(_))), "{:?}", res);
+
+    let completed: bool = client
+        .query_one(
+            "SELECT completed FROM subscription_checkout_sessions WHERE stripe_id=$1",
+            &[&CHECKOUT_SESSION],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert!(!completed);
+    assert_eq!(count(&client, "user_subscriptions").await, 0);
+    assert_eq!(count(&client, "stripe_events").await, 0);
+
+    // and the redelivery goes through once the table is back
+    client
+        .batch_execute("ALTER TABLE stripe_customers_moved RENAME TO stripe_customers")
+        .await
+        .unwrap();
+    otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await
+        .unwrap();
+    assert_eq!(count(&client, "user_subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn missing_session_is_skipped_by_default() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+
+    otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await
+        .unwrap();
+
+    // handled, so a session row showing up later doesn't bring the event back
+    assert_eq!(count(&client, "user_subscriptions").await, 0);
+    assert_eq!(count(&client, "stripe_events").await, 1);
+}
+
+#[tokio::test]
+async fn missing_session_is_retried_in_error_mode() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .on_missing_session(MissingSessionBehavior::Error)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+
+    let res = otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await;
+    assert!(
+        matches!(res, Err(OtterhoundError::NotFound(_))),
+        "{:?}",
+        res
+    );
+    assert_eq!(count(&client, "stripe_events").await, 0);
+
+    // the session row shows up after Stripe's event did
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+    otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await
+        .unwrap();
+
+    assert_eq!(count(&client, "user_subscriptions").await, 1);
+    assert_eq!(count(&client, "stripe_events").await, 1);
+}
+
+#[tokio::test]
+async fn unparseable_stripe_response_is_a_stripe_error() {
+    let db = TestDatabase::new().await;
+    let mut objects = std::collections::HashMap::new();
+    objects.insert(
+        format!("subscriptions/{}", CHECKOUT_SUBSCRIPTION),
+        serde_json::json!({ "id": 5 }),
+    );
+    let stripe = MockStripe::with_objects(objects);
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    // Stripe's side failed, not the event, so the webhook is answered with a retryable status
+    let res = otterhound
+        .handle_event(event("checkout_session_completed"))
+        .await;
+    assert!(
+        matches!(res, Err(OtterhoundError::StripeApi(_))),
+        "{:?}",
+        res
+    );
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use otterhound::{EventItem, OtterhoundError};
+
+use crate::support::{
+    self, event, fixture, insert_subscription, MockStripe, TestDatabase, CHECKOUT_SUBSCRIPTION,
+};
+
+const CANCELLED_EVENT: &str = "evt_1QbHl9LkdIwHu7ixQe4vR7Hn";
+
+/// Follows `subscription_downgraded.json` a second later, scheduling the cancellation.
+fn cancelled_event() -> EventItem {
+    let mut value: serde_json::Value =
+        serde_json::from_str(&fixture("subscription_downgraded")).unwrap();
+    value["id"] = CANCELLED_EVENT.into();
+    value["created"] = (value["created"].as_u64().unwrap() + 1).into();
+    value["data"]["object"]["cancel_at_period_end"] = true.into();
+    value["data"]["previous_attributes"] = serde_json::json!({"cancel_at_period_end": false});
+    serde_json::from_value(value).unwrap()
+}
+
+async fn subscription(client: &tokio_postgres::Client) -> (i32, Option<i32>, bool) {
+    let row = client
+        .query_one(
+            "SELECT tier, pending_tier, cancel_at_period_end FROM user_subscriptions WHERE stripe_subscription=$1",
+            &[&CHECKOUT_SUBSCRIPTION],
+        )
+        .await
+        .unwrap();
+
+    (row.get(0), row.get(1), row.get(2))
+}
+
+#[tokio::test]
+async fn rapid_updates_are_coalesced() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .update_debounce(Duration::from_secs(1))
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    // delivered out of order
+    let (cancelled, downgraded) = tokio::join!(
+        otterhound.handle_event(cancelled_event()),
+        otterhound.handle_event(event("subscription_downgraded")),
+    );
+    cancelled.unwrap();
+    downgraded.unwrap();
+
+    // one write, still seeing the downgrade the later event didn't mention
+    assert_eq!(subscription(&client).await, (2, Some(1), true));
+    let recorded: Vec<String> = client
+        .query("SELECT id FROM stripe_events", &[])
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(recorded, [CANCELLED_EVENT]);
+}
+
+#[tokio::test]
+async fn flush_writes_buffered_updates() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .update_debounce(Duration::from_secs(30))
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    let (res, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            otterhound.handle_event(event("subscription_downgraded")),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                otterhound.flush_updates();
+            }
+        )
+    })
+    .await
+    .unwrap();
+    res.unwrap();
+
+    assert_eq!(subscription(&client).await, (1, None, false));
+}
+
+#[tokio::test]
+async fn debounce_must_be_shorter_than_timeout() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+
+    let res = support::builder(&db, &stripe)
+        .event_timeout(Duration::from_secs(10))
+        .update_debounce(Duration::from_secs(10))
+        .build()
+        .await;
+
+    assert!(matches!(res, Err(OtterhoundError::Config(_))));
+}
@@ -0,0 +1,14 @@
+//! Runs the event handlers against a real Postgres migrated from V0, with a mock Stripe API.
+//!
+//! Enabled by the `integration-tests` feature. Each test starts a Postgres container, unless
+//! `OTTERHOUND_TEST_DATABASE_URL` points at a server to create throwaway databases on.
+//!
+//! `support` is shared with the server's own tests in `src/main.rs`.
+
+mod cancellation;
+mod checkout;
+mod debouncing;
+mod processing;
+mod support;
+mod tier_changes;
+mod transactions;
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use otterhound::worker::Worker;
+use otterhound::{peek_event_meta, EventContext, EventHandler, OtterhoundError};
+
+use crate::support::{
+    self, count, event, fixture, insert_checkout_session, MockStripe, TestDatabase,
+    CHECKOUT_SESSION,
+};
+
+const EVENT_ID: &str = "evt_1QbF2pLkdIwHu7ixKd8Xq3Ns";
+
+async fn event_status(client: &tokio_postgres::Client) -> String {
+    client
+        .query_one("SELECT status FROM event_log WHERE id=$1", &[&EVENT_ID])
+        .await
+        .unwrap()
+        .get(0)
+}
+
+#[tokio::test]
+async fn sync_mode_handles_event_before_returning() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    // what the webhook endpoint does for `Sync`
+    let body = fixture("checkout_session_completed");
+    let meta = peek_event_meta(body.as_bytes()).unwrap();
+    otterhound.log_event(&meta, body.as_bytes()).await.unwrap();
+    otterhound
+        .handle_logged_event(event("checkout_session_completed"))
+        .await
+        .unwrap();
+
+    assert_eq!(event_status(&client).await, "handled");
+    assert_eq!(count(&client, "user_subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn background_mode_handles_event_in_worker() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = Arc::new(support::builder(&db, &stripe).build().await.unwrap());
+    let client = db.connect().await;
+    insert_checkout_session(&client, CHECKOUT_SESSION, 42, 2).await;
+
+    // what the webhook endpoint does for `Background`, leaving the rest to the worker
+    let body = fixture("checkout_session_completed");
+    let meta = peek_event_meta(body.as_bytes()).unwrap();
+    otterhound
+        .enqueue_event(&meta, body.as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(event_status(&client).await, "queued");
+    assert_eq!(count(&client, "user_subscriptions").await, 0);
+
+    let worker = Worker::new(2, Duration::from_millis(50));
+    let wait = async {
+        while event_status(&client).await != "handled" {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        worker.stop();
+    };
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        futures::future::join(worker.run(otterhound.clone()), wait),
+    )
+    .await
+    .expect("Worker didn't handle the queued event");
+
+    assert_eq!(count(&client, "user_subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn unparseable_queued_event_is_dead_lettered() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+
+    // enough to be queued, but without the object to handle
+    let body = br#"{"id": "evt_no_object", "type": "invoice.paid", "created": 1735689612}"#;
+    let meta = peek_event_meta(body).unwrap();
+    otterhound.enqueue_event(&meta, body).await.unwrap();
+
+    assert!(otterhound.claim_queued_events(10).await.unwrap().is_empty());
+    assert_eq!(count(&client, "event_log").await, 0);
+    assert_eq!(
+        count(&client, "dead_letter_events WHERE id='evt_no_object'").await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn unparseable_failed_event_is_dead_lettered_on_retry() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+
+    client
+        .execute(
+            "INSERT INTO event_log (id, event_type, created, payload, status, next_attempt_at) VALUES ('evt_no_object', 'invoice.paid', now(), '{\"id\": \"evt_no_object\"}', 'failed', now())",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(otterhound.retry_due_events(10).await.unwrap(), 1);
+    assert_eq!(count(&client, "event_log").await, 0);
+    assert_eq!(
+        count(&client, "dead_letter_events WHERE id='evt_no_object'").await,
+        1
+    );
+}
+
+/// Handles `test.event`, holding events with `"slow": true` in their object until released.
+struct SlowHandler {
+    release: Arc<tokio::sync::Semaphore>,
+}
+
+#[async_trait]
+impl EventHandler for SlowHandler {
+    fn event_type(&self) -> &str {
+        "test.event"
+    }
+
+    async fn handle(
+        &self,
+        _ctx: EventContext<'_>,
+        object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        if object["slow"] == true {
+            self.release.acquire().await.unwrap().forget();
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn worker_claims_events_as_slots_free_up() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let mut otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let release = Arc::new(tokio::sync::Semaphore::new(0));
+    otterhound.register_handler(SlowHandler {
+        release: release.clone(),
+    });
+    let otterhound = Arc::new(otterhound);
+    let client = db.connect().await;
+
+    // the slow event is the oldest, so it's claimed first
+    for (id, created, slow) in [
+        ("evt_slow", 1735689600, true),
+        ("evt_fast_1", 1735689612, false),
+        ("evt_fast_2", 1735689613, false),
+        ("evt_fast_3", 1735689614, false),
+    ] {
+        let body = serde_json::json!({
+            "id": id,
+            "type": "test.event",
+            "created": created,
+            "data": {"object": {"slow": slow}},
+        })
+        .to_string();
+        let meta = peek_event_meta(body.as_bytes()).unwrap();
+        otterhound
+            .enqueue_event(&meta, body.as_bytes())
+            .await
+            .unwrap();
+    }
+
+    let worker = Worker::new(2, Duration::from_millis(50));
+    let wait = async {
+        // the fast events share the one slot the slow event leaves free
+        while count(&client, "event_log WHERE status='handled'").await < 3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            count(
+                &client,
+                "event_log WHERE id='evt_slow' AND status='processing'"
+            )
+            .await,
+            1
+        );
+        release.add_permits(1);
+        while count(&client, "event_log WHERE status='handled'").await < 4 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        worker.stop();
+    };
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        futures::future::join(worker.run(otterhound.clone()), wait),
+    )
+    .await
+    .expect("Worker waited for the slow event before claiming more");
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use otterhound::{EventItem, OtterhoundBuilder};
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::ContainerAsync;
+
+/// Points the suite at an existing server instead of starting a container. Each test creates
+/// its own database there and drops it afterwards, so the user needs CREATEDB.
+const DATABASE_URL_VAR: &str = "OTTERHOUND_TEST_DATABASE_URL";
+
+/// The subscription created by `checkout_session_completed.json`.
+pub const CHECKOUT_SUBSCRIPTION: &str = "sub_1QbF2nLkdIwHu7ixYv3sQ8pW";
+pub const CHECKOUT_SESSION: &str = "cs_test_a1Yb0mQ6c9kZs8xWvTq2LpN4rE7uH3jF5gD8sA6kB2nM9";
+pub const CHECKOUT_CUSTOMER: &str = "cus_RUuTfzNcPvXa1b";
+
+static DATABASE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn fixture(name: &str) -> String {
+    let path = format!(
+        "{}/tests/fixtures/{}.json",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+}
+
+pub fn event(name: &str) -> EventItem {
+    serde_json::from_str(&fixture(name)).unwrap()
+}
+
+/// The `data.object` of a recorded event, for serving from the mock API.
+pub fn fixture_object(name: &str) -> serde_json::Value {
+    let mut value: serde_json::Value = serde_json::from_str(&fixture(name)).unwrap();
+    value["data"]["object"].take()
+}
+
+/// An empty database with nothing migrated, dropped again when this goes out of scope.
+pub struct TestDatabase {
+    pub url: String,
+    // the admin URL and name of a database created on the server from `DATABASE_URL_VAR`
+    created: Option<(String, String)>,
+    _container: Option<ContainerAsync<Postgres>>,
+}
+
+impl TestDatabase {
+    pub async fn new() -> Self {
+        match std::env::var(DATABASE_URL_VAR) {
+            Ok(admin_url) => {
+                let name = format!(
+                    "otterhound_test_{}_{}",
+                    std::process::id(),
+                    DATABASE_COUNT.fetch_add(1, Ordering::Relaxed)
+                );
+                let admin = connect(&admin_url).await;
+                admin
+                    .batch_execute(&format!("CREATE DATABASE {}", name))
+                    .await
+                    .unwrap();
+
+                TestDatabase {
+                    url: with_database(&admin_url, &name),
+                    created: Some((admin_url, name)),
+                    _container: None,
+                }
+            }
+            Err(_) => {
+                let container = Postgres::default().start().await.unwrap();
+                let url = format!(
+                    "postgres://postgres:postgres@{}:{}/postgres",
+                    container.get_host().await.unwrap(),
+                    container.get_host_port_ipv4(5432).await.unwrap()
+                );
+
+                TestDatabase {
+                    url,
+                    created: None,
+                    _container: Some(container),
+                }
+            }
+        }
+    }
+
+    pub async fn connect(&self) -> tokio_postgres::Client {
+        connect(&self.url).await
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        if let Some((admin_url, name)) = self.created.take() {
+            // the test's runtime may be shutting down, so drop it from a runtime of its own
+            let res = std::thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(async {
+                        let (client, conn) =
+                            tokio_postgres::connect(&admin_url, tokio_postgres::NoTls).await?;
+                        tokio::spawn(conn);
+                        client
+                            .batch_execute(&format!("DROP DATABASE {} WITH (FORCE)", name))
+                            .await
+                    })
+            })
+            .join()
+            .unwrap();
+            if let Err(err) = res {
+                eprintln!("Failed to drop test database: {}", err);
+            }
+        }
+    }
+}
+
+async fn connect(url: &str) -> tokio_postgres::Client {
+    let (client, conn) = tokio_postgres::connect(url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            eprintln!("Test connection failed: {}", err);
+        }
+    });
+
+    client
+}
+
+fn with_database(url: &str, name: &str) -> String {
+    let (base, params) = match url.find('?') {
+        Some(idx) => url.split_at(idx),
+        None => (url, ""),
+    };
+    // the database name is optional, in which case there's only the scheme's slashes
+    let prefix = match base.rsplit_once('/') {
+        Some((prefix, _)) if !prefix.ends_with('/') => prefix,
+        _ => base,
+    };
+
+    format!("{}/{}{}", prefix, name, params)
+}
+
+/// A stand-in for the Stripe API serving canned objects by path, ignoring the query string.
+/// Anything else gets Stripe's 404 error body.
+pub struct MockStripe {
+    pub api_base: String,
+    _shutdown: tokio::sync::oneshot::Sender<()>,
+}
+
+impl MockStripe {
+    /// Serves the subscription from the checkout fixture with its customer expanded.
+    pub fn start() -> Self {
+        let mut customer = fixture_object("customer_updated");
+        customer["id"] = CHECKOUT_CUSTOMER.into();
+        let mut sub = fixture_object("subscription_updated");
+        sub["customer"] = customer;
+
+        let mut objects = HashMap::new();
+        objects.insert(format!("subscriptions/{}", CHECKOUT_SUBSCRIPTION), sub);
+
+        Self::with_objects(objects)
+    }
+
+    pub fn with_objects(objects: HashMap<String, serde_json::Value>) -> Self {
+        let objects = Arc::new(objects);
+
+        let make_service = make_service_fn(move |_| {
+            let objects = objects.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let objects = objects.clone();
+                    async move {
+                        let path = req.uri().path().trim_start_matches("/v1/");
+                        let res = match objects.get(path) {
+                            Some(object) => Response::new(Body::from(object.to_string())),
+                            None => {
+                                let mut res = Response::new(Body::from(
+                                    serde_json::json!({
+                                        "error": {
+                                            "type": "invalid_request_error",
+                                            "message": format!("No such object: {}", path),
+                                        }
+                                    })
+                                    .to_string(),
+                                ));
+                                *res.status_mut() = StatusCode::NOT_FOUND;
+                                res
+                            }
+                        };
+                        Ok::<_, Infallible>(res)
+                    }
+                }))
+            }
+        });
+
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let api_base = format!("http://{}/v1/", server.local_addr());
+        tokio::spawn(server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        }));
+
+        MockStripe {
+            api_base,
+            _shutdown: shutdown,
+        }
+    }
+}
+
+/// A builder for an instance on `db`, migrated from V0, that talks to `stripe`.
+pub fn builder(db: &TestDatabase, stripe: &MockStripe) -> OtterhoundBuilder {
+    OtterhoundBuilder::new()
+        .database_url(db.url.clone())
+        .stripe_secret_key("sk_test_integration")
+        .stripe_api_base(stripe.api_base.clone())
+        .stripe_max_retries(0)
+        .run_migrations(true)
+}
+
+pub async fn insert_checkout_session(
+    client: &tokio_postgres::Client,
+    session_id: &str,
+    user_id: i32,
+    tier_id: i32,
+) {
+    client
+        .execute(
+            "INSERT INTO subscription_checkout_sessions (stripe_id, user_id, tier_id) VALUES ($1, $2, $3)",
+            &[&session_id, &user_id, &tier_id],
+        )
+        .await
+        .unwrap();
+}
+
+/// Counts the rows of `from`, a table optionally followed by a `WHERE` clause.
+pub async fn count(client: &tokio_postgres::Client, from: &str) -> i64 {
+    client
+        .query_one(&*format!("SELECT COUNT(*) FROM {}", from), &[])
+        .await
+        .unwrap()
+        .get(0)
+}
+
+/// An active subscription to `CHECKOUT_SUBSCRIPTION`, as checkout would have left it.
+pub async fn insert_subscription(client: &tokio_postgres::Client, user_id: i32, tier_id: i32) {
+    client
+        .execute(
+            "INSERT INTO user_subscriptions (tier, user_id, start_timestamp, end_timestamp, stripe_subscription) VALUES ($1, $2, now(), now() + interval '30 days', $3)",
+            &[&tier_id, &user_id, &CHECKOUT_SUBSCRIPTION],
+        )
+        .await
+        .unwrap();
+}
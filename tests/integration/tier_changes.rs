@@ -0,0 +1,198 @@
+use std::time::{Duration, SystemTime};
+
+use otterhound::EventItem;
+
+use crate::support::{
+    self, event, fixture, insert_subscription, MockStripe, TestDatabase, CHECKOUT_SUBSCRIPTION,
+};
+
+/// The end of the period in `subscription_downgraded.json`.
+const PERIOD_END: u64 = 1738367977;
+
+/// `subscription_downgraded.json` with its event edited.
+fn downgraded_event(edit: impl FnOnce(&mut serde_json::Value)) -> EventItem {
+    let mut value: serde_json::Value =
+        serde_json::from_str(&fixture("subscription_downgraded")).unwrap();
+    edit(&mut value);
+    serde_json::from_value(value).unwrap()
+}
+
+/// The same change the other way round, from tier 1 to tier 2.
+fn upgraded_event() -> EventItem {
+    downgraded_event(|value| {
+        let data = &mut value["data"];
+        let previous = data["previous_attributes"]["items"].take();
+        let current = std::mem::replace(&mut data["object"]["items"], previous);
+        data["previous_attributes"]["items"] = current;
+    })
+}
+
+async fn tiers(client: &tokio_postgres::Client) -> (i32, Option<i32>, Option<SystemTime>) {
+    let row = client
+        .query_one(
+            "SELECT tier, pending_tier, pending_tier_from FROM user_subscriptions WHERE stripe_subscription=$1",
+            &[&CHECKOUT_SUBSCRIPTION],
+        )
+        .await
+        .unwrap();
+
+    (row.get(0), row.get(1), row.get(2))
+}
+
+#[tokio::test]
+async fn downgrade_applies_immediately_by_default() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    otterhound
+        .handle_event(event("subscription_downgraded"))
+        .await
+        .unwrap();
+
+    assert_eq!(tiers(&client).await, (1, None, None));
+}
+
+#[tokio::test]
+async fn downgrade_is_deferred_to_period_end() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    otterhound
+        .handle_event(event("subscription_downgraded"))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        tiers(&client).await,
+        (
+            2,
+            Some(1),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(PERIOD_END))
+        )
+    );
+}
+
+#[tokio::test]
+async fn upgrade_is_never_deferred() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 1).await;
+
+    otterhound.handle_event(upgraded_event()).await.unwrap();
+
+    assert_eq!(tiers(&client).await, (2, None, None));
+}
+
+#[tokio::test]
+async fn downgrade_with_new_period_applies_immediately() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+
+    // as a schedule would change it at renewal
+    otterhound
+        .handle_event(downgraded_event(|value| {
+            value["data"]["previous_attributes"]["current_period_end"] =
+                (PERIOD_END - 31 * 24 * 60 * 60).into();
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(tiers(&client).await, (1, None, None));
+}
+
+#[tokio::test]
+async fn deferred_downgrade_applies_once_due() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+    client
+        .execute(
+            "UPDATE user_subscriptions SET pending_tier=1, pending_tier_from=now() + interval '1 day'",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    // an unrelated update before the period ends keeps the pending tier
+    let unrelated = |id: &str| {
+        downgraded_event(|value| {
+            value["id"] = id.into();
+            value["data"]["previous_attributes"] =
+                serde_json::json!({"cancel_at_period_end": true});
+        })
+    };
+    otterhound
+        .handle_event(unrelated("evt_1QbHp7LkdIwHu7ixK3vM2aRz"))
+        .await
+        .unwrap();
+    let (tier, pending_tier, _) = tiers(&client).await;
+    assert_eq!((tier, pending_tier), (2, Some(1)));
+
+    client
+        .execute(
+            "UPDATE user_subscriptions SET pending_tier_from=now() - interval '1 second'",
+            &[],
+        )
+        .await
+        .unwrap();
+    otterhound
+        .handle_event(unrelated("evt_1QbHq1LkdIwHu7ixB8nT5cWe"))
+        .await
+        .unwrap();
+
+    assert_eq!(tiers(&client).await, (1, None, None));
+}
+
+#[tokio::test]
+async fn upgrade_drops_deferred_downgrade() {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let otterhound = support::builder(&db, &stripe)
+        .defer_downgrades(true)
+        .build()
+        .await
+        .unwrap();
+    let client = db.connect().await;
+    insert_subscription(&client, 42, 2).await;
+    client
+        .execute(
+            "UPDATE user_subscriptions SET pending_tier=1, pending_tier_from=now() + interval '1 day'",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    // moving back to the current tier before the period ends
+    otterhound.handle_event(upgraded_event()).await.unwrap();
+
+    assert_eq!(tiers(&client).await, (2, None, None));
+}
@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use otterhound::{EventContext, EventHandler, EventItem, OtterhoundError};
+
+use crate::support::{self, count, MockStripe, TestDatabase};
+
+/// Handles `test.event` by recording the event in a transaction, failing its first `failures`
+/// attempts with `errcode`.
+struct FlakyHandler {
+    errcode: &'static str,
+    failures: u32,
+    attempts: Arc<AtomicU32>,
+}
+
+#[async_trait]
+impl EventHandler for FlakyHandler {
+    fn event_type(&self) -> &str {
+        "test.event"
+    }
+
+    async fn handle(
+        &self,
+        ctx: EventContext<'_>,
+        _object: serde_json::Value,
+    ) -> Result<(), OtterhoundError> {
+        ctx.transaction(async |txn| {
+            txn.execute("CREATE TABLE IF NOT EXISTS test_writes (id TEXT)", &[])
+                .await?;
+            txn.execute("INSERT INTO test_writes VALUES ('written')", &[])
+                .await?;
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                txn.batch_execute(&format!(
+                    "DO $$ BEGIN RAISE EXCEPTION 'simulated' USING ERRCODE = '{}'; END $$",
+                    self.errcode
+                ))
+                .await?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn handle_flaky(
+    errcode: &'static str,
+    failures: u32,
+) -> (Result<(), OtterhoundError>, u32, i64) {
+    let db = TestDatabase::new().await;
+    let stripe = MockStripe::start();
+    let mut otterhound = support::builder(&db, &stripe).build().await.unwrap();
+    let attempts = Arc::new(AtomicU32::new(0));
+    otterhound.register_handler(FlakyHandler {
+        errcode,
+        failures,
+        attempts: attempts.clone(),
+    });
+
+    let evt: EventItem = serde_json::from_value(serde_json::json!({
+        "id": "evt_flaky",
+        "type": "test.event",
+        "created": 1735689612,
+        "data": {"object": {}},
+    }))
+    .unwrap();
+    let res = otterhound.handle_event(evt).await;
+
+    let client = db.connect().await;
+    let writes = if count(&client, "pg_tables WHERE tablename='test_writes'").await == 1 {
+        count(&client, "test_writes").await
+    } else {
+        0
+    };
+    (res, attempts.load(Ordering::SeqCst), writes)
+}
+
+#[tokio::test]
+async fn serialization_failure_is_retried() {
+    let (res, attempts, writes) = handle_flaky("serialization_failure", 1).await;
+    res.unwrap();
+    assert_eq!(attempts, 2);
+    // the failed attempt was rolled back
+    assert_eq!(writes, 1);
+}
+
+#[tokio::test]
+async fn deadlock_is_retried_until_retries_run_out() {
+    let (res, attempts, writes) = handle_flaky("deadlock_detected", u32::MAX).await;
+    assert!(
+        matches!(
+            res,
+            Err(OtterhoundError::DbRetryable(
+                otterhound::DbRetryReason::Deadlock,
+                _
+            ))
+        ),
+        "{:?}",
+        res
+    );
+    // the first attempt and three retries
+    assert_eq!(attempts, 4);
+    assert_eq!(writes, 0);
+}
+
+#[tokio::test]
+async fn other_errors_are_not_retried() {
+    let (res, attempts, writes) = handle_flaky("unique_violation", 1).await;
+    assert!(matches!(res, Err(OtterhoundError::Db(_))), "{:?}", res);
+    assert_eq!(attempts, 1);
+    assert_eq!(writes, 0);
+}